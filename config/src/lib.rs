@@ -1,21 +1,172 @@
 mod object_store;
+mod manifest;
 
 use awscreds::Credentials;
 use awsregion::Region;
 use serde::Deserialize;
 use tracing::debug;
-use std::{env, path::Path};
+use std::{collections::HashMap, env, path::Path};
 use std::path::PathBuf;
 use std::fs::read_to_string;
 use anyhow::{anyhow, bail, Result};
 
 pub use object_store::*;
+pub use manifest::*;
 
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Server {
     pub host: String,
     pub port: u16,
+    /// Resizing on request under `/img`, disabled if not set
+    pub resize: Option<DynamicResize>,
+    /// `Cache-Control` of images by key pattern, the first match wins
+    #[serde(default)]
+    pub cache_control: Vec<CacheControl>,
+    /// Image sent with 404 responses of `/s3/get`, relative to the config dir
+    pub placeholder: Option<PathBuf>,
+    /// Objects streamed to clients at once, more requests wait for their turn
+    pub max_transfers: Option<usize>,
+    /// Every route is open if not set
+    pub auth: Option<Auth>,
+    /// HTTPS with these files instead of plain HTTP
+    pub tls: Option<Tls>,
+    /// Unix socket to listen on instead of `host` and `port`, relative to the config dir
+    pub socket: Option<PathBuf>,
+    /// Seconds until a response has to start, 408 after that
+    pub request_timeout: Option<u64>,
+    /// Requests handled at once, more of them wait for their turn
+    pub max_requests: Option<usize>,
+    /// Seconds running requests get to finish on shutdown
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+}
+
+/// PEM files, relative to the config dir.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tls {
+    /// Certificate chain, the server certificate first
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+/// API keys for listings and signed URLs for images.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Auth {
+    /// Accepted API keys, old ones stay here until clients have moved on
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    /// Keys of signed URLs, the first one signs and all of them verify
+    #[serde(default)]
+    pub keys: Vec<SigningKey>,
+    /// Access by key prefix, the longest matching prefix wins
+    #[serde(default)]
+    pub rules: Vec<AccessRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SigningKey {
+    pub id: String,
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccessRule {
+    pub prefix: String,
+    /// Listing routes `/s3/list`, `/s3/index`, `/list` and `/images`
+    #[serde(default = "default_list_access")]
+    pub list: Access,
+    /// Image routes `/s3/get` and `/img`
+    #[serde(default)]
+    pub get: Access,
+}
+
+/// From the most open to the strictest.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    #[default]
+    Public,
+    /// API key or signed URL needed
+    Signed,
+    /// API key needed
+    Token,
+}
+
+fn default_list_access() -> Access {
+    Access::Token
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheControl {
+    /// Glob of object keys, `*` stays within a dir and `**` crosses them
+    pub pattern: String,
+    pub value: String,
+}
+
+fn default_quality() -> u8 {
+    75
+}
+
+fn default_cache_prefix() -> String {
+    "_cache".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DynamicResize {
+    /// Sizes that may be asked for, anything else is refused
+    pub sizes: Vec<AllowedSize>,
+    /// Used when the request has no quality
+    #[serde(default = "default_quality")]
+    pub quality: u8,
+    /// Other qualities that may be asked for
+    #[serde(default)]
+    pub qualities: Vec<u8>,
+    /// Local cache, relative to the config dir. Results are cached into the bucket if not set
+    pub cache_dir: Option<PathBuf>,
+    /// Key prefix of results cached into the bucket
+    #[serde(default = "default_cache_prefix")]
+    pub cache_prefix: String,
+}
+
+impl DynamicResize {
+    pub fn allows(&self, width: Option<u32>, height: Option<u32>) -> bool {
+        self.sizes.iter().any(|s| s.0 == width && s.1 == height)
+    }
+    pub fn allows_quality(&self, quality: u8) -> bool {
+        quality == self.quality || self.qualities.contains(&quality)
+    }
+}
+
+/// `WIDTHxHEIGHT`, `WIDTHx` or `xHEIGHT`, a missing side follows the aspect ratio.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct AllowedSize(pub Option<u32>, pub Option<u32>);
+
+impl TryFrom<String> for AllowedSize {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let side = |v: &str| match v {
+            "" => Ok(None),
+            v => match v.parse::<u32>() {
+                Ok(0) | Err(_) => Err(anyhow!("Size {:?} needs positive numbers as WIDTHxHEIGHT", value)),
+                Ok(n) => Ok(Some(n)),
+            },
+        };
+
+        match value.split_once('x') {
+            Some((w, h)) => match (side(w)?, side(h)?) {
+                (None, None) => bail!("Size {:?} needs a width or a height", value),
+                (w, h) => Ok(Self(w, h)),
+            },
+            None => bail!("Size {:?} is not in WIDTHxHEIGHT format", value),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -29,8 +180,117 @@ pub struct S3 {
 pub struct Export {
     pub prefix: Option<PathBuf>,
     pub filesystem_path: Option<PathBuf>,
+    #[serde(default)]
     pub filesystem: bool,
+    #[serde(default)]
     pub s3: bool,
+    #[serde(default)]
+    pub naming: Naming,
+    pub path_template: Option<String>,
+    #[serde(default)]
+    pub targets: Vec<ExportTarget>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Naming {
+    #[default]
+    Plain,
+    Hashed,
+    Content,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportKind {
+    Filesystem,
+    S3,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExportTarget {
+    pub name: Option<String>,
+    pub kind: ExportKind,
+    pub path: Option<PathBuf>,
+    pub prefix: Option<PathBuf>,
+    pub bucket: Option<String>,
+    pub region: Option<Region>,
+    pub credentials: Option<Credentials>,
+    pub naming: Option<Naming>,
+    #[serde(default)]
+    pub filter: ExportFilter,
+}
+
+impl ExportTarget {
+    /// Missing bucket, region or credentials are taken from the `[s3]` section.
+    pub fn s3_config(&self, fallback: Option<&S3>) -> Result<S3> {
+        let bucket = match (&self.bucket, fallback) {
+            (Some(b), _) => b.to_owned(),
+            (None, Some(f)) => f.bucket.to_owned(),
+            (None, None) => bail!("S3 export target {:?} has no bucket", self.name),
+        };
+
+        let region = match (&self.region, fallback) {
+            (Some(r), _) => r.to_owned(),
+            (None, Some(f)) => f.region.to_owned(),
+            (None, None) => bail!("S3 export target {:?} has no region", self.name),
+        };
+
+        let credentials = match (&self.credentials, fallback) {
+            (Some(c), _) => c.to_owned(),
+            (None, Some(f)) => f.credentials.to_owned(),
+            (None, None) => Credentials {
+                access_key: None,
+                secret_key: None,
+                security_token: None,
+                session_token: None,
+                expiration: None,
+            },
+        };
+
+        Ok(S3 { bucket, region, credentials })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ExportFilter {
+    pub sizes: Option<Vec<String>>,
+    pub shapes: Option<Vec<String>>,
+    pub variants: Option<Vec<String>>,
+    /// Shorthand for leaving out the gray variant
+    pub gray: bool,
+}
+
+impl Default for ExportFilter {
+    fn default() -> Self {
+        Self { sizes: None, shapes: None, variants: None, gray: true }
+    }
+}
+
+impl ExportFilter {
+    pub fn size(&self, id: &str) -> bool {
+        match self.sizes {
+            Some(ref v) => v.iter().any(|s|s.eq(id)),
+            None => true,
+        }
+    }
+    pub fn shape(&self, id: &str) -> bool {
+        match self.shapes {
+            Some(ref v) => v.iter().any(|s|s.eq(id)),
+            None => true,
+        }
+    }
+    pub fn variant(&self, name: &str) -> bool {
+        if name == "gray" && !self.gray {
+            return false
+        }
+
+        match self.variants {
+            Some(ref v) => v.iter().any(|s|s.eq(name)),
+            None => true,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -38,6 +298,23 @@ pub struct Import {
     pub include: Option<Vec<PathBuf>>,
     #[serde(default)]
     pub exclude: Vec<PathBuf>,
+    /// Allowed source file extensions, known image formats if not set
+    pub extensions: Option<Vec<String>>,
+    /// Walk into hidden files and dirs
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub limits: SourceLimits,
+}
+
+/// Sources outside of these are skipped, decoders refuse to allocate more than `max_alloc` bytes.
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct SourceLimits {
+    pub max_pixels: Option<u64>,
+    pub max_dimension: Option<u32>,
+    pub min_dimension: Option<u32>,
+    pub max_file_size: Option<u64>,
+    pub max_alloc: Option<u64>,
 }
 
 #[derive(Debug, Clone, Deserialize, Default)]
@@ -48,8 +325,94 @@ pub struct Resize {
     pub md: Option<u32>,
     pub sm: Option<u32>,
     pub xs: Option<u32>,
+    #[serde(default)]
+    pub upscale: UpscalePolicy,
+    #[serde(default)]
+    pub filter: FilterPolicy,
+    #[serde(default)]
+    pub sharpen: SharpenPolicy,
+}
+
+/// What to do with a target size larger than the source image.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Upscale {
+    Skip,
+    #[default]
+    CopyLargest,
+    Upscale,
+    Alias,
+}
+
+/// Setting per target size, sizes not listed fall back to `default`.
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(bound = "T: Deserialize<'de> + Default")]
+pub struct PerSize<T> {
+    #[serde(default)]
+    pub default: T,
+    pub original: Option<T>,
+    pub xl: Option<T>,
+    pub lg: Option<T>,
+    pub md: Option<T>,
+    pub sm: Option<T>,
+    pub xs: Option<T>,
+}
+
+impl<T: Clone> PerSize<T> {
+    pub fn get(&self, id: &str) -> T {
+        let value = match id {
+            "og" => &self.original,
+            "xl" => &self.xl,
+            "lg" => &self.lg,
+            "md" => &self.md,
+            "sm" => &self.sm,
+            "xs" => &self.xs,
+            _ => &None,
+        };
+
+        value.as_ref().unwrap_or(&self.default).clone()
+    }
 }
 
+pub type UpscalePolicy = PerSize<Upscale>;
+
+/// Resampling filter used to resize.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Filter {
+    Nearest,
+    Box,
+    Bilinear,
+    Hamming,
+    CatmullRom,
+    Mitchell,
+    Gaussian,
+    #[default]
+    Lanczos3,
+    /// Box filter over supersampled nearest pixels, fast for thumbnails
+    SupersampledBox,
+}
+
+pub type FilterPolicy = PerSize<Filter>;
+
+/// Unsharp mask applied after resizing.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Sharpen {
+    pub amount: f32,
+    /// Gaussian blur sigma in pixels
+    #[serde(default = "default_sharpen_radius")]
+    pub radius: f32,
+    /// Smallest difference in 8 bit levels that gets sharpened
+    #[serde(default)]
+    pub threshold: u8,
+}
+
+fn default_sharpen_radius() -> f32 {
+    1.0
+}
+
+pub type SharpenPolicy = PerSize<Option<Sharpen>>;
+
 #[derive(Debug, Clone, Deserialize, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum TransformVariant {
@@ -63,6 +426,269 @@ pub enum TransformVariant {
     Xs,
 }
 
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnimationMode {
+    Animate,
+    #[default]
+    Poster,
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnimationFormat {
+    #[default]
+    Webp,
+    Gif,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct Animation {
+    #[serde(default)]
+    pub mode: AnimationMode,
+    pub shapes: Option<AnimationMode>,
+    #[serde(default)]
+    pub format: AnimationFormat,
+    #[serde(default)]
+    pub poster_frame: usize,
+}
+
+impl Animation {
+    pub fn shapes(&self) -> AnimationMode {
+        self.shapes.unwrap_or(self.mode)
+    }
+}
+
+/// Color space of the exported images.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorSpace {
+    #[default]
+    Srgb,
+    DisplayP3,
+    AdobeRgb,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct Color {
+    #[serde(default)]
+    pub space: ColorSpace,
+    /// Downscale in linear light instead of gamma encoded values
+    #[serde(default)]
+    pub linear: bool,
+}
+
+/// Outline of a user defined shape, coordinates are normalized to the 0..1 square.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outline {
+    /// Points of a polygon, `[x, y]` pairs
+    Polygon(Vec<[f32; 2]>),
+    /// SVG path data
+    Path(String),
+    /// SVG file used as a mask, relative to the config dir
+    File(PathBuf),
+    /// Regular polygon, the first corner points up
+    Ngon { sides: u32 },
+    /// Star with `inner` as the ratio of the inner to the outer radius
+    Star {
+        points: u32,
+        #[serde(default = "default_star_inner")]
+        inner: f32,
+    },
+    /// Rectangle with rounded corners, `ratio` is height to width
+    Rect {
+        #[serde(default)]
+        radius: f32,
+        #[serde(default = "default_ratio")]
+        ratio: f32,
+    },
+    /// Superellipse, 2 is a circle and larger exponents get closer to a square
+    Squircle {
+        #[serde(default = "default_squircle_exponent")]
+        exponent: f32,
+    },
+    Heart {},
+    /// `ratio` is height to width
+    Ellipse {
+        #[serde(default = "default_ratio")]
+        ratio: f32,
+    },
+}
+
+fn default_star_inner() -> f32 {
+    0.5
+}
+
+fn default_ratio() -> f32 {
+    1.
+}
+
+fn default_squircle_exponent() -> f32 {
+    4.
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShapeDef {
+    pub name: String,
+    #[serde(flatten)]
+    pub outline: Outline,
+    /// Coordinate system of `path` as `[x, y, width, height]`, defaults to the 0..1 square
+    pub viewbox: Option<[f32; 4]>,
+    /// Clockwise degrees around the center
+    #[serde(default)]
+    pub rotation: f32,
+    /// Crop the empty canvas around the shape
+    #[serde(default = "default_true")]
+    pub trim: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct Shapes {
+    /// Built-in shapes to make, all of them if not set
+    pub presets: Option<Vec<String>>,
+    #[serde(default)]
+    pub custom: Vec<ShapeDef>,
+    /// Decoration by shape name, `"*"` for every shape without its own
+    #[serde(default)]
+    pub decoration: HashMap<String, Decoration>,
+}
+
+impl Shapes {
+    pub fn decoration(&self, name: &str) -> Option<&Decoration> {
+        self.decoration.get(name).or_else(|| self.decoration.get("*"))
+    }
+}
+
+/// Lengths are fractions of the shape size so the decoration scales with the output.
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct Decoration {
+    pub stroke: Option<Stroke>,
+    /// Gap between the shape edge and the image
+    #[serde(default)]
+    pub padding: f32,
+    /// Color of the padding, transparent if not set
+    pub ring: Option<HexColor>,
+    pub shadow: Option<Shadow>,
+}
+
+/// Outline drawn around the shape.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Stroke {
+    pub width: f32,
+    pub color: HexColor,
+}
+
+fn default_shadow_opacity() -> f32 {
+    0.5
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Shadow {
+    /// `[x, y]`, positive moves the shadow right and down
+    #[serde(default)]
+    pub offset: [f32; 2],
+    /// Gaussian blur sigma
+    #[serde(default)]
+    pub blur: f32,
+    #[serde(default)]
+    pub color: HexColor,
+    #[serde(default = "default_shadow_opacity")]
+    pub opacity: f32,
+}
+
+/// `#rrggbb` color of the effects and shape decoration, black by default.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Default)]
+#[serde(try_from = "String")]
+pub struct HexColor(pub [u8; 3]);
+
+impl TryFrom<String> for HexColor {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let hex = value.trim_start_matches('#');
+
+        if hex.len() != 6 || !hex.is_ascii() {
+            bail!("Color {:?} is not in #rrggbb format", value)
+        }
+
+        let mut rgb = [0u8; 3];
+
+        for (i, c) in rgb.iter_mut().enumerate() {
+            *c = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| anyhow!("Color {:?} is not in #rrggbb format", value))?;
+        }
+
+        Ok(Self(rgb))
+    }
+}
+
+fn default_tint_amount() -> f32 {
+    0.5
+}
+
+/// Color effect of a variant, applied after resizing.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    Grayscale,
+    Sepia,
+    /// Shadows to the first color, highlights to the second
+    Duotone([HexColor; 2]),
+    /// Gaussian blur sigma in pixels
+    Blur(f32),
+    /// Added to every channel in 8 bit levels, negative darkens
+    Brightness(i32),
+    /// Percentage, negative reduces contrast
+    Contrast(f32),
+    Tint {
+        color: HexColor,
+        #[serde(default = "default_tint_amount")]
+        amount: f32,
+    },
+    /// Block size in pixels
+    Pixelate(u32),
+}
+
+/// Named output variant, the resized images with the effects applied in order.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Variant {
+    pub name: String,
+    pub filters: Vec<Effect>,
+    /// Sizes the variant is made in, all sizes if not set
+    pub sizes: Option<Vec<String>>,
+    /// Shapes the variant is made of, none if not set
+    #[serde(default)]
+    pub shapes: Vec<String>,
+}
+
+impl Variant {
+    pub fn size(&self, id: &str) -> bool {
+        match self.sizes {
+            Some(ref v) => v.iter().any(|s|s.eq(id)),
+            None => true,
+        }
+    }
+    pub fn shape(&self, id: &str) -> bool {
+        self.shapes.iter().any(|s|s.eq(id) || s.eq("*"))
+    }
+}
+
+/// Gray copies of the cropped sizes, used when no variants are configured.
+fn default_variants() -> Vec<Variant> {
+    vec![Variant {
+        name: "gray".to_string(),
+        filters: vec![Effect::Grayscale],
+        sizes: Some(vec!["md".to_string(), "sm".to_string(), "xs".to_string()]),
+        shapes: vec![],
+    }]
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     #[serde(skip)]
@@ -72,6 +698,14 @@ pub struct Config {
     pub resize: Resize,
     #[serde(default)]
     pub transform_variant: TransformVariant,
+    #[serde(default)]
+    pub animation: Animation,
+    #[serde(default)]
+    pub color: Color,
+    #[serde(default = "default_variants")]
+    pub variants: Vec<Variant>,
+    #[serde(default)]
+    pub shapes: Shapes,
     pub import: Option<Import>,
     pub export: Option<Export>,
     pub server: Option<Server>,
@@ -107,9 +741,27 @@ impl Config {
 
         // Add decided dir path to config
         config.dir = dir;
+        config.check_variants()?;
 
         Ok(config)
     }
+    /// Variant names end up in the output paths, they have to be unique path components.
+    fn check_variants(&self) -> Result<()> {
+        for (i, v) in self.variants.iter().enumerate() {
+            match v.name.as_str() {
+                "" | "." | ".." | "shapes" => bail!("Variant name {:?} is not allowed", v.name),
+                n if n.contains(['/', '\\']) => bail!("Variant name {:?} can't contain path separators", n),
+                n if self.variants[..i].iter().any(|o|o.name.eq(n)) => bail!("Variant {:?} is defined twice", n),
+                _ => (),
+            }
+
+            if v.filters.is_empty() {
+                bail!("Variant {:?} has no filters", v.name)
+            }
+        }
+
+        Ok(())
+    }
     pub fn import(&self) -> Result<Import> {
         self.import.clone().ok_or_else(|| anyhow!("Import config not defined"))
     }
@@ -0,0 +1,117 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub key: String,
+    pub checksum: u32,
+    /// Dimensions of the encoded image, missing from manifests of older runs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+}
+
+/// Maps logical output names (`source_files/one/two/chili/md`) to the physical
+/// filesystem path or object key currently holding the image.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest(pub BTreeMap<String, ManifestEntry>);
+
+impl Manifest {
+    pub const FILENAME: &'static str = "manifest.json";
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        serde_json::from_slice(bytes).map_err(|e| anyhow!("Unable to read manifest as json: {}", e))
+    }
+    pub fn to_vec(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec_pretty(self).map_err(|e| anyhow!("Unable to write manifest as json: {}", e))
+    }
+    /// Logical name is the output path without file extension, always separated by `/`.
+    pub fn logical_name(path: &Path) -> String {
+        path.with_extension("")
+            .iter()
+            .map(|c|c.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+    pub fn get(&self, name: &str) -> Option<&ManifestEntry> {
+        self.0.get(name)
+    }
+    /// Returns the key the entry pointed to before, if no entry points to it anymore.
+    pub fn insert(&mut self, name: String, key: String, checksum: u32, dimensions: Option<(u32, u32)>)
+    -> Option<String> {
+        let (width, height) = dimensions.unzip();
+
+        let replaced = self.0.insert(name, ManifestEntry { key: key.to_owned(), checksum, width, height })?;
+
+        // Aliases and equal content share keys
+        match replaced.key != key && self.0.values().all(|e| e.key != replaced.key) {
+            true => Some(replaced.key),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_new_name() {
+        let mut m = Manifest::default();
+
+        assert_eq!(m.insert("a/md".into(), "a/md.1.jpeg".into(), 1, Some((800, 600))), None);
+
+        let e = m.get("a/md").unwrap();
+        assert_eq!((e.key.as_str(), e.checksum, e.width, e.height), ("a/md.1.jpeg", 1, Some(800), Some(600)));
+    }
+
+    #[test]
+    fn insert_returns_replaced_key() {
+        let mut m = Manifest::default();
+        m.insert("a/md".into(), "a/md.1.jpeg".into(), 1, None);
+
+        assert_eq!(m.insert("a/md".into(), "a/md.2.jpeg".into(), 2, None), Some("a/md.1.jpeg".into()));
+        assert_eq!(m.get("a/md").unwrap().key, "a/md.2.jpeg");
+    }
+
+    #[test]
+    fn insert_same_key_keeps_it() {
+        let mut m = Manifest::default();
+        m.insert("a/md".into(), "a/md.1.jpeg".into(), 1, None);
+
+        assert_eq!(m.insert("a/md".into(), "a/md.1.jpeg".into(), 1, None), None);
+    }
+
+    #[test]
+    fn insert_keeps_shared_keys() {
+        let mut m = Manifest::default();
+        m.insert("a/xl".into(), "a/xl.1.jpeg".into(), 1, None);
+        m.insert("a/og".into(), "a/xl.1.jpeg".into(), 1, None);
+
+        // Alias still points to the old output
+        assert_eq!(m.insert("a/xl".into(), "a/xl.2.jpeg".into(), 2, None), None);
+
+        // Last reference gone
+        assert_eq!(m.insert("a/og".into(), "a/xl.2.jpeg".into(), 2, None), Some("a/xl.1.jpeg".into()));
+    }
+
+    #[test]
+    fn logical_name_drops_extension() {
+        assert_eq!(Manifest::logical_name(Path::new("a/b/md.jpeg")), "a/b/md");
+        assert_eq!(Manifest::logical_name(Path::new("a/b/md")), "a/b/md");
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut m = Manifest::default();
+        m.insert("a/md".into(), "a/md.1.jpeg".into(), 7, None);
+
+        let read = Manifest::from_slice(&m.to_vec().unwrap()).unwrap();
+        assert_eq!(read.get("a/md").unwrap().checksum, 7);
+        assert!(Manifest::from_slice(b"[").is_err());
+    }
+}
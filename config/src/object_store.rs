@@ -1,5 +1,5 @@
 use std::env;
-use std::path::PathBuf;
+use std::path::Path;
 use anyhow::{Result, anyhow, bail};
 use s3::error::S3Error;
 use s3::{Bucket, BucketConfiguration, Tag};
@@ -21,9 +21,9 @@ impl ObjectStore {
             config.credentials.secret_key = env::var("S3_SECRET_KEY").ok();
         }
 
-        let mut store = Self::get(&config)?;
+        let mut store = Self::get(config)?;
 
-        store.exists(&config.bucket, &config, create).await
+        store.exists(&config.bucket, config, create).await
     }
     pub fn get(config: &S3Config) -> Result<Self> {
         let bucket = Bucket::new(&config.bucket, config.region.clone(), config.credentials.clone())
@@ -77,17 +77,29 @@ impl ObjectStore {
     }
     pub async fn get_tags<P>(&self, key: P) -> Result<Vec<Tag>> where P: AsRef<str> {
         match self.0.get_object_tagging(&key).await {
-            Ok((v, _)) => return Ok(v),
+            Ok((v, _)) => Ok(v),
             Err(e) => match e {
                 S3Error::Http(c, s) => match c {
-                    404 => return Ok(vec![]),
+                    404 => Ok(vec![]),
                     _ => bail!("Get tags http error: {} ({})", s, c),
                 },
                 _ => bail!("Get tags error: {}", e),
             }
         }
     }
-    pub async fn tag_source(&self, checksum: &String, key: &PathBuf) -> Result<()> {
+    pub async fn get_bytes<P>(&self, key: P) -> Result<Option<Vec<u8>>> where P: AsRef<str> {
+        match self.0.get_object(&key).await {
+            Ok(v) => Ok(Some(v.to_vec())),
+            Err(e) => match e {
+                S3Error::Http(c, s) => match c {
+                    404 => Ok(None),
+                    _ => bail!("Get object http error: {} ({})", s, c),
+                },
+                _ => bail!("Get object error: {}", e),
+            }
+        }
+    }
+    pub async fn tag_source(&self, checksum: &str, key: &Path) -> Result<()> {
         let path = key.to_string_lossy();
     
         let response = self.0.put_object_tagging(path.as_ref(), &[
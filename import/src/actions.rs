@@ -1,27 +1,64 @@
 
 use std::path::PathBuf;
+use std::sync::Arc;
 use anyhow::{bail, Result};
-use config::{Config, ObjectStore};
+use config::{Animation, AnimationFormat, AnimationMode, Config};
 use fast_image_resize::{ResizeOptions, SrcCropping};
 use fast_image_resize::PixelType;
+use image::{imageops, io::Limits, DynamicImage, Frame};
 
+use super::resize::gif::*;
 use super::resize::jpeg::*;
 use super::resize::png::*;
-use crate::transform::{
-    cross_from_rect, hexagonal_from_rect, multi_rect_horizontal, round_from_rect, septagonal_from_rect,
-    sq45_from_rect, star_from_rect, transformable_img, triangle_down, triangle_left, triangle_right,
-    triangle_up, ScaleRef
-};
-use crate::{resize_handler, ResizeStats, TargetSize};
-
-
-pub async fn resize_action(importable: PathBuf, target_path: PathBuf, config: Config, fs_root: Option<PathBuf>, s3_store: Option<ObjectStore>)
+use super::resize::webp::*;
+use crate::decode::{is_limit_error, SourcePolicy};
+use crate::export::OutputTarget;
+use crate::template::{OutputName, PathTemplate};
+use crate::transform::{transformable_img, OutputKind, ScaleRef, Shape, Transformable, SHAPES_VARIANT};
+use crate::resize::upscale::{link_handler, UpscalePlan};
+use crate::{animation_handler, resize_handler, ResizeStats, TargetSize};
+
+pub async fn resize_action(importable: PathBuf, name: OutputName, config: Config, template: PathTemplate,
+targets: Vec<OutputTarget>)
 -> Result<ResizeStats> {
-    let (transformable, img) = transformable_img(importable, target_path).await?;
-
     // Create Resizer instance and resize source image
     // into buffer of destination image
     let mut stats = ResizeStats::new();
+    let policy = SourcePolicy::from_config(&config);
+
+    // Sources outside of the limits are skipped before and after decoding
+    if let Some(reason) = policy.check(&importable)? {
+        stats.skipped.push(reason);
+
+        return Ok(stats)
+    }
+
+    let (mut transformable, mut img) = match transformable_img(
+        importable.clone(),
+        name,
+        template,
+        policy.limits(),
+        config.color.clone(),
+        config.animation.mode,
+        config.animation.poster_frame,
+    ).await {
+        Ok(t) => t,
+        Err(e) if is_limit_error(&e) => {
+            stats.skipped.push(format!("{:?}: {}", importable, e));
+
+            return Ok(stats)
+        },
+        Err(e) => return Err(e),
+    };
+
+    transformable.filter = config.resize.filter.clone();
+    transformable.sharpen = config.resize.sharpen.clone();
+
+    if let Some(reason) = policy.check_dimensions(&importable, img.width(), img.height()) {
+        stats.skipped.push(reason);
+
+        return Ok(stats)
+    }
     
     // Resize options for normal size images
     let resize_opts = Some(ResizeOptions::new());
@@ -64,227 +101,118 @@ pub async fn resize_action(importable: PathBuf, target_path: PathBuf, config: Co
         Some(o)
     };
 
-    // Create grayscale PNG image
-    let gray_img = img.grayscale();
-    let mut transformable_gray = transformable.clone();
-    transformable_gray.target_path = {
-        let mut p = transformable_gray.target_path.clone();
-        p.push("gray");
-        p
-    };
-
-    // Sizes that should not be cropped
-    let resize_multiple = [
+    let sizes = [
         (og.to_px(), og.to_str()),
         (xl.to_px(), xl.to_str()),
         (lg.to_px(), lg.to_str()),
+        (md.to_px(), md.to_str()),
+        (sm.to_px(), sm.to_str()),
+        (xs.to_px(), xs.to_str()),
     ];
 
-    // ..sizes that will be cropped
-    let md_ratio = [(md.to_px(), md.to_str())];
-    let sm_ratio = [(sm.to_px(), sm.to_str())];
-    let xs_ratio = [(xs.to_px(), xs.to_str())];
+    // Sizes that should not be cropped and the cropped ones with their own crop
+    let groups = [
+        (&sizes[..3], &resize_opts),
+        (&sizes[3..4], &crop_opts_md),
+        (&sizes[4..5], &crop_opts_sm),
+        (&sizes[5..], &crop_opts_xs),
+    ];
 
-    match transformable.target_ext {
-        "png" => {
-            // Normal resize for PNG images
-            let handler = resize_handler(
-                &resize_multiple,
-                &transformable,
-                &img,
-                &resize_opts,
-                &s3_store,
-                &fs_root,
-                png_writer,
-            );
-            stats.extend(handler.await);
-
-            // Set transparent pixel for grayscale PNG image
-            transformable_gray.pixel = PixelType::U8x2;
-
-            // Crop and resize 'md' size PNG image
-            let handler = resize_handler(
-                &md_ratio,
-                &transformable,
-                &img,
-                &crop_opts_md,
-                &s3_store,
-                &fs_root,
-                png_writer,
-            );
-            stats.extend(handler.await);
-
-            // Crop and resize 'md' size grayscale PNG image
-            let handler = resize_handler(
-                &md_ratio,
-                &transformable_gray,
-                &gray_img,
-                &crop_opts_md,
-                &s3_store,
-                &fs_root,
-                png_gray_writer,
-            );
-            stats.extend(handler.await);
-            
-            // Crop and resize 'sm' size PNG image
-            let handler = resize_handler(
-                &sm_ratio,
-                &transformable,
-                &img,
-                &crop_opts_sm,
-                &s3_store,
-                &fs_root,
-                png_writer,
-            );
-            stats.extend(handler.await);
-
-            // Crop and resize 'sm' size grayscale PNG image
-            let handler = resize_handler(
-                &sm_ratio,
-                &transformable_gray,
-                &gray_img,
-                &crop_opts_sm,
-                &s3_store,
-                &fs_root,
-                png_gray_writer,
-            );
-            stats.extend(handler.await);
-
-            // Crop and resize 'xs' size PNG image
-            let handler = resize_handler(
-                &xs_ratio,
-                &transformable,
-                &img,
-                &crop_opts_xs,
-                &s3_store,
-                &fs_root,
-                png_writer,
-            );
-            stats.extend(handler.await);
-
-            // Crop and resize 'xs' size grayscale PNG image
-            let handler = resize_handler(
-                &xs_ratio,
-                &transformable_gray,
-                &gray_img,
-                &crop_opts_xs,
-                &s3_store,
-                &fs_root,
-                png_gray_writer,
-            );
-            stats.extend(handler.await);
-        },
-        "jpeg" => {
-            // Normal resize for JPEG images
-            let handler = resize_handler(
-                &resize_multiple,
-                &transformable,
-                &img,
-                &resize_opts,
-                &s3_store,
-                &fs_root,
-                jpeg_writer,
-            );
-            stats.extend(handler.await);
-
-            // Set pixel for grayscale PNG image
-            transformable_gray.pixel = PixelType::U8;
-
-            // Crop and resize 'md' size JPEG image
-            let handler = resize_handler(
-                &md_ratio,
-                &transformable,
-                &img,
-                &crop_opts_md,
-                &s3_store,
-                &fs_root,
-                jpeg_writer,
-            );
-            stats.extend(handler.await);
-
-            // Crop and resize 'md' size grayscale JPEG image
-            let handler = resize_handler(
-                &md_ratio,
-                &transformable_gray,
-                &gray_img,
-                &crop_opts_md,
-                &s3_store,
-                &fs_root,
-                jpeg_gray_writer,
-            );
-            stats.extend(handler.await);
-
-            // Crop and resize 'sm' size JPEG image
-            let handler = resize_handler(
-                &sm_ratio,
-                &transformable,
-                &img,
-                &crop_opts_sm,
-                &s3_store,
-                &fs_root,
-                jpeg_writer,
-            );
-            stats.extend(handler.await);
-
-            // Crop and resize 'sm' size grayscale JPEG image
-            let handler = resize_handler(
-                &sm_ratio,
-                &transformable_gray,
-                &gray_img,
-                &crop_opts_sm,
-                &s3_store,
-                &fs_root,
-                jpeg_gray_writer,
-            );
-            stats.extend(handler.await);
-
-            // Crop and resize 'xs' size JPEG image
-            let handler = resize_handler(
-                &xs_ratio,
-                &transformable,
-                &img,
-                &crop_opts_xs,
-                &s3_store,
-                &fs_root,
-                jpeg_writer,
-            );
-            stats.extend(handler.await);
-
-            // Crop and resize 'xs' size grayscale JPEG image
-            let handler = resize_handler(
-                &xs_ratio,
-                &transformable_gray,
-                &gray_img,
-                &crop_opts_xs,
-                &s3_store,
-                &fs_root,
-                jpeg_gray_writer,
-            );
-            stats.extend(handler.await);
-        },
-        _ => bail!("Unsupported image format: {:?}", transformable.target_ext),
+    // Sizes larger than the source are skipped, upscaled, copied or aliased. A cropped size
+    // looks nothing like an uncropped one, so links stay within their crop group.
+    let plans = groups.iter()
+        .map(|(g, o)|UpscalePlan::new(&transformable, g, cropped(o), &config.resize.upscale))
+        .collect::<Vec<_>>();
+
+    transformable.upscale = UpscalePlan::upscaled(&plans);
+
+    // Animated sources are either resized frame by frame, reduced to a still or left alone
+    if let Some(count) = transformable.frames.as_ref().map(|f|f.count()) {
+        match config.animation.mode {
+            AnimationMode::Skip => {
+                stats.skipped.push(format!(
+                    "Image {:?} skipped, it's animated with {} frames and animation mode is 'skip'",
+                    transformable.source_path, count
+                ));
+
+                return Ok(stats)
+            },
+            AnimationMode::Poster => {
+                img = transformable.poster(config.animation.poster_frame, config.color.space)?;
+            },
+            AnimationMode::Animate => {
+                transformable.target_ext = animation_ext(&config.animation);
+                transformable.pixel = PixelType::U8x4;
+            },
+        }
+    }
+
+    // Every variant has its own sizes and so its own upscale plans
+    let mut outputs = vec![(transformable.clone(), plans, None)];
+
+    for v in config.variants.iter() {
+        let variant_plans = groups.iter()
+            .map(|(g, o)|{
+                let variant_sizes = g.iter()
+                    .filter(|(_, id)|v.size(id))
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                UpscalePlan::new(&transformable, &variant_sizes, cropped(o), &config.resize.upscale)
+            })
+            .collect::<Vec<_>>();
+
+        let mut transformable_variant = transformable.variant(v);
+        transformable_variant.upscale = UpscalePlan::upscaled(&variant_plans);
+
+        outputs.push((transformable_variant, variant_plans, Some(v)));
+    }
+
+    for (t, plans, v) in outputs.iter() {
+        for ((group, opts), p) in groups.iter().zip(plans.iter()) {
+            let items = p.resized(group)
+                .into_iter()
+                .filter(|(_, id)|v.map(|v|v.size(id)).unwrap_or(true))
+                .collect::<Vec<_>>();
+
+            // Frames are still there only when animating
+            match t.frames.is_some() {
+                true => stats.extend(animate(&items, t, opts, &targets, &config.animation).await),
+                false => stats.extend(resize(&items, t, &img, opts, &targets).await),
+            }
+        }
+    }
+
+    // Oversized sizes point to the largest real output once it's written
+    for (t, plans, _) in outputs.iter() {
+        for p in plans.iter() {
+            stats.extend(link_handler(p, t, &targets).await);
+        }
     }
 
     Ok(stats)
 }
 
-pub async fn transform_action(importable: PathBuf, target_path: PathBuf, size: TargetSize, fs_root: Option<PathBuf>,
-s3_store: Option<ObjectStore>)
+pub async fn transform_action(importable: PathBuf, name: OutputName, size: TargetSize, template: PathTemplate,
+config: Config, shapes: Arc<Vec<Shape>>, targets: Vec<OutputTarget>)
 -> Result<ResizeStats> {
-    let (mut transformable, img) = transformable_img(
+    let (mut transformable, mut img) = transformable_img(
         importable,
-        target_path
+        name,
+        template,
+        Limits::default(),
+        config.color.clone(),
+        config.animation.shapes(),
+        config.animation.poster_frame,
     ).await?;
+    let animation = config.animation;
 
-    // Since we use already resized image as a source our target paths and names are all cocked up
-    let mut target_path = transformable.target_path.clone();
+    // Shapes are resized with the filter and sharpening of the transform size
+    transformable.filter.default = config.resize.filter.get(size.to_str());
+    transformable.sharpen.default = config.resize.sharpen.get(size.to_str());
 
-    // Has the 'target size' as the parent element in the path, take it away
-    target_path.pop();
-
-    // Add shapes dir to collect all transformed images
-    target_path.push("shapes");
-    transformable.target_path = target_path;
+    // Add shapes variant to collect all transformed images
+    transformable.name.variant = Some(SHAPES_VARIANT.to_string());
 
     // Create Resizer instance and resize source image
     // into buffer of destination image
@@ -312,205 +240,115 @@ s3_store: Option<ObjectStore>)
     transformable.scale = ScaleRef::Fixed(rect_side, rect_side);
     transformable.pixel = PixelType::U8x4;
     transformable.target_ext = "png";
+    transformable.output = OutputKind::Shape;
+
+    // Shapes of an animated source follow their own animation mode
+    if let Some(frames) = transformable.frames.clone() {
+        match animation.shapes() {
+            AnimationMode::Skip => {
+                stats.skipped.push(format!(
+                    "Shapes for {:?} skipped, it's animated with {} frames and shape animation mode is 'skip'",
+                    transformable.source_path, frames.count()
+                ));
+
+                return Ok(stats)
+            },
+            AnimationMode::Poster => {
+                img = transformable.poster(animation.poster_frame, config.color.space)?;
+            },
+            AnimationMode::Animate => {
+                transformable.target_ext = animation_ext(&animation);
+
+                // Every frame is cut into every shape
+                let squares = frames.iter()
+                    .map(|f|imageops::crop_imm(f.buffer(), x, y, rect_side, rect_side).to_image())
+                    .collect::<Vec<_>>();
+
+                for shape in shapes.iter() {
+                    let id = shape.name.as_str();
+                    let mask = shape.mask(rect_side)?;
+                    let mut shaped = None;
+                    let mut shaped_frames = vec![];
+
+                    for (square, f) in squares.iter().zip(frames.iter()) {
+                        let (img_shape, transf_shape) = mask.cut(square, shape.trim, &transformable);
+
+                        shaped_frames.push(Frame::from_parts(img_shape.to_rgba8(), 0, 0, f.delay()));
+                        shaped.get_or_insert(transf_shape);
+                    }
+
+                    if let Some(mut transf_shape) = shaped {
+                        transf_shape.frames = Some(shaped_frames.into());
+
+                        let ratio = [(size.to_px(), id)];
+                        stats.extend(animate(&ratio, &transf_shape, &resize_opts, &targets, &animation).await);
+
+                        for v in config.variants.iter().filter(|v|v.shape(id)) {
+                            stats.extend(animate(&ratio, &transf_shape.variant(v), &resize_opts, &targets, &animation).await);
+                        }
+                    }
+                }
+
+                return Ok(stats)
+            },
+        }
+    }
     
     // Create dynamic square image and read its contents to buffer
     let img_square = img.clone();
     let img_square_buf = img_square.crop_imm(x, y, rect_side, rect_side).to_rgba8();
 
-    // Crop and resize 'sm' size ROUND PNG image
-    let img_circle = round_from_rect(&img_square_buf, rect_side);
-    let ratio = [(size.to_px(), "round")];
-
-    let handler = resize_handler(
-        &ratio,
-        &transformable,
-        &img_circle,
-        &resize_opts,
-        &s3_store,
-        &fs_root,
-        png_writer,
-    );
-    stats.extend(handler.await);
-
-    // Cut hexagonal image from the square image
-    let (cropped_hex, img_hex_transf) = hexagonal_from_rect(&img_square_buf, rect_side, &transformable);
-    let ratio = [(size.to_px(), "hex")];
-
-    let handler = resize_handler(
-        &ratio,
-        &img_hex_transf,
-        &cropped_hex,
-        &resize_opts,
-        &s3_store,
-        &fs_root,
-        png_writer,
-    );
-    stats.extend(handler.await);
-
-    // Cut septagonal image from the square image
-    let cropped_sep = septagonal_from_rect(&img_square_buf, rect_side);
-    let ratio = [(size.to_px(), "sep")];
-
-    let handler = resize_handler(
-        &ratio,
-        &transformable,
-        &cropped_sep,
-        &resize_opts,
-        &s3_store,
-        &fs_root,
-        png_writer,
-    );
-    stats.extend(handler.await);
-
-    // Crop and resize 'sm' size 45 DEGREE ANGLE TILTED square PNG image
-    let cropped_sq45 = sq45_from_rect(&img_square_buf, rect_side);
-    let ratio = [(size.to_px(), "sq45")];
-
-    let handler = resize_handler(
-        &ratio,
-        &transformable,
-        &cropped_sq45,
-        &resize_opts,
-        &s3_store,
-        &fs_root,
-        png_writer,
-    );
-    stats.extend(handler.await);
-
-    // Crop and resize 'sm' size triangle RIGHT PNG image
-    let (cropped_triangle, transf_triangle) = triangle_right(&img_square_buf, rect_side, &transformable);
-    let ratio = [(size.to_px(), "right")];
-
-    let handler = resize_handler(
-        &ratio,
-        &transf_triangle,
-        &cropped_triangle,
-        &resize_opts,
-        &s3_store,
-        &fs_root,
-        png_writer,
-    );
-    stats.extend(handler.await);
-
-    // Crop and resize 'sm' size triangle LEFT PNG image
-    let (cropped_triangle, transf_triangle) = triangle_left(&img_square_buf, rect_side, &transformable);
-    let ratio = [(size.to_px(), "left")];
-
-    let handler = resize_handler(
-        &ratio,
-        &transf_triangle,
-        &cropped_triangle,
-        &resize_opts,
-        &s3_store,
-        &fs_root,
-        png_writer,
-    );
-    stats.extend(handler.await);
-
-    // Crop and resize 'sm' size triangle DOWN PNG image
-    let (cropped_triangle, transf_triangle) = triangle_down(&img_square_buf, rect_side, &transformable);
-    let ratio = [(size.to_px(), "down")];
-
-    let handler = resize_handler(
-        &ratio,
-        &transf_triangle,
-        &cropped_triangle,
-        &resize_opts,
-        &s3_store,
-        &fs_root,
-        png_writer,
-    );
-    stats.extend(handler.await);
-
-    // Crop and resize 'sm' size triangle UP PNG image
-    let (cropped_triangle, transf_triangle) = triangle_up(&img_square_buf, rect_side, &transformable);
-    let ratio = [(size.to_px(), "up")];
-
-    let handler = resize_handler(
-        &ratio,
-        &transf_triangle,
-        &cropped_triangle,
-        &resize_opts,
-        &s3_store,
-        &fs_root,
-        png_writer,
-    );
-    stats.extend(handler.await);
-
-    // Crop and resize 'sm' size 2 HORIZONTAL RECTANGLES PNG image
-    let cropped_rect = multi_rect_horizontal(&img_square_buf, rect_side, 2);
-    let ratio = [(size.to_px(), "row2")];
-
-    let handler = resize_handler(
-        &ratio,
-        &transformable,
-        &cropped_rect,
-        &resize_opts,
-        &s3_store,
-        &fs_root,
-        png_writer,
-    );
-    stats.extend(handler.await);
-
-    // Crop and resize 'sm' size 3 HORIZONTAL RECTANGLES PNG image
-    let cropped_rect = multi_rect_horizontal(&img_square_buf, rect_side, 3);
-    let ratio = [(size.to_px(), "row3")];
-
-    let handler = resize_handler(
-        &ratio,
-        &transformable,
-        &cropped_rect,
-        &resize_opts,
-        &s3_store,
-        &fs_root,
-        png_writer,
-    );
-    stats.extend(handler.await);
-    
-        // Crop and resize 'sm' size 4 HORIZONTAL RECTANGLES PNG image
-        let cropped_rect = multi_rect_horizontal(&img_square_buf, rect_side, 4);
-        let ratio = [(size.to_px(), "row4")];
-    
+    // Cut every shape from the square image and resize it to the transform size
+    for shape in shapes.iter() {
+        let id = shape.name.as_str();
+        let (img_shape, transf_shape) = shape.mask(rect_side)?.cut(&img_square_buf, shape.trim, &transformable);
+        let ratio = [(size.to_px(), id)];
+
         let handler = resize_handler(
             &ratio,
-            &transformable,
-            &cropped_rect,
+            &transf_shape,
+            &img_shape,
             &resize_opts,
-            &s3_store,
-            &fs_root,
+            &targets,
             png_writer,
         );
         stats.extend(handler.await);
 
-    // Crop and resize 'sm' size CROSS PNG image
-    let cropped_cross = cross_from_rect(&img_square_buf, rect_side);
-    let ratio = [(size.to_px(), "cross")];
-
-    let handler = resize_handler(
-        &ratio,
-        &transformable,
-        &cropped_cross,
-        &resize_opts,
-        &s3_store,
-        &fs_root,
-        png_writer,
-    );
-    stats.extend(handler.await);
-
-    // Crop and resize 'sm' size STAR PNG image
-    let (cropped_star, transf_star) = star_from_rect(&img_square_buf, rect_side, &transformable);
-    let ratio = [(size.to_px(), "star")];
-
-    let handler = resize_handler(
-        &ratio,
-        &transf_star,
-        &cropped_star,
-        &resize_opts,
-        &s3_store,
-        &fs_root,
-        png_writer,
-    );
-    stats.extend(handler.await);
+        // Variants made of this shape
+        for v in config.variants.iter().filter(|v|v.shape(id)) {
+            stats.extend(resize(&ratio, &transf_shape.variant(v), &img_shape, &resize_opts, &targets).await);
+        }
+    }
 
     Ok(stats)
 }
+
+fn animation_ext(animation: &Animation) -> &'static str {
+    match animation.format {
+        AnimationFormat::Webp => "webp",
+        AnimationFormat::Gif => "gif",
+    }
+}
+
+fn cropped(options: &Option<ResizeOptions>) -> bool {
+    options.is_some_and(|o|!matches!(o.cropping, SrcCropping::None))
+}
+
+async fn animate(items: &[(u32, &str)], transformable: &Transformable, options: &Option<ResizeOptions>,
+targets: &[OutputTarget], animation: &Animation)
+-> Result<ResizeStats> {
+    match animation.format {
+        AnimationFormat::Webp => animation_handler(items, transformable, options, targets, webp_writer).await,
+        AnimationFormat::Gif => animation_handler(items, transformable, options, targets, gif_writer).await,
+    }
+}
+
+async fn resize(items: &[(u32, &str)], transformable: &Transformable, img: &DynamicImage, options: &Option<ResizeOptions>,
+targets: &[OutputTarget])
+-> Result<ResizeStats> {
+    match transformable.target_ext {
+        "png" => resize_handler(items, transformable, img, options, targets, png_writer).await,
+        "jpeg" => resize_handler(items, transformable, img, options, targets, jpeg_writer).await,
+        _ => bail!("Unsupported image format: {:?}", transformable.target_ext),
+    }
+}
@@ -0,0 +1,196 @@
+use std::path::Path;
+use anyhow::{anyhow, bail, Result};
+use config::ColorSpace;
+use image::{DynamicImage, ImageBuffer, Pixel, Rgb, Rgba, RgbImage, RgbaImage};
+use lcms2::{CIExyY, CIExyYTRIPLE, Flags, Intent, PixelFormat, Profile, ToneCurve, Transform};
+
+
+/// D65 white point shared by all the supported output spaces.
+const D65: CIExyY = CIExyY { x: 0.3127, y: 0.3290, Y: 1.0 };
+
+fn primaries(r: (f64, f64), g: (f64, f64), b: (f64, f64)) -> CIExyYTRIPLE {
+    CIExyYTRIPLE {
+        Red: CIExyY { x: r.0, y: r.1, Y: 1.0 },
+        Green: CIExyY { x: g.0, y: g.1, Y: 1.0 },
+        Blue: CIExyY { x: b.0, y: b.1, Y: 1.0 },
+    }
+}
+
+fn output_profile(space: ColorSpace) -> Result<Profile> {
+    let profile = match space {
+        ColorSpace::Srgb => return Ok(Profile::new_srgb()),
+        ColorSpace::DisplayP3 => {
+            // Display P3 uses the sRGB transfer function
+            let curve = ToneCurve::new_parametric(4, &[2.4, 1. / 1.055, 0.055 / 1.055, 1. / 12.92, 0.04045])
+                .map_err(|e| anyhow!("Failed to create sRGB tone curve: {}", e))?;
+
+            Profile::new_rgb(
+                &D65,
+                &primaries((0.680, 0.320), (0.265, 0.690), (0.150, 0.060)),
+                &[&curve, &curve, &curve],
+            )
+        },
+        ColorSpace::AdobeRgb => {
+            let curve = ToneCurve::new(563. / 256.);
+
+            Profile::new_rgb(
+                &D65,
+                &primaries((0.64, 0.33), (0.21, 0.71), (0.15, 0.06)),
+                &[&curve, &curve, &curve],
+            )
+        },
+    };
+
+    profile.map_err(|e| anyhow!("Failed to create {:?} color profile: {}", space, e))
+}
+
+/// ICC profile to embed into the outputs, sRGB is assumed without one.
+pub fn output_icc(space: ColorSpace) -> Result<Option<Vec<u8>>> {
+    match space {
+        ColorSpace::Srgb => Ok(None),
+        _ => output_profile(space)?
+            .icc()
+            .map(Some)
+            .map_err(|e| anyhow!("Failed to serialize {:?} color profile: {}", space, e)),
+    }
+}
+
+/// Profile declares CMYK data, the color space signature lives at bytes 16..20 of the header.
+pub fn is_cmyk(icc: &[u8]) -> bool {
+    icc.get(16..20) == Some(b"CMYK")
+}
+
+/// Convert the decoded image from its embedded profile, sRGB if there is none, into the output space.
+/// HDR float images are encoded into 16 bit sRGB first.
+pub fn convert(img: DynamicImage, icc: Option<&[u8]>, space: ColorSpace) -> Result<DynamicImage> {
+    let img = match img {
+        DynamicImage::ImageRgb32F(b) => DynamicImage::ImageRgb16(encode_hdr(b)?),
+        DynamicImage::ImageRgba32F(b) => DynamicImage::ImageRgba16(encode_hdr(b)?),
+        i => i,
+    };
+
+    // Nothing to convert
+    if icc.is_none() && space == ColorSpace::Srgb {
+        return Ok(img)
+    }
+
+    let source = match icc {
+        Some(i) => match Profile::new_icc(i) {
+            Ok(p) => p,
+            // Broken profiles are common enough, the pixels are still fine
+            Err(_) => Profile::new_srgb(),
+        },
+        None => Profile::new_srgb(),
+    };
+
+    // Gray profiles can't be transformed into RGB in place, those images are left as they are
+    if !matches!(source.color_space(), lcms2::ColorSpaceSignature::RgbData) {
+        return Ok(img)
+    }
+
+    let target = output_profile(space)?;
+
+    match img {
+        DynamicImage::ImageRgb8(mut b) => {
+            transform::<u8>(&source, PixelFormat::RGB_8, &target, &mut b)?;
+            Ok(DynamicImage::ImageRgb8(b))
+        },
+        DynamicImage::ImageRgba8(mut b) => {
+            transform::<u8>(&source, PixelFormat::RGBA_8, &target, &mut b)?;
+            Ok(DynamicImage::ImageRgba8(b))
+        },
+        DynamicImage::ImageRgb16(mut b) => {
+            transform::<[u16; 3]>(&source, PixelFormat::RGB_16, &target, bytemuck::cast_slice_mut(&mut b))?;
+            Ok(DynamicImage::ImageRgb16(b))
+        },
+        DynamicImage::ImageRgba16(mut b) => {
+            transform::<[u16; 4]>(&source, PixelFormat::RGBA_16, &target, bytemuck::cast_slice_mut(&mut b))?;
+            Ok(DynamicImage::ImageRgba16(b))
+        },
+        i => Ok(i),
+    }
+}
+
+fn transform<P: Copy + bytemuck::Pod>(source: &Profile, format: PixelFormat, target: &Profile, pixels: &mut [P]) -> Result<()> {
+    let t = Transform::<P, P>::new_flags(source, format, target, format, Intent::Perceptual, Flags::COPY_ALPHA)
+        .map_err(|e| anyhow!("Failed to create color transform: {}", e))?;
+
+    t.transform_in_place(pixels);
+
+    Ok(())
+}
+
+/// CMYK JPEGs are decoded without the naive RGB conversion and converted with their profile.
+pub fn decode_cmyk_jpeg(path: &Path, icc: &[u8], space: ColorSpace) -> Result<DynamicImage> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut decoder = jpeg_decoder::Decoder::new(file);
+    let pixels = decoder.decode()?;
+
+    let info = match decoder.info() {
+        Some(i) if i.pixel_format == jpeg_decoder::PixelFormat::CMYK32 => i,
+        _ => bail!("{:?}: JPEG with a CMYK profile doesn't have CMYK pixels", path),
+    };
+
+    let source = Profile::new_icc(icc).map_err(|e| anyhow!("{:?}: Broken CMYK profile: {}", path, e))?;
+    let target = output_profile(space)?;
+    let t = Transform::<u8, u8>::new(&source, PixelFormat::CMYK_8, &target, PixelFormat::RGB_8, Intent::Perceptual)
+        .map_err(|e| anyhow!("Failed to create CMYK color transform: {}", e))?;
+
+    let mut rgb = vec![0u8; info.width as usize * info.height as usize * 3];
+    t.transform_pixels(&pixels, &mut rgb);
+
+    match RgbImage::from_raw(info.width as u32, info.height as u32, rgb) {
+        Some(b) => Ok(DynamicImage::ImageRgb8(b)),
+        None => bail!("{:?}: Decoded JPEG doesn't match its dimensions", path),
+    }
+}
+
+/// Linear light floats into sRGB encoded 16 bit values, anything brighter than white is clipped.
+fn srgb_encode(v: f32) -> u16 {
+    let v = v.clamp(0., 1.);
+    let e = match v <= 0.0031308 {
+        true => v * 12.92,
+        false => 1.055 * v.powf(1. / 2.4) - 0.055,
+    };
+
+    (e * 65535.).round() as u16
+}
+
+fn encode_hdr<P>(buf: ImageBuffer<P, Vec<f32>>) -> Result<ImageBuffer<P::Converted, Vec<u16>>>
+where
+    P: Pixel<Subpixel = f32> + HdrPixel,
+{
+    let (w, h) = buf.dimensions();
+    let channels = P::CHANNEL_COUNT as usize;
+    let encoded = buf.into_raw()
+        .into_iter()
+        .enumerate()
+        // Alpha is linear already
+        .map(|(i, v)| match channels == 4 && i % 4 == 3 {
+            true => (v.clamp(0., 1.) * 65535.).round() as u16,
+            false => srgb_encode(v),
+        })
+        .collect();
+
+    ImageBuffer::from_raw(w, h, encoded).ok_or_else(|| anyhow!("HDR image doesn't match its dimensions"))
+}
+
+trait HdrPixel {
+    type Converted: Pixel<Subpixel = u16>;
+}
+
+impl HdrPixel for Rgb<f32> {
+    type Converted = Rgb<u16>;
+}
+
+impl HdrPixel for Rgba<f32> {
+    type Converted = Rgba<u16>;
+}
+
+/// Frames of animations are kept as 8 bit RGBA, only their colors are converted.
+pub fn convert_rgba8(buf: RgbaImage, icc: Option<&[u8]>, space: ColorSpace) -> Result<RgbaImage> {
+    match convert(DynamicImage::ImageRgba8(buf), icc, space)? {
+        DynamicImage::ImageRgba8(b) => Ok(b),
+        i => Ok(i.to_rgba8()),
+    }
+}
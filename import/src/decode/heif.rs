@@ -0,0 +1,46 @@
+use std::path::Path;
+use anyhow::{anyhow, bail, Result};
+use image::{io::Limits, DynamicImage, RgbImage, RgbaImage};
+use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+
+/// Image with its ICC profile, iPhone photos are Display P3.
+pub fn decode(path: &Path, limits: &Limits) -> Result<(DynamicImage, Option<Vec<u8>>)> {
+    let lib_heif = LibHeif::new();
+    let ctx = HeifContext::read_from_file(&path.to_string_lossy())?;
+    let handle = ctx.primary_image_handle()?;
+    limits.check_dimensions(handle.width(), handle.height())?;
+
+    // Pixels are decoded as they are, without converting them out of this profile
+    let icc = handle.color_profile_raw().map(|p| p.data);
+
+    let alpha = handle.has_alpha_channel();
+    let (chroma, channels) = match alpha {
+        true => (RgbChroma::Rgba, 4),
+        false => (RgbChroma::Rgb, 3),
+    };
+
+    let image = lib_heif.decode(&handle, ColorSpace::Rgb(chroma), None)?;
+    let planes = image.planes();
+    let plane = planes.interleaved
+        .ok_or_else(|| anyhow!("Decoded HEIF image has no interleaved plane"))?;
+
+    // Rows may be padded, copy only the pixels
+    let row = plane.width as usize * channels;
+    let mut buf = Vec::with_capacity(row * plane.height as usize);
+
+    for y in 0..plane.height as usize {
+        let start = y * plane.stride;
+        buf.extend_from_slice(&plane.data[start..start + row]);
+    }
+
+    let img = match alpha {
+        true => RgbaImage::from_raw(plane.width, plane.height, buf).map(DynamicImage::ImageRgba8),
+        false => RgbImage::from_raw(plane.width, plane.height, buf).map(DynamicImage::ImageRgb8),
+    };
+
+    match img {
+        Some(i) => Ok((i, icc)),
+        None => bail!("Decoded HEIF image buffer doesn't match its dimensions"),
+    }
+}
@@ -0,0 +1,237 @@
+#[cfg(feature = "raw")]
+mod raw;
+#[cfg(feature = "heif")]
+mod heif;
+mod policy;
+mod color;
+
+pub use policy::*;
+pub use color::*;
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use anyhow::{anyhow, bail, Result};
+use config::{AnimationMode, ColorSpace};
+use image::codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder};
+use image::error::{ImageError, LimitError, LimitErrorKind};
+use image::{io::Limits, io::Reader as ImageReader, AnimationDecoder, Delay, DynamicImage, Frame, ImageDecoder, ImageFormat, RgbaImage};
+use webp::AnimDecoder;
+
+
+pub const RAW_EXTENSIONS: &[&str] = &[
+    "3fr", "arw", "cr2", "cr3", "crw", "dcr", "dng", "erf", "iiq", "kdc", "mef", "mos", "mrw",
+    "nef", "nrw", "orf", "pef", "raf", "rw2", "rwl", "sr2", "srf", "srw", "x3f",
+];
+
+pub const HEIF_EXTENSIONS: &[&str] = &["heic", "heics", "heif", "heifs", "hif"];
+
+#[derive(Debug, Clone, Copy)]
+pub enum SourceFormat {
+    Image(ImageFormat),
+    Raw,
+    Heif,
+}
+
+impl SourceFormat {
+    /// Camera formats can't be sniffed reliably (most RAWs look like TIFFs), trust the extension.
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_string_lossy().to_lowercase();
+
+        match ext.as_str() {
+            e if RAW_EXTENSIONS.contains(&e) => Some(Self::Raw),
+            e if HEIF_EXTENSIONS.contains(&e) => Some(Self::Heif),
+            _ => None,
+        }
+    }
+    /// Formats that support transparency are exported as PNG, everything else as JPEG.
+    pub fn target_ext(&self) -> &'static str {
+        match self {
+            Self::Image(ImageFormat::Png) |
+            Self::Image(ImageFormat::Gif) |
+            Self::Image(ImageFormat::WebP) |
+            Self::Image(ImageFormat::Bmp) |
+            Self::Image(ImageFormat::Tiff) => "png",
+            _ => "jpeg",
+        }
+    }
+}
+
+/// Decode a source image with whichever backend can read it and convert it into the output color space.
+/// The embedded ICC profile is returned for the frames of animated sources.
+pub fn decode_source(path: &Path, limits: &Limits, space: ColorSpace) -> Result<(DynamicImage, SourceFormat, Option<Vec<u8>>)> {
+    let (img, format, icc) = match SourceFormat::from_extension(path) {
+        #[cfg(feature = "raw")]
+        Some(SourceFormat::Raw) => (raw::decode(path, limits)?, SourceFormat::Raw, None),
+        #[cfg(not(feature = "raw"))]
+        Some(SourceFormat::Raw) => bail!("Camera RAW sources require scandumper built with the 'raw' feature"),
+        #[cfg(feature = "heif")]
+        Some(SourceFormat::Heif) => {
+            let (img, icc) = heif::decode(path, limits)?;
+            (img, SourceFormat::Heif, icc)
+        },
+        #[cfg(not(feature = "heif"))]
+        Some(SourceFormat::Heif) => bail!("HEIF sources require scandumper built with the 'heif' feature"),
+        _ => {
+            let mut reader = ImageReader::open(path)?.with_guessed_format()?;
+            reader.limits(limits.clone());
+
+            let format = match reader.format() {
+                Some(f) => f,
+                None => bail!("Unable to detect image format from file."),
+            };
+
+            let mut decoder = reader.into_decoder()?;
+            let icc = decoder.icc_profile()?;
+
+            // CMYK JPEGs need their profile for the conversion, the generic decoder would guess
+            if let (ImageFormat::Jpeg, Some(ref i)) = (format, &icc) {
+                if is_cmyk(i) {
+                    return Ok((decode_cmyk_jpeg(path, i, space)?, SourceFormat::Image(format), None))
+                }
+            }
+
+            (DynamicImage::from_decoder(decoder)?, SourceFormat::Image(format), icc)
+        },
+    };
+
+    Ok((convert(img, icc.as_deref(), space)?, format, icc))
+}
+
+/// Frame count and frames of an animated GIF, WebP or APNG source, `None` for still images.
+/// Only animated outputs need every frame, posters keep the one picked and skipped sources none.
+pub fn decode_frames(path: &Path, format: SourceFormat, limits: &Limits, mode: AnimationMode, poster: usize)
+-> Result<Option<(usize, Vec<Frame>)>> {
+    let reader = BufReader::new(File::open(path)?);
+
+    let (count, frames) = match format {
+        SourceFormat::Image(ImageFormat::Gif) => {
+            let mut decoder = GifDecoder::new(reader)?;
+            decoder.set_limits(limits.clone())?;
+            select_frames(decoder.into_frames(), mode, poster, limits)?
+        },
+        SourceFormat::Image(ImageFormat::Png) => {
+            let mut decoder = PngDecoder::new(reader)?;
+            decoder.set_limits(limits.clone())?;
+
+            match decoder.is_apng()? {
+                true => select_frames(decoder.apng()?.into_frames(), mode, poster, limits)?,
+                false => return Ok(None),
+            }
+        },
+        SourceFormat::Image(ImageFormat::WebP) => {
+            let decoder = WebPDecoder::new(reader)?;
+            let (width, height) = decoder.dimensions();
+            limits.check_dimensions(width, height)?;
+
+            if !decoder.has_animation() {
+                return Ok(None)
+            }
+
+            let data = std::fs::read(path)?;
+            let count = webp_frame_count(&data);
+
+            match mode {
+                // libwebp decodes every frame at once, each of them the size of the canvas
+                AnimationMode::Animate => {
+                    check_frames_alloc(limits, count, count as u64 * width as u64 * height as u64 * 4)?;
+                    (count, webp_frames(&data)?)
+                },
+                // Frames of the image crate loop forever, the count tells where to stop
+                AnimationMode::Poster => {
+                    let frames = decoder.into_frames().take(poster.min(count.saturating_sub(1)) + 1);
+                    (count, select_frames(frames, mode, poster, limits)?.1)
+                },
+                AnimationMode::Skip => (count, vec![]),
+            }
+        },
+        _ => return Ok(None),
+    };
+
+    // Single frame GIFs are just images
+    match count > 1 {
+        true => Ok(Some((count, frames))),
+        false => Ok(None),
+    }
+}
+
+/// Counts the frames while decoding them one at a time, keeping only the ones needed.
+/// Decoder limits cover a single frame, all of them together get `max_alloc` bytes.
+fn select_frames(frames: impl Iterator<Item = image::ImageResult<Frame>>, mode: AnimationMode, poster: usize,
+limits: &Limits) -> Result<(usize, Vec<Frame>)> {
+    let mut count = 0;
+    let mut decoded = 0;
+    let mut kept = vec![];
+
+    for f in frames {
+        let f = f?;
+
+        decoded += f.buffer().as_raw().len() as u64;
+        check_frames_alloc(limits, count + 1, decoded)?;
+
+        match mode {
+            AnimationMode::Animate => kept.push(f),
+            // Index past the end picks the last one
+            AnimationMode::Poster if count <= poster => kept = vec![f],
+            _ => (),
+        }
+
+        count += 1;
+    }
+
+    Ok((count, kept))
+}
+
+/// Animations with too many frames are limit errors like any other source too large to decode.
+fn check_frames_alloc(limits: &Limits, frames: usize, bytes: u64) -> Result<()> {
+    match limits.max_alloc {
+        Some(max) if bytes > max => {
+            let e = ImageError::Limits(LimitError::from_kind(LimitErrorKind::InsufficientMemory));
+            Err(anyhow::Error::from(e).context(format!("{} frames take more than {} bytes", frames, max)))
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Frames of an animated WebP are `ANMF` chunks of the RIFF container.
+fn webp_frame_count(data: &[u8]) -> usize {
+    let mut count = 0;
+    let mut offset = 12;
+
+    while let Some(header) = data.get(offset..offset + 8) {
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+
+        if &header[..4] == b"ANMF" {
+            count += 1;
+        }
+
+        // Chunks are padded to an even size
+        offset += 8 + size + size % 2;
+    }
+
+    count
+}
+
+/// WebP frames iterator of the image crate never ends, libwebp decodes them instead.
+fn webp_frames(data: &[u8]) -> Result<Vec<Frame>> {
+    let decoded = AnimDecoder::new(data).decode()
+        .map_err(|e| anyhow!("Failed to decode animated WebP: {}", e))?;
+
+    // Frames have the timestamp of when they end
+    let mut start = 0;
+    let mut frames = vec![];
+
+    for f in decoded.into_iter() {
+        let buffer = match RgbaImage::from_raw(f.width(), f.height(), f.get_image().to_vec()) {
+            Some(b) => b,
+            None => bail!("Animated WebP frame doesn't match its dimensions"),
+        };
+
+        let delay = (f.get_time_ms() - start).max(0) as u32;
+        start = f.get_time_ms();
+
+        frames.push(Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(delay, 1)));
+    }
+
+    Ok(frames)
+}
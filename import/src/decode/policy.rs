@@ -0,0 +1,111 @@
+use std::fmt::Debug;
+use std::io::Cursor;
+use std::path::Path;
+use anyhow::Result;
+use config::{Config, SourceLimits};
+use image::{io::Reader as ImageReader, error::{ImageError, LimitError, LimitErrorKind}, io::Limits};
+
+use super::SourceFormat;
+
+
+/// Source size guards from `[import.limits]`.
+#[derive(Debug, Clone, Default)]
+pub struct SourcePolicy(SourceLimits);
+
+impl SourcePolicy {
+    pub fn from_config(config: &Config) -> Self {
+        match config.import {
+            Some(ref i) => Self(i.limits.clone()),
+            None => Self::default(),
+        }
+    }
+    /// Decoder limits, image crate defaults apply to anything not configured.
+    pub fn limits(&self) -> Limits {
+        let mut limits = Limits::default();
+
+        limits.max_image_width = self.0.max_dimension;
+        limits.max_image_height = self.0.max_dimension;
+
+        if let Some(a) = self.0.max_alloc {
+            limits.max_alloc = Some(a);
+        }
+
+        limits
+    }
+    /// Check what can be known without decoding, file size and the dimensions from the header.
+    /// Returns the reason to skip the source.
+    pub fn check(&self, path: &Path) -> Result<Option<String>> {
+        let size = path.metadata()?.len();
+
+        if let Some(max) = self.0.max_file_size {
+            if size > max {
+                return Ok(Some(format!("{:?}: File size {} exceeds the limit of {} bytes", path, size, max)))
+            }
+        }
+
+        // Camera formats only tell their size once decoded
+        if SourceFormat::from_extension(path).is_some() {
+            return Ok(None)
+        }
+
+        // Unreadable headers are left for the decoder to report
+        match ImageReader::open(path)?.with_guessed_format()?.into_dimensions() {
+            Ok((w, h)) => Ok(self.check_dimensions(path, w, h)),
+            Err(_) => Ok(None),
+        }
+    }
+    /// Same checks for a source read into memory, failing with a limit error.
+    pub fn check_slice(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        self.check_size(name, bytes.len() as u64)?;
+
+        match ImageReader::new(Cursor::new(bytes)).with_guessed_format()?.into_dimensions() {
+            Ok((w, h)) => match self.check_dimensions(name, w, h) {
+                Some(r) => Err(limit_error(r)),
+                None => Ok(()),
+            },
+            Err(_) => Ok(()),
+        }
+    }
+    /// File size check alone, for sources whose size is known before reading them.
+    pub fn check_size(&self, name: &str, size: u64) -> Result<()> {
+        match self.0.max_file_size {
+            Some(max) if size > max => Err(limit_error(
+                format!("{:?}: File size {} exceeds the limit of {} bytes", name, size, max)
+            )),
+            _ => Ok(()),
+        }
+    }
+    pub fn check_dimensions(&self, path: &(impl Debug + ?Sized), width: u32, height: u32) -> Option<String> {
+        let pixels = width as u64 * height as u64;
+
+        if let Some(max) = self.0.max_pixels {
+            if pixels > max {
+                return Some(format!("{:?}: {}x{} image exceeds the limit of {} pixels", path, width, height, max))
+            }
+        }
+
+        if let Some(max) = self.0.max_dimension {
+            if width > max || height > max {
+                return Some(format!("{:?}: {}x{} image exceeds the max dimension of {}px", path, width, height, max))
+            }
+        }
+
+        if let Some(min) = self.0.min_dimension {
+            if width < min || height < min {
+                return Some(format!("{:?}: {}x{} image is below the min dimension of {}px", path, width, height, min))
+            }
+        }
+
+        None
+    }
+}
+
+fn limit_error(reason: String) -> anyhow::Error {
+    let e = ImageError::Limits(LimitError::from_kind(LimitErrorKind::DimensionError));
+    anyhow::Error::from(e).context(reason)
+}
+
+/// Decoder gave up because of the limits rather than a broken file.
+pub fn is_limit_error(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<ImageError>(), Some(ImageError::Limits(_)))
+}
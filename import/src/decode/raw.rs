@@ -0,0 +1,212 @@
+use std::io::Cursor;
+use std::path::Path;
+use anyhow::{anyhow, Context, Result};
+use image::{io::Limits, io::Reader as ImageReader, DynamicImage, ImageFormat};
+use tracing::debug;
+
+
+/// Camera RAW files carry one or more JPEG previews rendered by the camera, the largest
+/// one is usually full size. Scan the file for JPEG streams and decode the biggest.
+pub fn decode(path: &Path, limits: &Limits) -> Result<DynamicImage> {
+    let bytes = std::fs::read(path)?;
+    let mut preview: Option<(usize, u32, u32)> = None;
+
+    // Start of image marker followed by the first segment marker
+    for (i, _) in bytes.windows(3).enumerate().filter(|(_, w)|w.eq(&[0xFF, 0xD8, 0xFF])) {
+        if let Some((w, h)) = jpeg_dimensions(&bytes[i..]) {
+            let larger = match preview {
+                Some((_, pw, ph)) => w as u64 * h as u64 > pw as u64 * ph as u64,
+                None => true,
+            };
+
+            if larger {
+                preview = Some((i, w, h));
+            }
+        }
+    }
+
+    let (offset, width, height) = preview
+        .ok_or_else(|| anyhow!("No embedded preview found from camera RAW file"))?;
+
+    debug!("Using embedded {}x{} preview at offset {} of {:?}", width, height, offset, path);
+
+    limits.check_dimensions(width, height)?;
+
+    let mut reader = ImageReader::with_format(Cursor::new(&bytes[offset..]), ImageFormat::Jpeg);
+    reader.limits(limits.clone());
+
+    // Limit errors have to stay image errors to be told apart from broken files
+    let image = reader.decode().context("Failed to decode embedded preview")?;
+
+    // Previews are stored as the sensor saw them, the RAW container tells how to turn them
+    let orientation = tiff_orientation(&bytes)
+        .or_else(|| exif(&bytes[offset..]).and_then(tiff_orientation))
+        .unwrap_or(1);
+
+    Ok(orient(image, orientation))
+}
+
+/// Apply an EXIF orientation value.
+fn orient(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Orientation tag of the first IFD of a TIFF structure. Most RAW formats are TIFF based,
+/// some with their own magic number after the byte order mark.
+fn tiff_orientation(buf: &[u8]) -> Option<u16> {
+    let u16_at = |i: usize| -> Option<u16> {
+        let b: [u8; 2] = buf.get(i..i + 2)?.try_into().ok()?;
+
+        match &buf[..2] {
+            b"II" => Some(u16::from_le_bytes(b)),
+            _ => Some(u16::from_be_bytes(b)),
+        }
+    };
+
+    let u32_at = |i: usize| -> Option<u32> {
+        let b: [u8; 4] = buf.get(i..i + 4)?.try_into().ok()?;
+
+        match &buf[..2] {
+            b"II" => Some(u32::from_le_bytes(b)),
+            _ => Some(u32::from_be_bytes(b)),
+        }
+    };
+
+    if !matches!(buf.get(..2), Some(b"II") | Some(b"MM")) {
+        return None
+    }
+
+    let ifd = u32_at(4)? as usize;
+
+    (0..u16_at(ifd)? as usize)
+        .map(|n| ifd + 2 + n * 12)
+        .find(|&e| u16_at(e) == Some(0x0112))
+        .and_then(|e| u16_at(e + 8))
+        .filter(|o| (1..=8).contains(o))
+}
+
+/// TIFF structure of the Exif APP1 segment of a JPEG stream.
+fn exif(buf: &[u8]) -> Option<&[u8]> {
+    let mut i = 2;
+
+    while i + 4 < buf.len() && buf[i] == 0xFF {
+        let len = u16::from_be_bytes([buf[i + 2], buf[i + 3]]) as usize;
+
+        match buf[i + 1] {
+            0xE1 if buf.get(i + 4..i + 10) == Some(b"Exif\0\0") => return buf.get(i + 10..i + 2 + len),
+            // Metadata segments come before the image data
+            0xDA => return None,
+            _ => i += 2 + len,
+        }
+    }
+
+    None
+}
+
+/// Walk JPEG segments until the frame header. Only baseline and progressive frames are
+/// accepted, lossless (SOF3) streams hold the undemosaiced sensor data in many RAW formats.
+fn jpeg_dimensions(buf: &[u8]) -> Option<(u32, u32)> {
+    let mut i = 2;
+
+    while i + 9 < buf.len() {
+        if buf[i] != 0xFF {
+            return None
+        }
+
+        let marker = buf[i + 1];
+        let len = u16::from_be_bytes([buf[i + 2], buf[i + 3]]) as usize;
+
+        match marker {
+            // Fill bytes before the actual marker
+            0xFF => i += 1,
+            0xC0..=0xC2 => {
+                let h = u16::from_be_bytes([buf[i + 5], buf[i + 6]]) as u32;
+                let w = u16::from_be_bytes([buf[i + 7], buf[i + 8]]) as u32;
+
+                return match w > 0 && h > 0 {
+                    true => Some((w, h)),
+                    false => None,
+                }
+            },
+            // Any other frame type or start of scan before a frame header
+            0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF | 0xDA => return None,
+            _ => i += 2 + len,
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, RgbImage};
+
+    fn tiff(order: &[u8; 2], orientation: u16) -> Vec<u8> {
+        let be = order == b"MM";
+        let u16b = |v: u16| if be { v.to_be_bytes() } else { v.to_le_bytes() };
+        let u32b = |v: u32| if be { v.to_be_bytes() } else { v.to_le_bytes() };
+
+        let mut t = order.to_vec();
+        t.extend(u16b(42));
+        t.extend(u32b(8));
+        t.extend(u16b(2));
+
+        // Image width and orientation entries
+        for (tag, value) in [(0x0100, 640), (0x0112, orientation)] {
+            t.extend(u16b(tag));
+            t.extend(u16b(3));
+            t.extend(u32b(1));
+            t.extend(u16b(value));
+            t.extend([0, 0]);
+        }
+
+        t
+    }
+
+    #[test]
+    fn orientation_from_tiff() {
+        assert_eq!(tiff_orientation(&tiff(b"II", 6)), Some(6));
+        assert_eq!(tiff_orientation(&tiff(b"MM", 8)), Some(8));
+        assert_eq!(tiff_orientation(&tiff(b"II", 9)), None);
+        assert_eq!(tiff_orientation(&tiff(b"II", 6)[..20]), None);
+        assert_eq!(tiff_orientation(&[0xFF, 0xD8, 0xFF, 0xE0]), None);
+    }
+
+    #[test]
+    fn orientation_from_jpeg_exif() {
+        let t = tiff(b"MM", 3);
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0, 4, 0, 0, 0xFF, 0xE1];
+        jpeg.extend((t.len() as u16 + 8).to_be_bytes());
+        jpeg.extend(b"Exif\0\0");
+        jpeg.extend(&t);
+        jpeg.extend([0xFF, 0xDA, 0, 2]);
+
+        assert_eq!(exif(&jpeg).and_then(tiff_orientation), Some(3));
+        assert_eq!(exif(&[0xFF, 0xD8, 0xFF, 0xDA, 0, 2]), None);
+    }
+
+    #[test]
+    fn oriented_dimensions() {
+        let mut image = RgbImage::new(4, 2);
+        image.put_pixel(0, 0, [255, 0, 0].into());
+        let image = DynamicImage::ImageRgb8(image);
+
+        for (o, (w, h), corner) in [(1, (4, 2), (0, 0)), (2, (4, 2), (3, 0)), (3, (4, 2), (3, 1)), (4, (4, 2), (0, 1)),
+            (5, (2, 4), (0, 0)), (6, (2, 4), (1, 0)), (7, (2, 4), (1, 3)), (8, (2, 4), (0, 3))] {
+            let oriented = orient(image.clone(), o);
+
+            assert_eq!(oriented.dimensions(), (w, h), "orientation {}", o);
+            assert_eq!(oriented.get_pixel(corner.0, corner.1).0, [255, 0, 0, 255], "orientation {}", o);
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use anyhow::{anyhow, bail, Result};
+use config::{Effect, HexColor};
+use fast_image_resize::images::Image;
+use fast_image_resize::PixelType;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageBuffer, Rgba32FImage};
+
+
+/// Apply the effects of a variant to a resized image, the pixel type follows the result.
+/// Grayscale gives gray pixels, any color effect after it gives color back.
+pub fn apply(image: Image<'static>, effects: &[Effect]) -> Result<Image<'static>> {
+    let mut img = to_dynamic(image)?;
+
+    for e in effects {
+        img = effect(img, e);
+    }
+
+    from_dynamic(img)
+}
+
+/// Same as `apply` but the result keeps the pixel type of the input, animation frames are RGBA.
+pub fn apply_keep(image: Image<'static>, effects: &[Effect]) -> Result<Image<'static>> {
+    let pixel = image.pixel_type();
+    let img = to_dynamic(apply(image, effects)?)?;
+
+    let img = match pixel {
+        PixelType::U8 => DynamicImage::ImageLuma8(img.into_luma8()),
+        PixelType::U8x2 => DynamicImage::ImageLumaA8(img.into_luma_alpha8()),
+        PixelType::U8x3 => DynamicImage::ImageRgb8(img.into_rgb8()),
+        PixelType::U8x4 => DynamicImage::ImageRgba8(img.into_rgba8()),
+        PixelType::U16 => DynamicImage::ImageLuma16(img.into_luma16()),
+        PixelType::U16x2 => DynamicImage::ImageLumaA16(img.into_luma_alpha16()),
+        PixelType::U16x3 => DynamicImage::ImageRgb16(img.into_rgb16()),
+        _ => DynamicImage::ImageRgba16(img.into_rgba16()),
+    };
+
+    from_dynamic(img)
+}
+
+fn effect(img: DynamicImage, effect: &Effect) -> DynamicImage {
+    match effect {
+        Effect::Grayscale => img.grayscale(),
+        Effect::Sepia => map_color(img, |[r, g, b]| [
+            0.393 * r + 0.769 * g + 0.189 * b,
+            0.349 * r + 0.686 * g + 0.168 * b,
+            0.272 * r + 0.534 * g + 0.131 * b,
+        ]),
+        Effect::Duotone([dark, light]) => {
+            let (dark, light) = (unit(dark), unit(light));
+
+            map_color(img, move |[r, g, b]| {
+                let l = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+                [0, 1, 2].map(|i| dark[i] + (light[i] - dark[i]) * l)
+            })
+        },
+        Effect::Blur(sigma) => img.blur(*sigma),
+        Effect::Brightness(v) => {
+            let v = *v as f32 / 255.;
+            map_color(img, move |c| c.map(|c| c + v))
+        },
+        Effect::Contrast(c) => img.adjust_contrast(*c),
+        Effect::Tint { color, amount } => {
+            let (tint, amount) = (unit(color), amount.clamp(0., 1.));
+            map_color(img, move |c| [0, 1, 2].map(|i| c[i] + (tint[i] - c[i]) * amount))
+        },
+        Effect::Pixelate(size) => {
+            let (w, h) = (img.width(), img.height());
+            let size = (*size).max(1);
+
+            // Blocks are averaged down and blown back up without smoothing
+            img.resize_exact((w / size).max(1), (h / size).max(1), FilterType::Triangle)
+                .resize_exact(w, h, FilterType::Nearest)
+        },
+    }
+}
+
+fn unit(color: &HexColor) -> [f32; 3] {
+    color.0.map(|c| c as f32 / 255.)
+}
+
+/// Run a color mapping in floats, depth and alpha of the image are kept. Gray images turn into color.
+fn map_color(img: DynamicImage, f: impl Fn([f32; 3]) -> [f32; 3]) -> DynamicImage {
+    let deep = img.color().bytes_per_pixel() > img.color().channel_count();
+    let alpha = img.color().has_alpha();
+    let mut buf: Rgba32FImage = img.into_rgba32f();
+
+    for p in buf.pixels_mut() {
+        let [r, g, b] = f([p[0], p[1], p[2]]);
+        p.0 = [r.clamp(0., 1.), g.clamp(0., 1.), b.clamp(0., 1.), p[3]];
+    }
+
+    let img = DynamicImage::ImageRgba32F(buf);
+
+    match (deep, alpha) {
+        (true, true) => DynamicImage::ImageRgba16(img.into_rgba16()),
+        (true, false) => DynamicImage::ImageRgb16(img.into_rgb16()),
+        (false, true) => DynamicImage::ImageRgba8(img.into_rgba8()),
+        (false, false) => DynamicImage::ImageRgb8(img.into_rgb8()),
+    }
+}
+
+fn to_dynamic(image: Image<'static>) -> Result<DynamicImage> {
+    let (w, h) = (image.width(), image.height());
+    let pixel = image.pixel_type();
+    let bytes = image.into_vec();
+
+    let img = match pixel {
+        PixelType::U8 => ImageBuffer::from_raw(w, h, bytes).map(DynamicImage::ImageLuma8),
+        PixelType::U8x2 => ImageBuffer::from_raw(w, h, bytes).map(DynamicImage::ImageLumaA8),
+        PixelType::U8x3 => ImageBuffer::from_raw(w, h, bytes).map(DynamicImage::ImageRgb8),
+        PixelType::U8x4 => ImageBuffer::from_raw(w, h, bytes).map(DynamicImage::ImageRgba8),
+        PixelType::U16 => ImageBuffer::from_raw(w, h, words(&bytes)).map(DynamicImage::ImageLuma16),
+        PixelType::U16x2 => ImageBuffer::from_raw(w, h, words(&bytes)).map(DynamicImage::ImageLumaA16),
+        PixelType::U16x3 => ImageBuffer::from_raw(w, h, words(&bytes)).map(DynamicImage::ImageRgb16),
+        PixelType::U16x4 => ImageBuffer::from_raw(w, h, words(&bytes)).map(DynamicImage::ImageRgba16),
+        p => bail!("Effects on {:?} images are not supported", p),
+    };
+
+    img.ok_or_else(|| anyhow!("Resized image doesn't match its dimensions"))
+}
+
+/// 16 bit values are native endian bytes in the resized image.
+fn words(bytes: &[u8]) -> Vec<u16> {
+    bytes.chunks_exact(2)
+        .map(|v| u16::from_ne_bytes([v[0], v[1]]))
+        .collect()
+}
+
+fn from_dynamic(img: DynamicImage) -> Result<Image<'static>> {
+    let (w, h) = (img.width(), img.height());
+
+    let pixel = match img {
+        DynamicImage::ImageLuma8(_) => PixelType::U8,
+        DynamicImage::ImageLumaA8(_) => PixelType::U8x2,
+        DynamicImage::ImageRgb8(_) => PixelType::U8x3,
+        DynamicImage::ImageRgba8(_) => PixelType::U8x4,
+        DynamicImage::ImageLuma16(_) => PixelType::U16,
+        DynamicImage::ImageLumaA16(_) => PixelType::U16x2,
+        DynamicImage::ImageRgb16(_) => PixelType::U16x3,
+        DynamicImage::ImageRgba16(_) => PixelType::U16x4,
+        _ => bail!("Effect produced an unsupported {:?} image", img.color()),
+    };
+
+    // as_bytes gives the 16 bit values in native endian
+    Image::from_vec_u8(w, h, img.as_bytes().to_vec(), pixel)
+        .map_err(|e| anyhow!("Failed to read effect result into an image: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIXELS: [PixelType; 8] = [
+        PixelType::U8, PixelType::U8x2, PixelType::U8x3, PixelType::U8x4,
+        PixelType::U16, PixelType::U16x2, PixelType::U16x3, PixelType::U16x4,
+    ];
+
+    /// Mid gray, alpha at half where there is one. Same bytes make the same 16 bit values in any endian.
+    fn image(pixel: PixelType) -> Image<'static> {
+        Image::from_vec_u8(8, 6, vec![0x80; 8 * 6 * pixel.size()], pixel).unwrap()
+    }
+
+    fn effects(toml: &str) -> Vec<Effect> {
+        toml::from_str::<config::Variant>(&format!("name = \"test\"\n{}", toml)).unwrap().filters
+    }
+
+    #[test]
+    fn grayscale_drops_color() {
+        let gray = [PixelType::U8, PixelType::U8x2, PixelType::U8, PixelType::U8x2,
+            PixelType::U16, PixelType::U16x2, PixelType::U16, PixelType::U16x2];
+
+        for (pixel, expected) in PIXELS.into_iter().zip(gray) {
+            let result = apply(image(pixel), &effects("filters = [\"grayscale\"]")).unwrap();
+
+            assert_eq!(result.pixel_type(), expected, "{:?}", pixel);
+            assert_eq!((result.width(), result.height()), (8, 6));
+        }
+    }
+
+    #[test]
+    fn color_effects_give_color() {
+        let color = [PixelType::U8x3, PixelType::U8x4, PixelType::U8x3, PixelType::U8x4,
+            PixelType::U16x3, PixelType::U16x4, PixelType::U16x3, PixelType::U16x4];
+
+        let filters = effects("filters = [\"grayscale\", \"sepia\", {duotone = [\"#000000\", \"#ffffff\"]}, {brightness = 10}, {tint = {color = \"#ff0000\"}}]");
+
+        for (pixel, expected) in PIXELS.into_iter().zip(color) {
+            assert_eq!(apply(image(pixel), &filters).unwrap().pixel_type(), expected, "{:?}", pixel);
+        }
+    }
+
+    #[test]
+    fn keep_pixel_type() {
+        let filters = effects("filters = [\"sepia\", {blur = 1.0}, {contrast = 10.0}, {pixelate = 3}, \"grayscale\"]");
+
+        for pixel in PIXELS {
+            let result = apply_keep(image(pixel), &filters).unwrap();
+
+            assert_eq!(result.pixel_type(), pixel);
+            assert_eq!((result.width(), result.height()), (8, 6));
+        }
+    }
+
+    #[test]
+    fn values_and_alpha() {
+        let brighter = effects("filters = [{brightness = 32}]");
+
+        let result = apply(image(PixelType::U8x4), &brighter).unwrap();
+        assert_eq!(&result.buffer()[..4], &[0xA0, 0xA0, 0xA0, 0x80]);
+
+        let result = apply(image(PixelType::U16x2), &brighter).unwrap();
+        let first = words(&result.buffer()[..8]);
+        assert_eq!(first[..3], [0xA0A0; 3]);
+        assert_eq!(first[3], 0x8080);
+
+        // Duotone from black to white is the luminance
+        let result = apply(image(PixelType::U8x3), &effects("filters = [{duotone = [\"#000000\", \"#ffffff\"]}]")).unwrap();
+        assert_eq!(&result.buffer()[..3], &[0x80, 0x80, 0x80]);
+    }
+
+    #[test]
+    fn unsupported_pixels() {
+        let image = Image::new(4, 4, PixelType::F32);
+
+        assert!(apply(image, &effects("filters = [\"sepia\"]")).is_err());
+    }
+}
@@ -0,0 +1,627 @@
+use std::io::Cursor;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use anyhow::{anyhow, bail, Result};
+use config::{Config, ExportFilter, ExportKind, Manifest, Naming, ObjectStore, Upscale};
+use image::io::Reader as ImageReader;
+use sha2::{Digest, Sha256};
+use tokio::fs::create_dir_all;
+use tracing::{debug, error, info, warn};
+
+use crate::resize::TargetSize;
+use crate::template::PREFIX;
+use crate::transform::OutputKind;
+
+
+#[derive(Clone, Debug)]
+pub enum OutputStore {
+    Filesystem(PathBuf),
+    S3(Box<ObjectStore>),
+}
+
+#[derive(Clone, Debug)]
+pub struct OutputTarget {
+    pub name: String,
+    pub store: OutputStore,
+    pub prefix: Option<PathBuf>,
+    pub filter: ExportFilter,
+    pub naming: Naming,
+    pub manifest: Option<Arc<Mutex<Manifest>>>,
+}
+
+impl OutputTarget {
+    pub async fn from_config(config: &mut Config) -> Result<Vec<Self>> {
+        let export = config.export()?;
+        let mut targets = vec![];
+
+        // Single filesystem root from the plain [export] settings
+        if export.filesystem {
+            if export.filesystem_path.is_none() && export.prefix.is_none() {
+                bail!("Filesystem export requires either 'filesystem_path' or 'prefix' to be set");
+            }
+
+            let root = match export.filesystem_path {
+                Some(ref p) => p.to_owned(),
+                None => config.dir.to_owned(),
+            };
+
+            let target = Self::new(
+                String::from("filesystem"),
+                OutputStore::Filesystem(root),
+                export.prefix.to_owned(),
+                ExportFilter::default(),
+                export.naming,
+            );
+
+            targets.push(target.await?);
+        }
+
+        // Single bucket from the [s3] section
+        if export.s3 {
+            let s3_config = config.s3_mut()
+                .map_err(|e| anyhow!("Incomplete config for S3: {}", e))?;
+
+            // Verify that our bucket exists
+            ObjectStore::init_from(s3_config, true).await
+                .map_err(|e| anyhow!("Object store setup from config failed: {}", e))?;
+
+            let store = ObjectStore::get(s3_config)?;
+
+            // Object keys have always matched the local output path, config dir included
+            let mut prefix = config.dir.to_owned();
+
+            if let Some(ref p) = export.prefix {
+                prefix.push(p);
+            }
+
+            let target = Self::new(
+                String::from("S3"),
+                OutputStore::S3(Box::new(store)),
+                Some(prefix),
+                ExportFilter::default(),
+                export.naming,
+            );
+
+            targets.push(target.await?);
+        }
+
+        for (i, t) in export.targets.iter().enumerate() {
+            let name = match t.name {
+                Some(ref n) => n.to_owned(),
+                None => format!("target #{}", i + 1),
+            };
+
+            let store = match t.kind {
+                // Relative paths are read from the config.toml dir
+                ExportKind::Filesystem => match t.path {
+                    Some(ref p) => OutputStore::Filesystem(config.dir.join(p)),
+                    None => bail!("{}: Filesystem target requires 'path' to be set", name),
+                },
+                ExportKind::S3 => {
+                    let mut s3_config = t.s3_config(config.s3.as_ref())?;
+
+                    ObjectStore::init_from(&mut s3_config, true).await
+                        .map_err(|e| anyhow!("{}: Object store setup from config failed: {}", name, e))?;
+
+                    OutputStore::S3(Box::new(ObjectStore::get(&s3_config)?))
+                },
+            };
+
+            debug!("Export target {} loaded...", name);
+
+            let target = Self::new(
+                name,
+                store,
+                t.prefix.to_owned(),
+                t.filter.to_owned(),
+                t.naming.unwrap_or(export.naming),
+            );
+
+            targets.push(target.await?);
+        }
+
+        // Sources are read from the config dir, outputs written over it would be imported again
+        let sources = absolute(&config.dir);
+
+        for t in targets.iter() {
+            if let Some(d) = t.output_dir() {
+                if sources.starts_with(absolute(&d)) {
+                    bail!("{}: Output dir {} contains the source images", t.name, d.display())
+                }
+            }
+
+            // Buckets have no links, only a manifest can point two names to one object
+            let aliased = TargetSize::ids().into_iter()
+                .filter(|id|t.filter.size(id))
+                .find(|id|config.resize.upscale.get(id) == Upscale::Alias);
+
+            if let (Some(id), OutputStore::S3(_), Naming::Plain) = (aliased, &t.store, t.naming) {
+                bail!("{}: Upscale policy 'alias' of size {} needs hashed or content naming on S3 targets", t.name, id)
+            }
+        }
+
+        Ok(targets)
+    }
+    async fn new(name: String, store: OutputStore, prefix: Option<PathBuf>, filter: ExportFilter, naming: Naming)
+    -> Result<Self> {
+        let mut target = Self { name, store, prefix, filter, naming, manifest: None };
+
+        // Hashed names can't be guessed, keep track of them in a manifest
+        if naming != Naming::Plain {
+            let manifest = target.load_manifest().await?;
+
+            debug!("Loaded manifest of {} entries for {}", manifest.0.len(), target.name);
+            target.manifest = Some(Arc::new(Mutex::new(manifest)));
+        }
+
+        Ok(target)
+    }
+    async fn load_manifest(&self) -> Result<Manifest> {
+        let object = self.object_path(&Path::new(PREFIX).join(Manifest::FILENAME));
+
+        match self.store {
+            OutputStore::Filesystem(_) => match object.is_file() {
+                true => match tokio::fs::read(&object).await {
+                    Ok(v) => Manifest::from_slice(&v),
+                    Err(e) => bail!("Failed to read manifest {}: {}", object.display(), e),
+                },
+                false => Ok(Manifest::default()),
+            },
+            OutputStore::S3(ref s) => match s.get_bytes(object.to_string_lossy()).await? {
+                Some(v) => Manifest::from_slice(&v),
+                None => Ok(Manifest::default()),
+            },
+        }
+    }
+    pub async fn save_manifest(&self) -> Result<()> {
+        let bytes = match self.manifest {
+            Some(ref m) => m.lock().map_err(|e| anyhow!("Manifest lock poisoned: {}", e))?.to_vec()?,
+            None => return Ok(()),
+        };
+
+        let object = self.object_path(&Path::new(PREFIX).join(Manifest::FILENAME));
+
+        match self.store {
+            OutputStore::Filesystem(_) => {
+                if let Some(p) = object.parent() {
+                    create_dir_all(p).await?;
+                }
+
+                if let Err(e) = tokio::fs::write(&object, &bytes).await {
+                    bail!("Failed to write manifest {}: {}", object.display(), e)
+                }
+            },
+            OutputStore::S3(ref s) => {
+                let key = object.to_string_lossy();
+
+                if let Err(e) = s.0.put_object_with_content_type(key.as_ref(), &bytes, "application/json").await {
+                    bail!("{}: Failed to store manifest: {}", key.as_ref(), e)
+                }
+            },
+        }
+
+        Ok(())
+    }
+    pub fn accepts(&self, kind: &OutputKind, id: &str) -> bool {
+        match kind {
+            OutputKind::Resized => self.filter.size(id),
+            OutputKind::Variant(v) => self.filter.variant(v) && self.filter.size(id),
+            OutputKind::Shape => self.filter.shape(id),
+            OutputKind::ShapeVariant(v) => self.filter.variant(v) && self.filter.shape(id),
+        }
+    }
+    /// Directory all outputs are written under, filesystem targets only.
+    pub fn output_dir(&self) -> Option<PathBuf> {
+        match self.store {
+            OutputStore::Filesystem(_) => Some(self.object_path(Path::new(PREFIX))),
+            OutputStore::S3(_) => None,
+        }
+    }
+    /// Filesystem path or S3 key of an output path relative to the export root.
+    pub fn object_path(&self, path: &Path) -> PathBuf {
+        let key = self.object_key(path);
+
+        match self.store {
+            OutputStore::Filesystem(ref r) => r.join(key),
+            OutputStore::S3(_) => key,
+        }
+    }
+    /// Output path with target prefix, this is what the manifest points to.
+    fn object_key(&self, path: &Path) -> PathBuf {
+        let mut key = PathBuf::new();
+
+        for c in path.iter() {
+            match c.eq(PREFIX) {
+                true => if let Some(ref p) = self.prefix {
+                    key.push(p);
+                },
+                false => key.push(c),
+            }
+        }
+
+        key
+    }
+    /// Logical manifest name of an output path, the same for every target.
+    fn logical_name(path: &Path) -> String {
+        let path = path.iter()
+            .filter(|c|c.ne(&PREFIX))
+            .collect::<PathBuf>();
+
+        Manifest::logical_name(&path)
+    }
+    /// Physical output path for the plain output path, depends on the encoded bytes
+    /// unless plain naming is used.
+    fn physical_path(&self, path: &Path, buf: &[u8]) -> PathBuf {
+        if self.naming == Naming::Plain {
+            return path.to_owned()
+        }
+
+        let hash = format!("{:x}", Sha256::digest(buf));
+        let ext = path.extension().unwrap_or_default().to_string_lossy();
+
+        match self.naming {
+            // md.3fa9c1.jpeg
+            Naming::Hashed => {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                path.with_file_name(format!("{}.{}.{}", stem, &hash[..6], ext))
+            },
+            // ab/cd/abcdef...jpeg
+            _ => {
+                let mut p = PathBuf::from(PREFIX);
+                p.push(&hash[..2]);
+                p.push(&hash[2..4]);
+                p.push(format!("{}.{}", hash, ext));
+                p
+            },
+        }
+    }
+    /// Current filesystem path or S3 key of an output path, if it was exported.
+    pub fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        let manifest = match self.manifest {
+            Some(ref m) => m.lock().ok()?,
+            None => return Some(self.object_path(path)),
+        };
+
+        let entry = manifest.get(&Self::logical_name(path))?;
+
+        // Logical names don't carry the extension, make sure the format matches
+        match Path::new(&entry.key).extension().eq(&path.extension()) {
+            true => Some(match self.store {
+                OutputStore::Filesystem(ref r) => r.join(&entry.key),
+                OutputStore::S3(_) => PathBuf::from(&entry.key),
+            }),
+            false => None,
+        }
+    }
+    pub async fn is_duplicate(&self, path: &Path, checksum: u32) -> bool {
+        // Manifest knows where the hashed output went and what it was made from
+        if self.manifest.is_some() {
+            let object = match self.resolve(path) {
+                Some(o) => o,
+                None => return false,
+            };
+
+            let unchanged = self.manifest.as_ref()
+                .and_then(|m|m.lock().ok())
+                .and_then(|m|m.get(&Self::logical_name(path)).map(|e|e.checksum.eq(&checksum)))
+                .unwrap_or(false);
+
+            return match self.store {
+                OutputStore::Filesystem(_) => unchanged && object.is_file(),
+                OutputStore::S3(_) => unchanged,
+            }
+        }
+
+        let object = self.object_path(path);
+
+        match self.store {
+            // See if the object already exists on S3
+            OutputStore::S3(ref s) => match s.get_tags(object.to_string_lossy()).await {
+                Ok(v) => match v.into_iter().find(|t|t.key().eq("checksum")) {
+                    Some(t) => match t.value().eq(&checksum.to_string()) {
+                        true => {
+                            debug!("Object {:?} on {} is same as the provided item, skipping...", object, self.name);
+                            true
+                        },
+                        false => {
+                            info!("Object {:?} on {} has changed, overwrite...", object, self.name);
+                            false
+                        }
+                    },
+                    None => {
+                        debug!("Object {:?} on {} does not exist / doesn't have checksum tag", object, self.name);
+                        false
+                    },
+                },
+                Err(e) => {
+                    error!("Duplicate check failed: {}, weird.", e);
+                    false
+                }
+            },
+            // See if the file already exists on filesystem
+            OutputStore::Filesystem(_) => {
+                if !object.is_file() {
+                    return false
+                }
+
+                // Can't compare image without checksum file, overwrite
+                let checksum_file = Self::checksum_file_path(&object);
+
+                if !checksum_file.is_file() {
+                    return false
+                }
+
+                match std::fs::read_to_string(checksum_file) {
+                    Ok(c) => match c.trim().parse::<u32>() {
+                        Ok(v) => match v.eq(&checksum) {
+                            true => {
+                                debug!("File {:?} already exists on filesystem", object);
+                                true
+                            },
+                            false => {
+                                info!("File {:?} has changed, overwrite...", object);
+                                false
+                            }
+                        },
+                        Err(e) => {
+                            error!("Failed to parse checksum from file: {:?}", e);
+                            false
+                        }
+                    },
+                    Err(e) => {
+                        error!("Failed to read checksum file: {:?}", e);
+                        false
+                    }
+                }
+            },
+        }
+    }
+    pub async fn write(&self, path: &Path, buf: &[u8], mime: &str, checksum: u32) -> Result<()> {
+        let physical = self.physical_path(path, buf);
+        let object = self.object_path(&physical);
+
+        // Header is enough to tell the dimensions
+        let dimensions = ImageReader::new(Cursor::new(buf)).with_guessed_format().ok()
+            .and_then(|r| r.into_dimensions().ok());
+
+        match self.store {
+            // Write image into the filesystem with checksum file next to it
+            OutputStore::Filesystem(_) => {
+                let dir = match object.parent() {
+                    Some(p) => p,
+                    None => bail!("Failed to extract parent dir from resized image path {:?}", object),
+                };
+
+                if let Err(e) = create_dir_all(dir).await {
+                    bail!("Failed to create target dir for resized images {}: {}", dir.display(), e)
+                }
+
+                if let Err(e) = tokio::fs::write(&object, buf).await {
+                    bail!("Failed to write resized image {}: {}", object.display(), e)
+                }
+
+                // Manifest holds the checksum for hashed names
+                if self.manifest.is_none() {
+                    let checksum_file = Self::checksum_file_path(&object);
+
+                    if let Err(e) = tokio::fs::write(&checksum_file, checksum.to_string()).await {
+                        bail!("Failed to write checksum file {}: {}", checksum_file.display(), e)
+                    }
+                }
+            },
+            // Write image into the S3 bucket with checksum tag, dimensions go into the metadata
+            // as the bucket can't be probed for them
+            OutputStore::S3(ref s) => {
+                let key = object.to_string_lossy();
+                let mut bucket = s.0.clone();
+
+                if let Some((w, h)) = dimensions {
+                    bucket.add_header("x-amz-meta-width", &w.to_string());
+                    bucket.add_header("x-amz-meta-height", &h.to_string());
+                }
+
+                if let Err(e) = bucket.put_object_with_content_type(key.as_ref(), buf, mime).await {
+                    bail!("{}: Failed to store S3 object: {}", key.as_ref(), e)
+                }
+
+                s.tag_source(&checksum.to_string(), &object).await?;
+            },
+        }
+
+        if let Some(ref m) = self.manifest {
+            let key = self.object_key(&physical).to_string_lossy().to_string();
+
+            let stale = match m.lock() {
+                Ok(mut v) => v.insert(Self::logical_name(path), key, checksum, dimensions),
+                Err(e) => bail!("Manifest lock poisoned: {}", e),
+            };
+
+            if let Some(k) = stale {
+                self.remove(&k).await;
+            }
+        }
+
+        Ok(())
+    }
+    /// Make an output path point to another output of the same source written earlier.
+    /// Copies write the bytes again under the new name, aliases only point to them.
+    pub async fn link(&self, path: &Path, real: &Path, mime: &str, checksum: u32, copy: bool) -> Result<()> {
+        let object = match self.resolve(real) {
+            Some(o) => o,
+            None => bail!("{}: {:?} is not exported to {}", path.display(), real, self.name),
+        };
+
+        if copy {
+            let buf = match self.store {
+                OutputStore::Filesystem(_) => match tokio::fs::read(&object).await {
+                    Ok(b) => b,
+                    Err(e) => bail!("Failed to read {} for copying: {}", object.display(), e),
+                },
+                OutputStore::S3(ref s) => s.get_bytes(object.to_string_lossy()).await?
+                    .ok_or_else(|| anyhow!("{}: S3 object to copy does not exist", object.display()))?,
+            };
+
+            return self.write(path, &buf, mime, checksum).await
+        }
+
+        // Manifest entries of aliases share the key of the real output
+        if let Some(ref m) = self.manifest {
+            let stale = match m.lock() {
+                Ok(mut v) => {
+                    let (key, dimensions) = match v.get(&Self::logical_name(real)) {
+                        Some(e) => (e.key.to_owned(), e.width.zip(e.height)),
+                        None => bail!("{}: {:?} missing from manifest of {}", path.display(), real, self.name),
+                    };
+
+                    v.insert(Self::logical_name(path), key, checksum, dimensions)
+                },
+                Err(e) => bail!("Manifest lock poisoned: {}", e),
+            };
+
+            if let Some(k) = stale {
+                self.remove(&k).await;
+            }
+
+            return Ok(())
+        }
+
+        let alias = self.object_path(path);
+
+        match self.store {
+            // Symlink to the real file with checksum file next to it
+            OutputStore::Filesystem(_) => {
+                let dir = match alias.parent() {
+                    Some(p) => p,
+                    None => bail!("Failed to extract parent dir from alias path {:?}", alias),
+                };
+
+                if let Err(e) = create_dir_all(dir).await {
+                    bail!("Failed to create target dir for aliases {}: {}", dir.display(), e)
+                }
+
+                // Symlinking fails on an existing file
+                if tokio::fs::symlink_metadata(&alias).await.is_ok() {
+                    tokio::fs::remove_file(&alias).await?;
+                }
+
+                // Relative to the alias so that links survive moving or mounting the output dir
+                let target = relative(&absolute(dir), &absolute(&object));
+
+                if let Err(e) = tokio::fs::symlink(&target, &alias).await {
+                    bail!("Failed to link {} to {}: {}", alias.display(), object.display(), e)
+                }
+
+                let checksum_file = Self::checksum_file_path(&alias);
+
+                if let Err(e) = tokio::fs::write(&checksum_file, checksum.to_string()).await {
+                    bail!("Failed to write checksum file {}: {}", checksum_file.display(), e)
+                }
+            },
+            // Refused at config load already
+            OutputStore::S3(_) => bail!("{}: S3 targets without a manifest can't alias outputs", alias.display()),
+        }
+
+        Ok(())
+    }
+    /// Deletes a hashed output the manifest replaced, a leftover is only a waste of space.
+    async fn remove(&self, key: &str) {
+        let result: Result<()> = match self.store {
+            OutputStore::Filesystem(ref r) => match tokio::fs::remove_file(r.join(key)).await {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                r => r.map_err(|e| e.into()),
+            },
+            OutputStore::S3(ref s) => s.0.delete_object(key).await.map(|_| ()).map_err(|e| e.into()),
+        };
+
+        match result {
+            Ok(()) => debug!("Removed replaced output {} from {}", key, self.name),
+            Err(e) => warn!("Failed to remove replaced output {} from {}: {}", key, self.name, e),
+        }
+    }
+    fn checksum_file_path(object: &Path) -> PathBuf {
+        let id = object.file_stem().unwrap_or_default().to_string_lossy();
+
+        object.with_file_name(format!(".{}.checksum", id))
+    }
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_owned())
+}
+
+/// Path from a dir to a file, both absolute.
+fn relative(dir: &Path, path: &Path) -> PathBuf {
+    let dir = dir.components().collect::<Vec<_>>();
+    let path = path.components().collect::<Vec<_>>();
+    let common = dir.iter().zip(path.iter()).take_while(|(a, b)| a == b).count();
+
+    std::iter::repeat_n(Component::ParentDir, dir.len() - common)
+        .chain(path[common..].iter().copied())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn target(name: &str, naming: Naming) -> (OutputTarget, PathBuf) {
+        let root = std::env::temp_dir().join(format!("scandumper-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+
+        let t = OutputTarget::new(name.into(), OutputStore::Filesystem(root.clone()), None, ExportFilter::default(), naming);
+
+        (t.await.unwrap(), root)
+    }
+
+    fn files(root: &Path) -> usize {
+        walkdir::WalkDir::new(root).into_iter().filter_map(|e| e.ok()).filter(|e| e.file_type().is_file()).count()
+    }
+
+    #[tokio::test]
+    async fn hashed_write_removes_replaced_output() {
+        let (t, root) = target("hashed", Naming::Hashed).await;
+        let path = Path::new(PREFIX).join("a/md.png");
+
+        t.write(&path, b"one", "image/png", 1).await.unwrap();
+        let first = t.resolve(&path).unwrap();
+
+        t.write(&path, b"two", "image/png", 2).await.unwrap();
+        let second = t.resolve(&path).unwrap();
+
+        assert_ne!(first, second);
+        assert!(!first.exists());
+        assert!(second.is_file());
+        assert_eq!(files(&root), 1);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn content_write_keeps_shared_output() {
+        let (t, root) = target("content", Naming::Content).await;
+        let (md, sm) = (Path::new(PREFIX).join("a/md.png"), Path::new(PREFIX).join("a/sm.png"));
+
+        t.write(&md, b"same", "image/png", 1).await.unwrap();
+        t.write(&sm, b"same", "image/png", 1).await.unwrap();
+        let shared = t.resolve(&md).unwrap();
+
+        assert_eq!(shared, t.resolve(&sm).unwrap());
+
+        // Still the output of sm
+        t.write(&md, b"other", "image/png", 2).await.unwrap();
+        assert!(shared.is_file());
+
+        t.write(&sm, b"more", "image/png", 3).await.unwrap();
+        assert!(!shared.exists());
+        assert_eq!(files(&root), 2);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn relative_links() {
+        assert_eq!(relative(Path::new("/o/a/gray"), Path::new("/o/a/md.png")), Path::new("../md.png"));
+        assert_eq!(relative(Path::new("/o/a"), Path::new("/o/a/xl.png")), Path::new("xl.png"));
+        assert_eq!(relative(Path::new("/o/a/b"), Path::new("/o/c/xl.png")), Path::new("../../c/xl.png"));
+    }
+}
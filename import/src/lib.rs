@@ -0,0 +1,10 @@
+pub mod resize;
+pub mod transform;
+pub mod actions;
+pub mod decode;
+pub mod export;
+pub mod template;
+pub mod prefilter;
+pub mod effects;
+
+pub use resize::*;
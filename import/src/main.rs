@@ -1,16 +1,19 @@
-mod resize;
-mod transform;
-mod actions;
-
+use std::collections::HashMap;
 use std::fs::read_dir;
+use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::task::JoinSet;
 use tracing::{error, debug, info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use walkdir::WalkDir;
 
 use config::*;
-use resize::*;
-use actions::*;
+use import::resize::*;
+use import::actions::*;
+use import::export::*;
+use import::template::*;
+use import::prefilter::*;
+use import::transform::shapes_from_config;
 
 #[tokio::main]
 async fn main() {
@@ -31,13 +34,6 @@ async fn main() {
 
     debug!("Config loaded...");
 
-    let export_config = match config.export() {
-        Ok(e) => e,
-        Err(e) => panic!("Incomplete config for export: {}", e),
-    };
-
-    debug!("Export config loaded...");
-
     let import_config = match config.import() {
         Ok(i) => i,
         Err(e) => panic!("Incomplete config for import: {}", e),
@@ -45,31 +41,36 @@ async fn main() {
 
     debug!("Import config loaded...");
 
-    // Init object store from config
-    let s3_store = match export_config.s3 {
-        true => {
-            let s3_config = match config.s3_mut() {
-                Ok(c) => c,
-                Err(e) => panic!("Incomplete config for S3: {}", e),
-            };
-
-            // Verify that our bucket exists
-            if let Err(e) = ObjectStore::init_from(s3_config, true).await {
-                panic!("Object store setup from config failed: {}", e)
-            }
-            Some(ObjectStore::get(&s3_config).unwrap())
-        },
-        false => None,
+    // Init filesystem roots and object stores for every export target
+    let targets = match OutputTarget::from_config(&mut config).await {
+        Ok(t) => t,
+        Err(e) => panic!("Incomplete config for export: {}", e),
     };
 
-    // Check if filesystem export config is valid
-    if export_config.filesystem {
-        if export_config.filesystem_path.is_none() && export_config.prefix.is_none() {
-            panic!("Filesystem export requires either 'filesystem_path' or 'prefix' to be set");
-        }
+    match targets.is_empty() {
+        true => panic!("No export targets enabled, nowhere to save resized images"),
+        false => debug!("Export config loaded with {} target(s)...", targets.len()),
     }
 
+    let template = match PathTemplate::from_config(&config) {
+        Ok(t) => t,
+        Err(e) => panic!("Invalid output path template: {}", e),
+    };
+
+    // Built-in and custom shapes are read once, a broken SVG stops the import early
+    let shapes = match shapes_from_config(&config) {
+        Ok(s) => Arc::new(s),
+        Err(e) => panic!("Invalid shapes config: {}", e),
+    };
+
+    // Dirs of filesystem targets, we should never read exported files again
+    let output_dirs = targets.iter()
+        .filter_map(|t|t.output_dir())
+        .collect::<Vec<PathBuf>>();
+
     let mut queue = vec![];
+    let mut stats = ResizeStats::new();
+    let prefilter = Prefilter::from_config(&import_config);
 
     if import_config.include.is_none() {
         debug!("No limited set of subdirs specified, using config.toml root dir as source");
@@ -89,23 +90,11 @@ async fn main() {
 
         let path = entry.path();
 
-        if export_config.filesystem {
-            // If we're exporting to filesystem and target path is given outside the config dir
-            // we can skip the prefix comparison
-            if let Some(ref i) = export_config.filesystem_path {
-                if config.dir.ne(i) {
-                    continue;
-                }
-            }
-
-            // Next iteration should not read exported files again. Compare prefix.
-            if let Some(ref i) = export_config.prefix {
-                if path.ends_with(i) {
-                    debug!("Skipping filesystem path as it ends to export prefix: {:?}", path);
+        // Skip dirs that hold exported files
+        if output_dirs.iter().any(|d|d.starts_with(&path)) {
+            debug!("Skipping filesystem path as it contains exported images: {:?}", path);
 
-                    continue;
-                }
-            }
+            continue;
         }
 
         if path.is_dir() {
@@ -127,7 +116,7 @@ async fn main() {
     // Iterate non-exluded subdirs from config.toml dir
     for p in root_dirs.iter() {
         // Walk all files in subdir
-        for i in WalkDir::new(&p) {
+        for i in WalkDir::new(p).into_iter().filter_entry(|e|prefilter.walk_entry(e)) {
             let e = match i {
                 Ok(x) => x,
                 Err(e) => {
@@ -141,52 +130,60 @@ async fn main() {
                 false => continue,
             };
 
-            info!("Sending file {:?} to resizer queue", &file);
-            let mut target_dir = config.dir.to_owned();
-
-            // Target dir starts with a custom root dir?
-            if let Some(ref e) = export_config.prefix {
-                target_dir.push(e);
-            };
+            // Non-images never make it to the queue
+            if let Some(reason) = prefilter.ignore_reason(&file) {
+                debug!("Ignoring {}", reason);
+                stats.ignored.push(reason);
 
-            // Add root dir to target path
-            target_dir.push(p.file_name().expect("Failed to extract root dir name"));
+                continue;
+            }
 
-            // Add subpath of file into the target path
-            match file.strip_prefix(&p) {
-                Ok(t) => target_dir.push(t),
+            // Output paths are built from the root dir name and the subpath of the file
+            match OutputName::new(p, &file) {
+                Ok(n) => queue.push((file, n)),
                 Err(e) => {
-                    error!("Failed to build target dir path: {}", e);
-                    continue;
+                    error!("{}", e);
+                    stats.failed.push(e.to_string());
                 }
             }
-            
-            // Path is expected to a dir, strip filename
-            target_dir.pop();
-
-            queue.push((file, target_dir));
         }
     }
 
-    // Should we save resized images to filesystem?
-    let export_fs = match export_config.filesystem {
-        true => match export_config.filesystem_path {
-            Some(ref p) => Some(p.to_owned()),
-            None => Some(config.dir.to_owned()),
-        },
-        false => None,
-    };
+    // Sources that would render into the same output paths would overwrite each other,
+    // find them before anything gets written.
+    let mut outputs: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+
+    for (file, name) in queue.iter() {
+        let output = template.render(name, "{size}", "{ext}");
+        outputs.entry(output).or_default().push(file.to_owned());
+    }
+
+    for (output, files) in outputs.iter().filter(|(_, f)|f.len() > 1) {
+        let sources = files.iter()
+            .map(|f|f.to_string_lossy().into())
+            .collect::<Vec<String>>();
+
+        let e = format!("Sources [{}] collide in output path {:?}", sources.join(", "), output);
+
+        error!("{}", e);
+        stats.failed.push(e);
+    }
+
+    queue.retain(|(_, name)|outputs[&template.render(name, "{size}", "{ext}")].len() == 1);
+
+    for (file, _) in queue.iter() {
+        info!("Sending file {:?} to resizer queue", file);
+    }
 
     // Process queue concurrently
     let queue_len = queue.len();
-    let mut stats = ResizeStats::new();
+
+    // Sources left out of the queue are failed already
+    let failed_before = stats.failed.len();
     
     // Lower chunk size for slower machines. Parallel processing of multiple large images
     // is a heavy task and can drain all resources.
-    let chunk_size = match config.parallel_img_max {
-        Some(u) => u,
-        None => 4,
-    };
+    let chunk_size = config.parallel_img_max.unwrap_or(4);
 
     info!("Processing resize queue of {} files in chunks of {} images concurrently...", queue_len, chunk_size);
 
@@ -200,13 +197,13 @@ async fn main() {
 
         let mut handles = JoinSet::new();
 
-        for (importable, target) in chunk {
-            let s3 = s3_store.clone();
-            let fs = export_fs.clone();
+        for (importable, name) in chunk {
+            let exports = targets.clone();
             let conf = config.clone();
+            let temp = template.clone();
             
             handles.spawn(async move {
-                resize_action(importable, target, conf, fs, s3).await
+                resize_action(importable, name, conf, temp, exports).await
             });
         }
 
@@ -216,16 +213,15 @@ async fn main() {
                 Err(e) => panic!("Failed to join concurrently running resizer tasks: {}", e),
             }
         }
+
+        save_manifests(&targets, &mut stats).await;
     }
 
     // Run transformations against resized images to save up some resources
     if let Some(f) = TargetSize::transform_size_variant(&config) {
         info!("Processing transform queue of {} files in chunks of {} images concurrently...", queue_len, chunk_size);
     
-        let source_img_opts = [
-            format!("{}.jpeg", f.to_str()),
-            format!("{}.png", f.to_str()),
-        ];
+        let source_img_opts = ["jpeg", "png", "webp", "gif"];
     
         for c in queue.chunks(chunk_size) {
             let chunk = c.to_vec();
@@ -233,31 +229,27 @@ async fn main() {
     
             let mut handles = JoinSet::new();
     
-            for (filepath, mut target) in chunk {
-                // Take filename without the extension from te filepath
-                let filename = filepath.file_stem().expect("Failed to extract filename from path");
-
-                // Try both possible resized image files, other should exist
-                for o in source_img_opts.iter() {
-                    let mut importable = target.clone();
-                    importable.push(filename);
-                    importable.push(o);
-
-                    if importable.is_file() {
-                        let s3 = s3_store.clone();
-                        let fs = export_fs.clone();
+            for (filepath, name) in chunk {
+                // Try both possible resized image files from filesystem targets, other should exist
+                let importable = source_img_opts.iter()
+                    .map(|o|template.render(&name, f.to_str(), o))
+                    .flat_map(|o|targets.iter().map(move |t|(o.to_owned(), t)))
+                    .filter_map(|(o, t)|t.output_dir().and_then(|_|t.resolve(&o)))
+                    .find(|p|p.is_file());
+
+                match importable {
+                    Some(importable) => {
+                        let exports = targets.clone();
                         let size = f.clone();
-                        target.push(filename);
+                        let temp = template.clone();
+                        let conf = config.clone();
+                        let shapes = shapes.clone();
 
                         handles.spawn(async move {
-                            transform_action(importable, target, size, fs, s3).await
+                            transform_action(importable, name, size, temp, conf, shapes, exports).await
                         });
-
-                        break;
-                    }
-                    else {
-                        debug!("Skipping transform for {:?} as it doesn't exist", importable);
-                    }
+                    },
+                    None => debug!("Skipping transform for {:?} as it doesn't exist on filesystem", filepath),
                 }
             }
     
@@ -267,25 +259,11 @@ async fn main() {
                     Err(e) => panic!("Failed to join concurrently running resizer tasks: {}", e),
                 }
             }
+
+            save_manifests(&targets, &mut stats).await;
         }
     }
 
-    // for (importable, target) in queue {
-    //     let s3 = s3_store.clone();
-    //     let fs = export_fs.clone();
-        
-    //     handles.spawn(async move {
-    //         img_sizer(importable, target, fs, s3).await
-    //     });
-    // }
-
-    // while let Some(r) = handles.join_next().await {
-    //     match r {
-    //         Ok(r) => stats.extend(r),
-    //         Err(e) => error!("Failed to join concurrently running resizer tasks: {}", e),
-    //     }
-    // }
-
     // Report errors
     if ! stats.failed.is_empty() {
         error!("Resizer failed for {} files", stats.failed.len());
@@ -304,11 +282,26 @@ async fn main() {
         }
     }
 
+    // Report non-images left out of the queue
+    if ! stats.ignored.is_empty() {
+        info!("Ignored {} non-image files", stats.ignored.len());
+    }
+
     // Report succeeded
     if ! stats.succeeded.is_empty() {
-        let tot = queue_len - stats.failed.len();
+        let tot = queue_len.saturating_sub(stats.failed.len() - failed_before);
 
         info!("Resized successfully {} source images", tot);
         info!("In total {} images were saved.", stats.succeeded.len());
     }
 }
+
+/// Manifests point to the hashed output names, saved after every chunk so that an import
+/// that stops halfway still knows where its outputs went.
+async fn save_manifests(targets: &[OutputTarget], stats: &mut ResizeStats) {
+    for t in targets.iter() {
+        if let Err(e) = t.save_manifest().await {
+            stats.failed.push(format!("{}: {}", t.name, e));
+        }
+    }
+}
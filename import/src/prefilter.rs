@@ -0,0 +1,156 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use config::Import;
+use walkdir::DirEntry;
+
+use crate::decode::{HEIF_EXTENSIONS, RAW_EXTENSIONS};
+
+
+/// Extensions of the formats the decoders can read.
+pub const IMAGE_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "jpe", "jfif", "png", "apng", "gif", "webp", "bmp", "tif", "tiff", "tga", "ico",
+    "pbm", "pgm", "ppm", "pnm", "qoi", "exr", "hdr", "dds", "ff",
+];
+
+/// Leading bytes of camera RAW containers, most of them are TIFFs.
+const RAW_MAGIC: &[&[u8]] = &[
+    b"II*\0", b"MM\0*", b"IIRO", b"IIRS", b"IIU\0", b"FUJIFILM", b"\0MRM", b"FOVb", b"II\x1a\0\0\0HEAPCCDR",
+];
+
+/// Keeps files that can't be images out of the resize queue.
+#[derive(Debug, Clone)]
+pub struct Prefilter {
+    extensions: Vec<String>,
+    hidden: bool,
+}
+
+impl Prefilter {
+    pub fn from_config(config: &Import) -> Self {
+        let extensions = match config.extensions {
+            Some(ref v) => v.iter()
+                .map(|e|e.trim_start_matches('.').to_lowercase())
+                .collect(),
+            None => IMAGE_EXTENSIONS.iter()
+                .chain(RAW_EXTENSIONS)
+                .chain(HEIF_EXTENSIONS)
+                .map(|e|e.to_string())
+                .collect(),
+        };
+
+        Self { extensions, hidden: config.hidden }
+    }
+    /// Walk filter, hidden files and dirs are left out unless enabled. The walked root always passes.
+    pub fn walk_entry(&self, entry: &DirEntry) -> bool {
+        let hidden = entry.file_name()
+            .to_str()
+            .map(|n|n.starts_with('.'))
+            .unwrap_or(false);
+
+        self.hidden || entry.depth() == 0 || !hidden
+    }
+    /// Reason why the file is not treated as an image, `None` if it should be queued.
+    pub fn ignore_reason(&self, path: &Path) -> Option<String> {
+        let ext = path.extension()
+            .map(|e|e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        if !self.extensions.contains(&ext) {
+            return Some(format!("{:?}: Extension {:?} is not allowed", path, ext))
+        }
+
+        // Only the first bytes are read, decoding is left for the resizer
+        let mut header = [0u8; 32];
+        let len = match File::open(path).and_then(|mut f|f.read(&mut header)) {
+            Ok(l) => l,
+            Err(e) => return Some(format!("{:?}: Failed to read file header: {}", path, e)),
+        };
+
+        match looks_like_image(&ext, &header[..len]) {
+            true => None,
+            false => Some(format!("{:?}: File content doesn't look like an image", path)),
+        }
+    }
+}
+
+fn looks_like_image(ext: &str, header: &[u8]) -> bool {
+    if image::guess_format(header).is_ok() {
+        return true
+    }
+
+    match ext {
+        e if RAW_EXTENSIONS.contains(&e) => RAW_MAGIC.iter().any(|m|header.starts_with(m))
+            // CR3 is an ISO base media file like HEIF
+            || header.get(4..8) == Some(b"ftyp"),
+        e if HEIF_EXTENSIONS.contains(&e) => header.get(4..8) == Some(b"ftyp"),
+        // Formats without a signature, TGA for one, pass on the extension alone
+        _ => image::ImageFormat::from_extension(ext)
+            .map(|f|matches!(f, image::ImageFormat::Tga))
+            .unwrap_or(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn prefilter(toml: &str) -> Prefilter {
+        Prefilter::from_config(&toml::from_str(toml).unwrap())
+    }
+
+    fn file(name: &str, content: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("scandumper-prefilter-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+
+        path
+    }
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    #[test]
+    fn images_pass() {
+        let p = prefilter("");
+
+        assert_eq!(p.ignore_reason(&file("a.png", PNG)), None);
+        assert_eq!(p.ignore_reason(&file("b.PNG", PNG)), None);
+        // Content decides, not the extension
+        assert_eq!(p.ignore_reason(&file("c.jpg", PNG)), None);
+        assert_eq!(p.ignore_reason(&file("d.tga", b"\0\0\x02\0")), None);
+        assert_eq!(p.ignore_reason(&file("e.cr2", b"II*\0\x10\0\0\0CR")), None);
+        assert_eq!(p.ignore_reason(&file("f.heic", b"\0\0\0\x18ftypheic")), None);
+    }
+
+    #[test]
+    fn extensions() {
+        let p = prefilter("");
+
+        let reason = p.ignore_reason(&file("notes.txt", b"hello")).unwrap();
+        assert!(reason.contains("Extension \"txt\""), "{}", reason);
+        assert!(p.ignore_reason(&file("noext", PNG)).is_some());
+
+        let p = prefilter("extensions = [\".PNG\"]");
+        assert_eq!(p.ignore_reason(&file("g.png", PNG)), None);
+        assert!(p.ignore_reason(&file("h.jpg", PNG)).is_some());
+    }
+
+    #[test]
+    fn content() {
+        let p = prefilter("");
+
+        for (name, content) in [("i.png", &b"hello"[..]), ("j.jpg", b""), ("k.nef", b"MZ\x90\0"), ("l.heic", b"\0\0\0\x18moov")] {
+            let reason = p.ignore_reason(&file(name, content)).unwrap();
+            assert!(reason.contains("doesn't look like an image"), "{}", reason);
+        }
+    }
+
+    #[test]
+    fn missing_file() {
+        let reason = prefilter("").ignore_reason(Path::new("/nonexistent/m.png")).unwrap();
+
+        assert!(reason.contains("Failed to read file header"), "{}", reason);
+    }
+}
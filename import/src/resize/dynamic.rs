@@ -0,0 +1,177 @@
+use std::io::Cursor;
+use std::str::FromStr;
+use anyhow::{anyhow, bail, Result};
+use config::ColorSpace;
+use fast_image_resize::images::Image;
+use fast_image_resize::{PixelType, ResizeOptions, Resizer};
+use image::{io::{Limits, Reader as ImageReader}, DynamicImage};
+
+use super::jpeg::jpeg_quality_writer;
+use super::png::png_writer;
+use super::webp::webp_still_writer;
+use crate::decode::{convert, SourcePolicy};
+
+
+/// How the image is placed into a box when both width and height are asked for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fit {
+    /// Fill the box and crop what is left over
+    Cover,
+    /// Whole image centered in the box, the rest is transparent or white
+    Contain,
+    /// Stretch to the box
+    Fill,
+    /// Whole image as large as fits in the box
+    Inside,
+}
+
+impl FromStr for Fit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cover" => Ok(Self::Cover),
+            "contain" => Ok(Self::Contain),
+            "fill" => Ok(Self::Fill),
+            "inside" => Ok(Self::Inside),
+            f => bail!("Unknown fit {:?}, use cover, contain, fill or inside", f),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "jpg" | "jpeg" => Ok(Self::Jpeg),
+            "png" => Ok(Self::Png),
+            "webp" => Ok(Self::Webp),
+            f => bail!("Unknown format {:?}, use jpeg, png or webp", f),
+        }
+    }
+}
+
+impl Format {
+    pub fn ext(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpeg",
+            Self::Png => "png",
+            Self::Webp => "webp",
+        }
+    }
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Webp => "image/webp",
+        }
+    }
+}
+
+/// Single image resized on request, no side given keeps the source size.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    pub format: Format,
+    pub quality: u8,
+}
+
+impl Request {
+    /// Output canvas and the resized image placed on it.
+    fn dimensions(&self, sw: u32, sh: u32) -> ((u32, u32), (u32, u32)) {
+        let scaled = |v: u32, num: u32, den: u32| ((v as u64 * num as u64 + den as u64 / 2) / den as u64).max(1) as u32;
+
+        match (self.width, self.height) {
+            (None, None) => ((sw, sh), (sw, sh)),
+            (Some(w), None) => ((w, scaled(w, sh, sw)), (w, scaled(w, sh, sw))),
+            (None, Some(h)) => ((scaled(h, sw, sh), h), (scaled(h, sw, sh), h)),
+            (Some(w), Some(h)) => {
+                // Side that limits the scale when the whole image has to fit
+                let inner = match sw as u64 * h as u64 > sh as u64 * w as u64 {
+                    true => (w, scaled(w, sh, sw)),
+                    false => (scaled(h, sw, sh), h),
+                };
+
+                match self.fit {
+                    Fit::Cover | Fit::Fill => ((w, h), (w, h)),
+                    Fit::Contain => ((w, h), inner),
+                    Fit::Inside => (inner, inner),
+                }
+            },
+        }
+    }
+}
+
+/// Decode an encoded image, resize and encode it again. Sources are converted to sRGB by
+/// their embedded profile, the result has no profile. Sources outside of the policy fail
+/// with a limit error.
+pub async fn resize_bytes(name: &str, bytes: Vec<u8>, request: Request, policy: &SourcePolicy) -> Result<Vec<u8>> {
+    policy.check_slice(name, &bytes)?;
+
+    let (r, limits) = (request.clone(), policy.limits());
+
+    let image = tokio::task::spawn_blocking(move || resize_image(bytes, &r, limits))
+        .await
+        .map_err(|e| anyhow!("Resize task failed: {}", e))??;
+
+    match request.format {
+        Format::Jpeg => jpeg_quality_writer(image, request.quality.clamp(1, 100)).await,
+        Format::Png => png_writer(image).await,
+        Format::Webp => webp_still_writer(image, request.quality.min(100)).await,
+    }
+}
+
+fn resize_image(bytes: Vec<u8>, request: &Request, limits: Limits) -> Result<Image<'static>> {
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder()?;
+    let icc = image::ImageDecoder::icc_profile(&mut decoder).ok().flatten();
+    let img = DynamicImage::from_decoder(decoder)?;
+    let img = convert(img, icc.as_deref(), ColorSpace::Srgb)?;
+
+    // JPEG has no alpha, the padding of contained images is white there
+    let (img, pixel, background) = match request.format {
+        Format::Jpeg => (DynamicImage::ImageRgb8(img.into_rgb8()), PixelType::U8x3, vec![255; 3]),
+        _ => (DynamicImage::ImageRgba8(img.into_rgba8()), PixelType::U8x4, vec![0; 4]),
+    };
+
+    let ((cw, ch), (bw, bh)) = request.dimensions(img.width(), img.height());
+    let mut resized = Image::new(bw, bh, pixel);
+
+    let options = match request.fit {
+        Fit::Cover => ResizeOptions::new().fit_into_destination(Some((0.5, 0.5))),
+        _ => ResizeOptions::new(),
+    };
+
+    Resizer::new().resize(&img, &mut resized, &options)
+        .map_err(|e| anyhow!("Failed to resize image: {}", e))?;
+
+    if (cw, ch) == (bw, bh) {
+        return Ok(resized)
+    }
+
+    // Resized image is centered on the padded canvas
+    let channels = background.len();
+    let mut canvas = background.repeat((cw * ch) as usize);
+    let (left, top) = ((cw - bw) as usize / 2, (ch - bh) as usize / 2);
+    let row = bw as usize * channels;
+
+    for (y, line) in resized.buffer().chunks_exact(row).enumerate() {
+        let start = ((top + y) * cw as usize + left) * channels;
+        canvas[start..start + row].copy_from_slice(line);
+    }
+
+    Image::from_vec_u8(cw, ch, canvas, pixel)
+        .map_err(|e| anyhow!("Failed to read padded image: {}", e))
+}
@@ -0,0 +1,29 @@
+use fast_image_resize::images::Image;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, Frame, RgbaImage};
+use anyhow::{Result, anyhow};
+
+
+/// Frames come with their delay in milliseconds.
+pub async fn gif_writer(frames: Vec<(Image<'static>, u32)>) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+
+    let frames = frames.into_iter()
+        .map(|(image, delay)| {
+            let buffer = RgbaImage::from_raw(image.width(), image.height(), image.into_vec())
+                .ok_or_else(|| anyhow!("Frame buffer doesn't match its dimensions"))?;
+
+            Ok(Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(delay, 1)))
+        })
+        .collect::<Result<Vec<Frame>>>()?;
+
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut buf, 10);
+
+        encoder.set_repeat(Repeat::Infinite)
+            .and_then(|_| encoder.encode_frames(frames))
+            .map_err(|e| anyhow!("Failed to create animated GIF image: {}", e))?;
+    }
+
+    Ok(buf)
+}
@@ -0,0 +1,72 @@
+use std::io::Write;
+use anyhow::{bail, Result};
+use flate2::{write::ZlibEncoder, Compression, Crc};
+
+
+/// Embed the output ICC profile into encoded PNG or JPEG bytes, the image crate encoders can't.
+pub fn embed_icc(buf: Vec<u8>, ext: &str, icc: &[u8]) -> Result<Vec<u8>> {
+    match ext {
+        "png" => png_iccp(buf, icc),
+        "jpeg" => jpeg_app2(buf, icc),
+        _ => Ok(buf),
+    }
+}
+
+/// iCCP chunk goes right after IHDR, which is always the first chunk.
+fn png_iccp(buf: Vec<u8>, icc: &[u8]) -> Result<Vec<u8>> {
+    const IHDR_END: usize = 8 + 4 + 4 + 13 + 4;
+
+    if buf.len() < IHDR_END || &buf[12..16] != b"IHDR" {
+        bail!("Encoded PNG doesn't start with an IHDR chunk")
+    }
+
+    let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+    encoder.write_all(icc)?;
+
+    let mut data = b"ICC profile\0\0".to_vec();
+    data.extend(encoder.finish()?);
+
+    let mut crc = Crc::new();
+    crc.update(b"iCCP");
+    crc.update(&data);
+
+    let mut out = Vec::with_capacity(buf.len() + data.len() + 12);
+    out.extend(&buf[..IHDR_END]);
+    out.extend((data.len() as u32).to_be_bytes());
+    out.extend(b"iCCP");
+    out.extend(&data);
+    out.extend(crc.sum().to_be_bytes());
+    out.extend(&buf[IHDR_END..]);
+
+    Ok(out)
+}
+
+/// APP2 segments right after SOI, large profiles are split into numbered chunks.
+fn jpeg_app2(buf: Vec<u8>, icc: &[u8]) -> Result<Vec<u8>> {
+    const MAX_CHUNK: usize = 65535 - 2 - 14;
+
+    if !buf.starts_with(&[0xFF, 0xD8]) {
+        bail!("Encoded JPEG doesn't start with SOI marker")
+    }
+
+    let chunks = icc.chunks(MAX_CHUNK).collect::<Vec<_>>();
+
+    if chunks.len() > 255 {
+        bail!("ICC profile of {} bytes is too large for JPEG", icc.len())
+    }
+
+    let mut out = Vec::with_capacity(buf.len() + icc.len() + chunks.len() * 18);
+    out.extend(&buf[..2]);
+
+    for (i, chunk) in chunks.iter().enumerate() {
+        out.extend([0xFF, 0xE2]);
+        out.extend(((chunk.len() + 2 + 14) as u16).to_be_bytes());
+        out.extend(b"ICC_PROFILE\0");
+        out.extend([i as u8 + 1, chunks.len() as u8]);
+        out.extend(*chunk);
+    }
+
+    out.extend(&buf[2..]);
+
+    Ok(out)
+}
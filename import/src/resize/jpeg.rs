@@ -1,29 +1,42 @@
+use std::borrow::Cow;
 use fast_image_resize::images::Image;
+use fast_image_resize::PixelType;
 use image::codecs::jpeg::JpegEncoder;
 use image::{ExtendedColorType, ImageEncoder};
-use anyhow::{Result, anyhow};
+use anyhow::{Result, anyhow, bail};
 
 
+/// Gray or color JPEG by the pixel, JPEG has no alpha.
 pub async fn jpeg_writer(image: Image<'static>) -> Result<Vec<u8>> {
+    jpeg_quality_writer(image, 75).await
+}
+
+/// Same as `jpeg_writer` with quality from 1 to 100.
+pub async fn jpeg_quality_writer(image: Image<'static>, quality: u8) -> Result<Vec<u8>> {
     let mut buf = vec![];
 
-    JpegEncoder::new(&mut buf).write_image(
-        image.buffer(),
+    let color = match image.pixel_type() {
+        PixelType::U8 | PixelType::U16 => ExtendedColorType::L8,
+        PixelType::U8x3 | PixelType::U16x3 => ExtendedColorType::Rgb8,
+        p => bail!("Unsupported JPEG pixel type: {:?}", p),
+    };
+
+    JpegEncoder::new_with_quality(&mut buf, quality).write_image(
+        &to_u8(&image),
         image.width(),
         image.height(),
-        ExtendedColorType::Rgb8,
+        color,
     ).map_err(|e| anyhow!("Failed to create JPEG image: {}", e))
     .map(|_| buf)
 }
 
-pub async fn jpeg_gray_writer(image: Image<'static>) -> Result<Vec<u8>> {
-    let mut buf = vec![];
-
-    JpegEncoder::new(&mut buf).write_image(
-        image.buffer(),
-        image.width(),
-        image.height(),
-        ExtendedColorType::L8,
-    ).map_err(|e| anyhow!("Failed to create grayscale JPEG image: {}", e))
-    .map(|_| buf)
+/// JPEG is 8 bits only, 16 bit images are resized first and reduced here.
+fn to_u8<'a>(image: &'a Image<'static>) -> Cow<'a, [u8]> {
+    match image.pixel_type() {
+        PixelType::U16 | PixelType::U16x3 => image.buffer()
+            .chunks_exact(2)
+            .map(|v| ((u16::from_ne_bytes([v[0], v[1]]) as u32 * 255 + 32767) / 65535) as u8)
+            .collect(),
+        _ => Cow::Borrowed(image.buffer()),
+    }
 }
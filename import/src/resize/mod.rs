@@ -1,19 +1,28 @@
 pub mod jpeg;
 pub mod png;
+pub mod gif;
+pub mod webp;
+pub mod upscale;
+pub mod icc;
+pub mod sharpen;
+pub mod dynamic;
 
-use std::path::PathBuf;
-use fast_image_resize::images::Image;
-use fast_image_resize::{IntoImageView, ResizeOptions};
+use fast_image_resize::images::{Image, ImageRef};
+use fast_image_resize::{create_srgb_mapper, IntoImageView, PixelType, ResizeOptions};
 use fast_image_resize::Resizer;
 use anyhow::{Result, bail};
-use tokio::fs::create_dir_all;
 use tokio::task::JoinSet;
 use std::future::Future;
+use std::path::PathBuf;
 use tracing::debug;
 
-use config::{Config, ObjectStore, TransformVariant};
+use config::{Config, TransformVariant};
 
+use crate::effects::{apply, apply_keep};
+use crate::export::OutputTarget;
 use crate::transform::Transformable;
+use icc::embed_icc;
+use sharpen::{resize_alg, unsharp_mask};
 
 
 #[derive(Debug, Clone)]
@@ -73,6 +82,10 @@ impl TargetSize {
             TargetSize::Xs(_) => "xs",
         }
     }
+    /// Ids of every size from the largest down.
+    pub fn ids() -> [&'static str; 6] {
+        [Self::Original(0), Self::Xl(0), Self::Lg(0), Self::Md(0), Self::Sm(0), Self::Xs(0)].map(|s| s.to_str())
+    }
     pub fn to_px(&self) -> u32 {
         match self {
             TargetSize::Original(u) |
@@ -96,15 +109,17 @@ impl TargetSize {
     }
 }
 
+#[derive(Default)]
 pub struct ResizeStats {
     pub succeeded: Vec<String>,
     pub skipped: Vec<String>,
     pub failed: Vec<String>,
+    pub ignored: Vec<String>,
 }
 
 impl ResizeStats {
     pub fn new() -> Self {
-        Self { succeeded: vec![], skipped: vec![], failed: vec![] }
+        Self { succeeded: vec![], skipped: vec![], failed: vec![], ignored: vec![] }
     }
     pub fn push(&mut self, response: Result<String>) {
         match response {
@@ -118,6 +133,7 @@ impl ResizeStats {
                 self.succeeded.extend(s.succeeded);
                 self.skipped.extend(s.skipped);
                 self.failed.extend(s.failed);
+                self.ignored.extend(s.ignored);
             },
             Err(e) => {
                 self.failed.push(e.to_string());
@@ -127,14 +143,20 @@ impl ResizeStats {
 }
 
 pub async fn resize_handler<F>(items: &[(u32, &str)], transformable: &Transformable, original: &impl IntoImageView,
-options: &Option<ResizeOptions>, s3: &Option<ObjectStore>, fs: &Option<PathBuf>,
+options: &Option<ResizeOptions>, targets: &[OutputTarget],
 writer: impl Fn(Image<'static>) -> F + Send + 'static + Copy) -> Result<ResizeStats>
 where
     F: Send + Sized,
     F: Future<Output = Result<Vec<u8>, anyhow::Error>>
 {
-    let resizables = transformable.get_resizables(items, s3, fs).await;
     let mut stats = ResizeStats::new();
+
+    // Every size was left out by the upscale policy
+    if items.is_empty() {
+        return Ok(stats)
+    }
+
+    let resizables = transformable.get_resizables(items, targets).await;
     let mut resizer = Resizer::new();
     
     if resizables.is_empty() {
@@ -143,12 +165,25 @@ where
         return Ok(stats)
     }
 
+    // Linear light is resized in 16 bits whatever the output depth
+    let mapper = create_srgb_mapper();
+    let linear = match transformable.linear {
+        true => {
+            let mut l = Image::new(original.width(), original.height(), linear_pixel(transformable.pixel));
+            mapper.forward_map(original, &mut l)?;
+            Some(l)
+        },
+        false => None,
+    };
+
     let mut handles = JoinSet::new();
 
-    for (ratio, id, resized_file) in resizables {
-        let (w, h) = transformable.convert_ratio(ratio);
+    for (ratio, id, resized_file, exportable) in resizables {
+        let (w, h) = transformable.target_dimensions(ratio, &id);
         let mut resized = Image::new(w, h, transformable.pixel);
-        let checksum = transformable.checksum.to_string();
+        let checksum = transformable.output_checksum(&id);
+        let ext = transformable.target_ext;
+        let opts = options.unwrap_or_default().resize_alg(resize_alg(transformable.filter.get(&id)));
 
         debug!(
             "Resizing input image {} to path {:?} to width: {} and height: {}...",
@@ -156,37 +191,32 @@ where
         );
 
         // Resize source image into buffer of destination image
-        resizer.resize(original, &mut resized, options)?;
-    
-        // Create new bucket for each task: https://github.com/durch/rust-s3/issues/337
-        // v.0.34.0 of rust-s3 should fix this issue
-        let s3 = s3.clone();
-        let fs = fs.clone();
-        let mime = match transformable.target_ext {
-            "png" => "image/png",
-            "jpeg" => "image/jpeg",
-            _ => bail!("Unsupported image format: {:?}", transformable.target_ext),
-        };
-
-        // Create target dir if filesystem is selected
-        let resized_dir = match resized_file.parent() {
-            Some(p) => p.to_owned(),
-            None => bail!("Failed to extract parent dir from resized image path {:?}", resized_file),
-        
-        };
+        match linear {
+            Some(ref l) => {
+                let mut resized_linear = Image::new(w, h, l.pixel_type());
+                resizer.resize(l, &mut resized_linear, &opts)?;
+                mapper.backward_map(&resized_linear, &mut resized)?;
+            },
+            None => resizer.resize(original, &mut resized, &opts)?,
+        }
 
-        // Create resized image dir if filesystem exporting is selected
-        if fs.is_some() {
-            if let Err(e) = create_dir_all(&resized_dir).await {
-                panic!("Failed to create target dir for resized images {}: {}", resized_dir.display(), e)
-            }
+        if let Some(s) = transformable.sharpen.get(&id) {
+            unsharp_mask(&mut resized, &s)?;
+        }
 
-            debug!("Created target dir for resized images: {:?}", &resized_dir);
+        if !transformable.effects.is_empty() {
+            resized = apply(resized, &transformable.effects)?;
         }
 
-        handles.spawn(async move {
-            let mut targets = vec![];
+        // Gray results have no gamut, an RGB profile would not be valid for them
+        let icc = match resized.pixel_type() {
+            PixelType::U8 | PixelType::U8x2 | PixelType::U16 | PixelType::U16x2 => None,
+            _ => transformable.icc.clone(),
+        };
+
+        let mime = mime(transformable.target_ext)?;
 
+        handles.spawn(async move {
             // Read resized image into bytes for writing
             let buf = match writer(resized).await {
                 Ok(v) => v,
@@ -195,45 +225,145 @@ where
                 }
             
             };
-            
-            // Write image into the filesystem
-            if fs.is_some() {
-                targets.push("filesystem");
 
-                // Write resized image to file
-                if let Err(e) = tokio::fs::write(&resized_file, &buf).await {
-                    bail!("Failed to write resized image {}: {}", resized_file.display(), e)
-                }
+            // Outputs in other than sRGB carry their profile
+            let buf = match icc {
+                Some(i) => embed_icc(buf, ext, &i)?,
+                None => buf,
+            };
 
-                // Write checksum to file next to the resized image
-                let mut checksum_file = resized_dir;
-                checksum_file.push(format!(".{}.checksum", id));
+            export(resized_file, id, buf, mime, checksum, exportable).await
+        });
+    }
 
-                if let Err(e) = tokio::fs::write(&checksum_file, &checksum).await {
-                    bail!("Failed to write checksum file {}: {}", checksum_file.display(), e)
-                }
-            }
-            
-            // Write image into the S3 bucket with checksum tag
-            if let Some(s) = s3 {
-                targets.push("S3");
+    while let Some(r) = handles.join_next().await {
+        let response = r.expect("Failed to execute spawned task");
+        stats.extend(response);
+    }
 
-                let s3_path = resized_file.to_string_lossy();
+    Ok(stats)
+}
 
-                if let Err(e) = s.0.put_object_with_content_type(s3_path.as_ref(), &buf, mime).await {
-                    bail!("{}: Failed to store S3 object: {}", s3_path.as_ref(), e)
-                }
+/// Resize every frame of an animated source and encode them back into a single animation.
+pub async fn animation_handler<F>(items: &[(u32, &str)], transformable: &Transformable, options: &Option<ResizeOptions>,
+targets: &[OutputTarget], writer: impl Fn(Vec<(Image<'static>, u32)>) -> F + Send + 'static + Copy)
+-> Result<ResizeStats>
+where
+    F: Send + Sized,
+    F: Future<Output = Result<Vec<u8>, anyhow::Error>>
+{
+    let frames = match transformable.frames {
+        Some(ref f) => f.clone(),
+        None => bail!("{:?}: Animation handler fed with a still image", transformable.source_path),
+    };
+
+    let mut stats = ResizeStats::new();
+
+    // Every size was left out by the upscale policy
+    if items.is_empty() {
+        return Ok(stats)
+    }
+
+    let resizables = transformable.get_resizables(items, targets).await;
+    let mut resizer = Resizer::new();
+    
+    if resizables.is_empty() {
+        stats.skipped.push(format!("Animation {:?} already resized", transformable.source_path));
+
+        return Ok(stats)
+    }
+
+    let mut handles = JoinSet::new();
+
+    for (ratio, id, resized_file, exportable) in resizables {
+        let (w, h) = transformable.target_dimensions(ratio, &id);
+        let checksum = transformable.output_checksum(&id);
+        let mime = mime(transformable.target_ext)?;
+        let opts = options.unwrap_or_default().resize_alg(resize_alg(transformable.filter.get(&id)));
+        let sharpen = transformable.sharpen.get(&id);
+        let mut resized_frames = vec![];
 
-                s.tag_source(&checksum, &resized_file).await?;
+        debug!(
+            "Resizing {} frames of input animation {} to path {:?} to width: {} and height: {}...",
+            frames.len(), id.to_uppercase(), resized_file, w, h
+        );
+
+        for f in frames.iter() {
+            let mut resized = Image::new(w, h, PixelType::U8x4);
+            let (numer, denom) = f.delay().numer_denom_ms();
+            let frame = ImageRef::new(f.buffer().width(), f.buffer().height(), f.buffer().as_raw(), PixelType::U8x4)?;
+
+            resizer.resize(&frame, &mut resized, &opts)?;
+
+            if let Some(ref s) = sharpen {
+                unsharp_mask(&mut resized, s)?;
             }
 
-            Ok(format!("Resized image {} / {} saved successfully to {}", resized_file.display(), id, targets.join(", ")))
+            if !transformable.effects.is_empty() {
+                resized = apply_keep(resized, &transformable.effects)?;
+            }
+
+            resized_frames.push((resized, numer / denom.max(1)));
+        }
+
+        handles.spawn(async move {
+            // Encode resized frames into animation bytes for writing
+            let buf = match writer(resized_frames).await {
+                Ok(v) => v,
+                Err(e) => {
+                    bail!("{}: Failed to read animation to bytes: {}", resized_file.display(), e)
+                }
+            };
+
+            export(resized_file, id, buf, mime, checksum, exportable).await
         });
     }
 
     while let Some(r) = handles.join_next().await {
         let response = r.expect("Failed to execute spawned task");
-        stats.push(response);
+        stats.extend(response);
+    }
+
+    Ok(stats)
+}
+
+/// 16 bit counterpart of the pixel, linear light needs the precision.
+fn linear_pixel(pixel: PixelType) -> PixelType {
+    match pixel {
+        PixelType::U8 => PixelType::U16,
+        PixelType::U8x2 => PixelType::U16x2,
+        PixelType::U8x3 => PixelType::U16x3,
+        PixelType::U8x4 => PixelType::U16x4,
+        p => p,
+    }
+}
+
+pub fn mime(ext: &str) -> Result<&'static str> {
+    match ext {
+        "png" => Ok("image/png"),
+        "jpeg" => Ok("image/jpeg"),
+        "webp" => Ok("image/webp"),
+        "gif" => Ok("image/gif"),
+        _ => bail!("Unsupported image format: {:?}", ext),
+    }
+}
+
+/// Fan the resized image out to every export target that accepted it, a failing target
+/// doesn't keep the image from the others.
+async fn export(resized_file: PathBuf, id: String, buf: Vec<u8>, mime: &str, checksum: u32, exportable: Vec<OutputTarget>)
+-> Result<ResizeStats> {
+    let mut stats = ResizeStats::new();
+    let mut saved = vec![];
+
+    for t in exportable.iter() {
+        match t.write(&resized_file, &buf, mime, checksum).await {
+            Ok(()) => saved.push(t.name.as_str()),
+            Err(e) => stats.failed.push(format!("{}: {}", t.name, e)),
+        }
+    }
+
+    if !saved.is_empty() {
+        stats.succeeded.push(format!("Resized image {} / {} saved successfully to {}", resized_file.display(), id, saved.join(", ")));
     }
 
     Ok(stats)
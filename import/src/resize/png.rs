@@ -1,29 +1,31 @@
 use fast_image_resize::images::Image;
+use fast_image_resize::PixelType;
 use image::codecs::png::PngEncoder;
 use image::{ExtendedColorType, ImageEncoder};
-use anyhow::{Result, anyhow};
+use anyhow::{Result, anyhow, bail};
 
 
+/// Color type follows the pixel, gray variants and 16 bit sources keep theirs.
 pub async fn png_writer(image: Image<'static>) -> Result<Vec<u8>> {
     let mut buf = vec![];
 
-    PngEncoder::new(&mut buf).write_image(
-        image.buffer(),
-        image.width(),
-        image.height(),
-        ExtendedColorType::Rgba8,
-    ).map_err(|e| anyhow!("Failed to create PNG image: {}", e))
-    .map(|_| buf)
-}
-
-pub async fn png_gray_writer(image: Image<'static>) -> Result<Vec<u8>> {
-    let mut buf = vec![];
+    let color = match image.pixel_type() {
+        PixelType::U8 => ExtendedColorType::L8,
+        PixelType::U8x2 => ExtendedColorType::La8,
+        PixelType::U8x3 => ExtendedColorType::Rgb8,
+        PixelType::U8x4 => ExtendedColorType::Rgba8,
+        PixelType::U16 => ExtendedColorType::L16,
+        PixelType::U16x2 => ExtendedColorType::La16,
+        PixelType::U16x3 => ExtendedColorType::Rgb16,
+        PixelType::U16x4 => ExtendedColorType::Rgba16,
+        p => bail!("Unsupported PNG pixel type: {:?}", p),
+    };
 
     PngEncoder::new(&mut buf).write_image(
         image.buffer(),
         image.width(),
         image.height(),
-        ExtendedColorType::La8,
-    ).map_err(|e| anyhow!("Failed to create grayscale PNG image: {}", e))
+        color,
+    ).map_err(|e| anyhow!("Failed to create PNG image: {}", e))
     .map(|_| buf)
 }
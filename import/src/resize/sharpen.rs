@@ -0,0 +1,93 @@
+use anyhow::{bail, Result};
+use config::{Filter, Sharpen};
+use fast_image_resize::images::Image;
+use fast_image_resize::{FilterType, PixelType, ResizeAlg};
+use image::{imageops, ImageBuffer, Luma, LumaA, Pixel, Primitive, Rgb, Rgba};
+
+
+pub fn resize_alg(filter: Filter) -> ResizeAlg {
+    match filter {
+        Filter::Nearest => ResizeAlg::Nearest,
+        Filter::Box => ResizeAlg::Convolution(FilterType::Box),
+        Filter::Bilinear => ResizeAlg::Convolution(FilterType::Bilinear),
+        Filter::Hamming => ResizeAlg::Convolution(FilterType::Hamming),
+        Filter::CatmullRom => ResizeAlg::Convolution(FilterType::CatmullRom),
+        Filter::Mitchell => ResizeAlg::Convolution(FilterType::Mitchell),
+        Filter::Gaussian => ResizeAlg::Convolution(FilterType::Gaussian),
+        Filter::Lanczos3 => ResizeAlg::Convolution(FilterType::Lanczos3),
+        Filter::SupersampledBox => ResizeAlg::SuperSampling(FilterType::Box, 4),
+    }
+}
+
+/// Sharpen the resized image in place, alpha is left alone.
+pub fn unsharp_mask(image: &mut Image<'static>, sharpen: &Sharpen) -> Result<()> {
+    let (w, h) = (image.width(), image.height());
+
+    match image.pixel_type() {
+        PixelType::U8 => unsharp::<Luma<u8>>(image.buffer_mut(), w, h, sharpen, 1.),
+        PixelType::U8x2 => unsharp::<LumaA<u8>>(image.buffer_mut(), w, h, sharpen, 1.),
+        PixelType::U8x3 => unsharp::<Rgb<u8>>(image.buffer_mut(), w, h, sharpen, 1.),
+        PixelType::U8x4 => unsharp::<Rgba<u8>>(image.buffer_mut(), w, h, sharpen, 1.),
+        p @ (PixelType::U16 | PixelType::U16x2 | PixelType::U16x3 | PixelType::U16x4) => {
+            // Buffer is bytes, 16 bit values are copied out and back in
+            let buf = image.buffer_mut();
+            let mut data = buf.chunks_exact(2)
+                .map(|v| u16::from_ne_bytes([v[0], v[1]]))
+                .collect::<Vec<_>>();
+
+            match p {
+                PixelType::U16 => unsharp::<Luma<u16>>(&mut data, w, h, sharpen, 257.),
+                PixelType::U16x2 => unsharp::<LumaA<u16>>(&mut data, w, h, sharpen, 257.),
+                PixelType::U16x3 => unsharp::<Rgb<u16>>(&mut data, w, h, sharpen, 257.),
+                _ => unsharp::<Rgba<u16>>(&mut data, w, h, sharpen, 257.),
+            }
+
+            for (b, v) in buf.chunks_exact_mut(2).zip(data) {
+                b.copy_from_slice(&v.to_ne_bytes());
+            }
+        },
+        p => bail!("Sharpening {:?} images is not supported", p),
+    }
+
+    Ok(())
+}
+
+/// `scale` takes the 8 bit threshold to the depth of the pixel.
+fn unsharp<P>(data: &mut [P::Subpixel], w: u32, h: u32, sharpen: &Sharpen, scale: f32)
+where
+    P: Pixel + 'static,
+    P::Subpixel: Primitive + Into<f32> + 'static,
+{
+    let blurred = match ImageBuffer::<P, Vec<P::Subpixel>>::from_raw(w, h, data.to_vec()) {
+        Some(b) => imageops::blur(&b, sharpen.radius),
+        None => return,
+    };
+
+    let channels = P::CHANNEL_COUNT as usize;
+    let has_alpha = channels == 2 || channels == 4;
+    let threshold = sharpen.threshold as f32 * scale;
+    let max: f32 = P::Subpixel::DEFAULT_MAX_VALUE.into();
+
+    for (i, (v, b)) in data.iter_mut().zip(blurred.into_raw()).enumerate() {
+        if has_alpha && i % channels == channels - 1 {
+            continue
+        }
+
+        let original: f32 = (*v).into();
+        let diff = original - b.into();
+
+        if diff.abs() < threshold {
+            continue
+        }
+
+        let sharpened = (original + sharpen.amount * diff).round().clamp(0., max);
+        *v = match num_cast(sharpened) {
+            Some(s) => s,
+            None => continue,
+        };
+    }
+}
+
+fn num_cast<T: Primitive>(v: f32) -> Option<T> {
+    T::from(v)
+}
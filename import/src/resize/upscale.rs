@@ -0,0 +1,169 @@
+use anyhow::Result;
+use config::{Upscale, UpscalePolicy};
+
+use crate::export::OutputTarget;
+use crate::transform::Transformable;
+use super::{mime, ResizeStats};
+
+
+/// Decides what happens to the target sizes larger than the source. Sizes that are copied
+/// or aliased all point to the smallest of them, which is resized to the source size.
+/// A plan covers sizes that are cropped the same way.
+#[derive(Debug, Clone, Default)]
+pub struct UpscalePlan {
+    pub upscaled: Vec<String>,
+    pub skipped: Vec<String>,
+    pub largest: Option<String>,
+    pub links: Vec<(String, Upscale)>,
+}
+
+impl UpscalePlan {
+    /// Uncropped sizes fit the long side of the source, cropped ones fill the square of the short side.
+    pub fn new(transformable: &Transformable, sizes: &[(u32, &str)], cropped: bool, policy: &UpscalePolicy) -> Self {
+        let side = match cropped {
+            true => transformable.width.min(transformable.height),
+            false => transformable.width.max(transformable.height),
+        };
+
+        Self::for_side(side.get(), sizes, policy)
+    }
+    fn for_side(side: u32, sizes: &[(u32, &str)], policy: &UpscalePolicy) -> Self {
+        let mut plan = Self::default();
+
+        let mut oversized = sizes.iter()
+            .filter(|(r, _)|*r > side)
+            .collect::<Vec<_>>();
+
+        oversized.sort_by_key(|(r, _)|*r);
+
+        for (_, id) in oversized {
+            match (policy.get(id), plan.largest.is_some()) {
+                (Upscale::Skip, _) => plan.skipped.push(id.to_string()),
+                (Upscale::Upscale, _) => plan.upscaled.push(id.to_string()),
+                (_, false) => plan.largest = Some(id.to_string()),
+                (p, true) => plan.links.push((id.to_string(), p)),
+            }
+        }
+
+        plan
+    }
+    /// Upscaled sizes of all plans of a source.
+    pub fn upscaled(plans: &[Self]) -> Vec<String> {
+        plans.iter()
+            .flat_map(|p|p.upscaled.iter().cloned())
+            .collect()
+    }
+    /// Sizes that get resized, copies and aliases are made afterwards from the largest one.
+    pub fn resized<'a>(&self, items: &[(u32, &'a str)]) -> Vec<(u32, &'a str)> {
+        items.iter()
+            .filter(|(_, id)|!self.skipped.iter().any(|s|s.eq(id)))
+            .filter(|(_, id)|!self.links.iter().any(|(l, _)|l.eq(id)))
+            .copied()
+            .collect()
+    }
+}
+
+/// Copy or alias the largest real output to the oversized sizes on every target that accepts them.
+pub async fn link_handler(plan: &UpscalePlan, transformable: &Transformable, targets: &[OutputTarget])
+-> Result<ResizeStats> {
+    let mut stats = ResizeStats::new();
+
+    let largest = match plan.largest {
+        Some(ref l) => l,
+        None => return Ok(stats),
+    };
+
+    let real = transformable.target_file_path(largest);
+    // Links hold the bytes of the largest output
+    let checksum = transformable.output_checksum(largest);
+    let mime = mime(transformable.target_ext)?;
+
+    for (id, policy) in plan.links.iter() {
+        let path = transformable.target_file_path(id);
+        let copy = Upscale::CopyLargest.eq(policy);
+        let mut saved = vec![];
+
+        for t in targets.iter().filter(|t|t.accepts(&transformable.output, id)) {
+            if t.is_duplicate(&path, checksum).await {
+                continue
+            }
+
+            if !t.accepts(&transformable.output, largest) {
+                stats.skipped.push(format!(
+                    "{}: Can't link {} to {} on {}, target doesn't accept it",
+                    path.display(), id, largest, t.name
+                ));
+
+                continue
+            }
+
+            match t.link(&path, &real, mime, checksum, copy).await {
+                Ok(_) => saved.push(t.name.as_str()),
+                Err(e) => stats.failed.push(e.to_string()),
+            }
+        }
+
+        if !saved.is_empty() {
+            let action = match copy {
+                true => "copied",
+                false => "aliased",
+            };
+
+            stats.succeeded.push(format!(
+                "Image {} / {} {} from {} on {}", path.display(), id, action, largest, saved.join(", ")
+            ));
+        }
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZES: [(u32, &str); 3] = [(2500, "og"), (1200, "xl"), (600, "lg")];
+
+    fn policy(toml: &str) -> UpscalePolicy {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn fitting_sizes_are_resized() {
+        let plan = UpscalePlan::for_side(3000, &SIZES, &policy(""));
+
+        assert!(plan.largest.is_none() && plan.links.is_empty() && plan.skipped.is_empty());
+        assert_eq!(plan.resized(&SIZES), SIZES);
+    }
+
+    #[test]
+    fn copy_largest() {
+        let plan = UpscalePlan::for_side(1000, &SIZES, &policy(""));
+
+        // Smallest oversized size is resized to the source size, the others copy it
+        assert_eq!(plan.largest.as_deref(), Some("xl"));
+        assert_eq!(plan.links, vec![("og".to_string(), Upscale::CopyLargest)]);
+        assert_eq!(plan.resized(&SIZES), vec![(1200, "xl"), (600, "lg")]);
+    }
+
+    #[test]
+    fn mixed_policies() {
+        let policy = policy("default = \"alias\"\noriginal = \"skip\"\nlg = \"upscale\"");
+        let sizes = [(2500, "og"), (1200, "xl"), (1000, "md"), (600, "lg")];
+        let plan = UpscalePlan::for_side(500, &sizes, &policy);
+
+        assert_eq!(plan.skipped, vec!["og"]);
+        assert_eq!(plan.upscaled, vec!["lg"]);
+        assert_eq!(plan.largest.as_deref(), Some("md"));
+        assert_eq!(plan.links, vec![("xl".to_string(), Upscale::Alias)]);
+        assert_eq!(plan.resized(&sizes), vec![(1000, "md"), (600, "lg")]);
+    }
+
+    #[test]
+    fn upscaled_of_all_plans() {
+        let policy = policy("default = \"upscale\"");
+        let plans = [UpscalePlan::for_side(1000, &SIZES, &policy), UpscalePlan::for_side(100, &[(300, "md")], &policy)];
+
+        assert_eq!(UpscalePlan::upscaled(&plans), vec!["xl", "og", "md"]);
+    }
+}
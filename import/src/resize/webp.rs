@@ -0,0 +1,42 @@
+use fast_image_resize::images::Image;
+use fast_image_resize::PixelType;
+use webp::{AnimEncoder, AnimFrame, Encoder, WebPConfig};
+use anyhow::{Result, anyhow, bail};
+
+
+/// Frames come with their delay in milliseconds.
+pub async fn webp_writer(frames: Vec<(Image<'static>, u32)>) -> Result<Vec<u8>> {
+    let (width, height) = match frames.first() {
+        Some((f, _)) => (f.width(), f.height()),
+        None => return Err(anyhow!("Animation has no frames")),
+    };
+
+    let config = WebPConfig::new()
+        .map_err(|_| anyhow!("Failed to init WebP encoder config"))?;
+
+    let mut encoder = AnimEncoder::new(width, height, &config);
+    encoder.set_loop_count(0);
+
+    // WebP frames are placed on a timeline instead of having a delay
+    let mut timestamp = 0;
+
+    for (image, delay) in frames.iter() {
+        encoder.add_frame(AnimFrame::from_rgba(image.buffer(), width, height, timestamp));
+        timestamp += *delay as i32;
+    }
+
+    encoder.try_encode()
+        .map(|m| m.to_vec())
+        .map_err(|e| anyhow!("Failed to create animated WebP image: {:?}", e))
+}
+
+/// Lossy still WebP with quality from 0 to 100.
+pub async fn webp_still_writer(image: Image<'static>, quality: u8) -> Result<Vec<u8>> {
+    let encoder = match image.pixel_type() {
+        PixelType::U8x3 => Encoder::from_rgb(image.buffer(), image.width(), image.height()),
+        PixelType::U8x4 => Encoder::from_rgba(image.buffer(), image.width(), image.height()),
+        p => bail!("Unsupported WebP pixel type: {:?}", p),
+    };
+
+    Ok(encoder.encode(quality as f32).to_vec())
+}
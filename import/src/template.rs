@@ -0,0 +1,166 @@
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Result};
+use config::Config;
+
+
+pub const DEFAULT_TEMPLATE: &str = "{prefix}/{root}/{rel_dir}/{stem}/{variant}/{size}.{ext}";
+
+/// Path component replaced with the prefix of each export target.
+pub const PREFIX: &str = "{prefix}";
+
+/// Parts of a source image path the output paths are built from.
+#[derive(Clone, Debug)]
+pub struct OutputName {
+    pub root: String,
+    pub rel_dir: PathBuf,
+    pub stem: String,
+    pub source_ext: String,
+    pub variant: Option<String>,
+}
+
+impl OutputName {
+    pub fn new(root: &Path, file: &Path) -> Result<Self> {
+        let root_name = match root.file_name().and_then(|f|f.to_str()) {
+            Some(s) => s.to_string(),
+            None => bail!("Failed to extract root dir name from {:?}", root),
+        };
+
+        // Subpath of the file without the filename
+        let rel_dir = match file.strip_prefix(root) {
+            Ok(t) => t.parent().map(|p|p.to_owned()).unwrap_or_default(),
+            Err(e) => bail!("Failed to build target dir path: {}", e),
+        };
+
+        // Target name is the filename without extension
+        let stem = match file.file_stem().and_then(|f|f.to_str()) {
+            Some(s) => s.to_string(),
+            None => bail!("Failed to extract filename from source image path {:?}", file),
+        };
+
+        let source_ext = file.extension()
+            .map(|e|e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        Ok(Self { root: root_name, rel_dir, stem, source_ext, variant: None })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PathTemplate(String);
+
+impl PathTemplate {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let template = match config.export {
+            Some(ref e) => e.path_template.to_owned(),
+            None => None,
+        };
+
+        let template = template.unwrap_or_else(|| DEFAULT_TEMPLATE.to_string());
+
+        // Without these every size and variant of an image would end up in the same file
+        for p in ["{size}", "{variant}"] {
+            if !template.contains(p) {
+                bail!("Path template {:?} is missing {}", template, p);
+            }
+        }
+
+        // Targets put their prefix in place of the first component, their output dirs are
+        // skipped when looking for sources
+        if template.split('/').next() != Some(PREFIX) || template.matches(PREFIX).count() > 1 {
+            bail!("Path template {:?} has to start with {}/ and can't use it anywhere else", template, PREFIX);
+        }
+
+        Ok(Self(template))
+    }
+    /// Relative output path, `{prefix}` is left for the export targets to fill in.
+    pub fn render(&self, name: &OutputName, size: &str, ext: &str) -> PathBuf {
+        let rel_dir = name.rel_dir.iter()
+            .map(|c|c.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let rendered = self.0
+            .replace("{root}", &name.root)
+            .replace("{rel_dir}", &rel_dir)
+            .replace("{stem}", &name.stem)
+            .replace("{source_ext}", &name.source_ext)
+            .replace("{variant}", name.variant.as_deref().unwrap_or_default())
+            .replace("{size}", size)
+            .replace("{ext}", ext);
+
+        // Empty placeholders leave empty path components behind
+        rendered.split('/')
+            .filter(|c|!c.is_empty())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(t: Option<&str>) -> Result<PathTemplate> {
+        let toml = match t {
+            Some(t) => format!("[export]\npath_template = {:?}", t),
+            None => String::new(),
+        };
+
+        PathTemplate::from_config(&toml::from_str(&toml).unwrap())
+    }
+
+    fn name(file: &str) -> OutputName {
+        OutputName::new(Path::new("/photos"), Path::new(file)).unwrap()
+    }
+
+    #[test]
+    fn default_template() {
+        let t = template(None).unwrap();
+        let mut n = name("/photos/2024/trip/Beach.JPG");
+
+        assert_eq!(n.root, "photos");
+        assert_eq!(n.source_ext, "jpg");
+        assert_eq!(t.render(&n, "md", "webp"), Path::new("{prefix}/photos/2024/trip/Beach/md.webp"));
+
+        n.variant = Some("gray".into());
+        assert_eq!(t.render(&n, "md", "webp"), Path::new("{prefix}/photos/2024/trip/Beach/gray/md.webp"));
+    }
+
+    #[test]
+    fn empty_placeholders_are_dropped() {
+        let t = template(None).unwrap();
+
+        assert_eq!(t.render(&name("/photos/a.png"), "xl", "png"), Path::new("{prefix}/photos/a/xl.png"));
+    }
+
+    #[test]
+    fn custom_template() {
+        let t = template(Some("{prefix}/{rel_dir}/{stem}-{source_ext}/{variant}/{size}.{ext}")).unwrap();
+
+        assert_eq!(t.render(&name("/photos/x/a.png"), "sm", "jpg"), Path::new("{prefix}/x/a-png/sm.jpg"));
+    }
+
+    #[test]
+    fn rejected_templates() {
+        assert!(template(Some("{prefix}/{stem}/{size}.{ext}")).is_err());
+        assert!(template(Some("{prefix}/{stem}/{variant}.{ext}")).is_err());
+        assert!(template(Some("{stem}/{variant}/{size}.{ext}")).is_err());
+        assert!(template(Some("out/{prefix}/{stem}/{variant}/{size}.{ext}")).is_err());
+        assert!(template(Some("{prefix}/{prefix}/{stem}/{variant}/{size}.{ext}")).is_err());
+    }
+
+    #[test]
+    fn stem_collision() {
+        let (jpg, png) = (name("/photos/a/pic.jpg"), name("/photos/a/pic.png"));
+
+        // Sources only differing by extension share their outputs by default
+        let t = template(None).unwrap();
+        assert_eq!(t.render(&jpg, "{size}", "{ext}"), t.render(&png, "{size}", "{ext}"));
+
+        let t = template(Some("{prefix}/{stem}.{source_ext}/{variant}/{size}.{ext}")).unwrap();
+        assert_ne!(t.render(&jpg, "{size}", "{ext}"), t.render(&png, "{size}", "{ext}"));
+
+        // Without rel_dir files in different dirs collide as well
+        let t = template(Some("{prefix}/{stem}/{variant}/{size}.{ext}")).unwrap();
+        assert_eq!(t.render(&jpg, "md", "png"), t.render(&name("/photos/b/pic.jpg"), "md", "png"));
+    }
+}
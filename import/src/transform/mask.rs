@@ -0,0 +1,467 @@
+use anyhow::{anyhow, Result};
+use config::Decoration;
+use image::imageops::FilterType;
+use image::{imageops, DynamicImage, GrayImage, Luma, RgbaImage};
+use resvg::tiny_skia::Pixmap;
+
+use super::{ScaleRef, Transformable, TRANSPARENT};
+
+
+/// Coverage of a shape over a square, 255 fully inside and 0 outside. Edge pixels get the
+/// part of them the shape covers so the cut has smooth edges at every size.
+/// Decorated shapes are drawn on a canvas grown by `margin` on every side.
+pub struct Mask {
+    /// Coverage of the image
+    picture: GrayImage,
+    /// Solid colored parts next to the image, they never overlap it or each other
+    fills: Vec<(GrayImage, [u8; 3])>,
+    /// Drawn under everything else, opacity included
+    shadow: Option<(GrayImage, [u8; 3])>,
+    margin: u32,
+}
+
+impl Mask {
+    /// Coverage is read from the alpha of the anti-aliased drawing.
+    pub fn from_pixmap(pixmap: &Pixmap) -> Result<Self> {
+        let coverage = pixmap.pixels()
+            .iter()
+            .map(|p| p.alpha())
+            .collect();
+
+        let picture = GrayImage::from_raw(pixmap.width(), pixmap.height(), coverage)
+            .ok_or_else(|| anyhow!("Mask doesn't match its dimensions"))?;
+
+        Ok(Self { picture, fills: vec![], shadow: None, margin: 0 })
+    }
+    /// Add the stroke, ring and shadow, lengths of the decoration are fractions of the square.
+    pub fn decorate(self, decoration: &Decoration) -> Self {
+        let side = self.picture.width();
+        let px = |v: f32| v * side as f32;
+
+        let stroke = decoration.stroke.as_ref().map(|s| (px(s.width), s.color.0));
+        let padding = px(decoration.padding);
+        let (offset, blur) = match decoration.shadow {
+            Some(ref s) => ([px(s.offset[0]).round() as i64, px(s.offset[1]).round() as i64], px(s.blur)),
+            None => ([0, 0], 0.),
+        };
+
+        // Room for everything drawn outside of the shape
+        let reach = stroke.map(|s| s.0).unwrap_or(0.) + offset[0].abs().max(offset[1].abs()) as f32 + 3. * blur;
+        let margin = match reach > 0. {
+            true => reach.ceil() as u32 + 1,
+            false => 0,
+        };
+
+        let canvas = side + 2 * margin;
+        let mut shape = GrayImage::new(canvas, canvas);
+        imageops::replace(&mut shape, &self.picture, margin as i64, margin as i64);
+
+        // Signed distance to the shape edge in pixels, negative inside
+        let inside = shape.pixels().map(|p| p.0[0] > 127).collect::<Vec<_>>();
+        let outside = inside.iter().map(|i| !i).collect::<Vec<_>>();
+        let to_inside = distance(&inside, canvas, canvas);
+        let to_outside = distance(&outside, canvas, canvas);
+        let sd = inside.iter()
+            .zip(to_inside.iter().zip(to_outside.iter()))
+            .map(|(i, (d_in, d_out))| match i {
+                true => 0.5 - d_out,
+                false => d_in - 0.5,
+            })
+            .collect::<Vec<_>>();
+
+        // Coverage of everything closer than `t` to the shape edge
+        let within = |t: f32| GrayImage::from_fn(canvas, canvas, |x, y| {
+            let v = (t - sd[(y * canvas + x) as usize] + 0.5).clamp(0., 1.);
+            Luma([(v * 255.).round() as u8])
+        });
+
+        let picture = match padding > 0. {
+            true => min(&within(-padding), &shape),
+            false => shape.clone(),
+        };
+
+        let mut fills = vec![];
+
+        if let Some(color) = decoration.ring {
+            fills.push((sub(&shape, &picture), color.0));
+        }
+
+        let footprint = match stroke {
+            Some((width, color)) => {
+                let dilated = max(&within(width), &shape);
+                fills.push((sub(&dilated, &shape), color));
+                dilated
+            },
+            None => shape,
+        };
+
+        let shadow = decoration.shadow.as_ref().map(|s| {
+            let mut moved = GrayImage::new(canvas, canvas);
+            imageops::replace(&mut moved, &footprint, offset[0], offset[1]);
+
+            let mut blurred = match blur > 0. {
+                true => soft(&moved, blur),
+                false => moved,
+            };
+
+            let opacity = s.opacity.clamp(0., 1.);
+            blurred.pixels_mut().for_each(|p| p.0[0] = (p.0[0] as f32 * opacity).round() as u8);
+
+            (blurred, s.color.0)
+        });
+
+        Self { picture, fills, shadow, margin }
+    }
+    /// Multiply the coverage into the alpha of a square image and draw the decoration around it.
+    pub fn apply(&self, square: &RgbaImage) -> RgbaImage {
+        let mut img = RgbaImage::from_pixel(self.picture.width(), self.picture.height(), TRANSPARENT);
+        imageops::replace(&mut img, square, self.margin as i64, self.margin as i64);
+
+        for (x, y, p) in img.enumerate_pixels_mut() {
+            let a = p.0[3] as f32 / 255. * self.picture.get_pixel(x, y).0[0] as f32 / 255.;
+            let mut color = [0, 1, 2].map(|i| p.0[i] as f32 * a);
+            let mut alpha = a;
+
+            // Fills are next to the image so their coverage adds up
+            for (cov, c) in self.fills.iter() {
+                let a = cov.get_pixel(x, y).0[0] as f32 / 255.;
+                color = [0, 1, 2].map(|i| color[i] + c[i] as f32 * a);
+                alpha += a;
+            }
+
+            if let Some((cov, c)) = self.shadow.as_ref() {
+                let a = cov.get_pixel(x, y).0[0] as f32 / 255. * (1. - alpha);
+                color = [0, 1, 2].map(|i| color[i] + c[i] as f32 * a);
+                alpha += a;
+            }
+
+            // Color of pixels left out is meaningless
+            *p = match alpha > 0. {
+                true => {
+                    let alpha = alpha.min(1.);
+                    let [r, g, b] = color.map(|c| (c / alpha).round().clamp(0., 255.) as u8);
+                    image::Rgba([r, g, b, (alpha * 255.).round() as u8])
+                },
+                false => TRANSPARENT,
+            };
+        }
+
+        img
+    }
+    /// Cut the shape from a square image of the undecorated mask size, transformable is
+    /// updated when the canvas or trimming changes the dimensions.
+    pub fn cut(&self, square: &RgbaImage, trim: bool, transformable: &Transformable) -> (DynamicImage, Transformable) {
+        let img = DynamicImage::ImageRgba8(self.apply(square));
+
+        let (x, y, width, height) = match (trim, self.margin) {
+            (true, _) => self.bounds(),
+            (false, 0) => return (img, transformable.clone()),
+            (false, _) => (0, 0, img.width(), img.height()),
+        };
+
+        let mut t = transformable.clone();
+        t.set_dimensions(width, height);
+
+        // Long side of the trimmed shape is scaled to the transform size
+        t.scale = match (trim, width >= height) {
+            (false, _) => ScaleRef::Fixed(width, height),
+            (true, true) => ScaleRef::Width(width),
+            (true, false) => ScaleRef::Height(height),
+        };
+
+        (img.crop_imm(x, y, width, height), t)
+    }
+    /// Smallest area holding any coverage, the full canvas for an empty mask.
+    fn bounds(&self) -> (u32, u32, u32, u32) {
+        let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+
+        for (x, y, p) in self.picture.enumerate_pixels() {
+            let covered = p.0[0] > 0
+                || self.fills.iter().any(|(f, _)| f.get_pixel(x, y).0[0] > 0)
+                || self.shadow.as_ref().is_some_and(|(s, _)| s.get_pixel(x, y).0[0] > 0);
+
+            if covered {
+                left = left.min(x);
+                top = top.min(y);
+                right = right.max(x);
+                bottom = bottom.max(y);
+            }
+        }
+
+        match left <= right && top <= bottom {
+            true => (left, top, right - left + 1, bottom - top + 1),
+            false => (0, 0, self.picture.width(), self.picture.height()),
+        }
+    }
+}
+
+/// Soft shadows don't need the full resolution, a smaller copy is blurred and scaled back.
+fn soft(img: &GrayImage, sigma: f32) -> GrayImage {
+    let k = (sigma / 4.).max(1.);
+    let (w, h) = (img.width(), img.height());
+    let small = imageops::resize(
+        img,
+        ((w as f32 / k).round() as u32).max(1),
+        ((h as f32 / k).round() as u32).max(1),
+        FilterType::Triangle,
+    );
+
+    imageops::resize(&gaussian(&small, sigma / k), w, h, FilterType::Triangle)
+}
+
+/// Separable gaussian blur, pixels past the edges are empty.
+fn gaussian(img: &GrayImage, sigma: f32) -> GrayImage {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    let radius = (3. * sigma).ceil() as isize;
+    let kernel = (-radius..=radius)
+        .map(|i| (-(i * i) as f32 / (2. * sigma * sigma)).exp())
+        .collect::<Vec<_>>();
+    let sum = kernel.iter().sum::<f32>();
+    let kernel = kernel.iter().map(|v| v / sum).collect::<Vec<_>>();
+
+    let pass = |src: &[f32], step: usize, len: usize, lines: usize, stride: usize| {
+        let mut out = vec![0.; src.len()];
+
+        for l in 0..lines {
+            for i in 0..len {
+                let mut v = 0.;
+
+                for (j, k) in kernel.iter().enumerate() {
+                    let at = i as isize + j as isize - radius;
+
+                    if at >= 0 && (at as usize) < len {
+                        v += src[l * stride + at as usize * step] * k;
+                    }
+                }
+
+                out[l * stride + i * step] = v;
+            }
+        }
+
+        out
+    };
+
+    let data = img.as_raw().iter().map(|v| *v as f32).collect::<Vec<_>>();
+    let rows = pass(&data, 1, w, h, w);
+    let blurred = pass(&rows, w, h, w, 1);
+
+    GrayImage::from_raw(img.width(), img.height(), blurred.iter().map(|v| v.round() as u8).collect())
+        .unwrap_or_else(|| img.clone())
+}
+
+fn min(a: &GrayImage, b: &GrayImage) -> GrayImage {
+    GrayImage::from_fn(a.width(), a.height(), |x, y| Luma([a.get_pixel(x, y).0[0].min(b.get_pixel(x, y).0[0])]))
+}
+
+fn max(a: &GrayImage, b: &GrayImage) -> GrayImage {
+    GrayImage::from_fn(a.width(), a.height(), |x, y| Luma([a.get_pixel(x, y).0[0].max(b.get_pixel(x, y).0[0])]))
+}
+
+fn sub(a: &GrayImage, b: &GrayImage) -> GrayImage {
+    GrayImage::from_fn(a.width(), a.height(), |x, y| Luma([a.get_pixel(x, y).0[0].saturating_sub(b.get_pixel(x, y).0[0])]))
+}
+
+/// Euclidean distance from every pixel to the nearest set one, in two separable passes
+/// (Felzenszwalb and Huttenlocher). Without any set pixel every distance is infinite.
+fn distance(set: &[bool], w: u32, h: u32) -> Vec<f32> {
+    let (w, h) = (w as usize, h as usize);
+    let mut d = set.iter()
+        .map(|s| match s {
+            true => 0.,
+            false => f32::INFINITY,
+        })
+        .collect::<Vec<f32>>();
+
+    let mut line = vec![0.; w.max(h)];
+
+    for x in 0..w {
+        (0..h).for_each(|y| line[y] = d[y * w + x]);
+        let column = squared_1d(&line[..h]);
+        (0..h).for_each(|y| d[y * w + x] = column[y]);
+    }
+
+    for y in 0..h {
+        let row = squared_1d(&d[y * w..(y + 1) * w]);
+        d[y * w..(y + 1) * w].copy_from_slice(&row);
+    }
+
+    d.iter().map(|v| v.sqrt()).collect()
+}
+
+/// Squared distance transform of a sampled function, lower envelope of parabolas.
+fn squared_1d(f: &[f32]) -> Vec<f32> {
+    let n = f.len();
+    let mut d = vec![f32::INFINITY; n];
+    let mut v = vec![0usize; n];
+    let mut z = vec![0f32; n + 1];
+    let mut k = 0;
+
+    // Parabolas rooted at infinity never make the envelope
+    let first = match f.iter().position(|v| v.is_finite()) {
+        Some(i) => i,
+        None => return d,
+    };
+
+    v[0] = first;
+    z[0] = f32::NEG_INFINITY;
+    z[1] = f32::INFINITY;
+
+    for q in first + 1..n {
+        if !f[q].is_finite() {
+            continue
+        }
+
+        // z[0] is minus infinity so the first parabola is never dropped
+        let mut s;
+
+        loop {
+            let p = v[k];
+            s = ((f[q] + (q * q) as f32) - (f[p] + (p * p) as f32)) / (2. * (q as f32 - p as f32));
+
+            match s <= z[k] {
+                true => k -= 1,
+                false => break,
+            }
+        }
+
+        k += 1;
+        v[k] = q;
+        z[k] = s;
+        z[k + 1] = f32::INFINITY;
+    }
+
+    let mut k = 0;
+
+    for (q, d) in d.iter_mut().enumerate() {
+        while z[k + 1] < q as f32 {
+            k += 1;
+        }
+
+        let p = v[k];
+        let dq = q as f32 - p as f32;
+        *d = dq * dq + f[p];
+    }
+
+    d
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::Shape;
+
+    fn mask(shape: &str, size: u32, decoration: Option<&str>) -> Mask {
+        let mut shape = Shape::preset(shape).unwrap();
+        shape.decoration = decoration.map(|d| toml::from_str(d).unwrap());
+
+        shape.mask(size).unwrap()
+    }
+
+    fn area(img: &GrayImage) -> f32 {
+        img.pixels().map(|p| p.0[0] as f32 / 255.).sum()
+    }
+
+    fn close(a: f32, b: f32, tolerance: f32) -> bool {
+        (a - b).abs() <= b * tolerance
+    }
+
+    #[test]
+    fn distance_matches_brute_force() {
+        let (w, h) = (9, 7);
+        let set = (0..w * h).map(|i| [3, 17, 40, 58].contains(&i)).collect::<Vec<_>>();
+        let d = distance(&set, w, h);
+
+        for (i, d) in d.iter().enumerate() {
+            let (x, y) = ((i as u32 % w) as f32, (i as u32 / w) as f32);
+            let nearest = set.iter()
+                .enumerate()
+                .filter(|(_, s)| **s)
+                .map(|(j, _)| ((j as u32 % w) as f32 - x).hypot((j as u32 / w) as f32 - y))
+                .fold(f32::INFINITY, f32::min);
+
+            assert!((d - nearest).abs() < 1e-4, "pixel {}: {} != {}", i, d, nearest);
+        }
+    }
+
+    #[test]
+    fn distance_without_set_pixels() {
+        assert!(distance(&[false; 12], 4, 3).iter().all(|d| d.is_infinite()));
+        assert_eq!(squared_1d(&[f32::INFINITY, 0., f32::INFINITY, f32::INFINITY]), vec![1., 0., 1., 4.]);
+    }
+
+    #[test]
+    fn round_coverage() {
+        let m = mask("round", 100, None);
+
+        assert_eq!(m.margin, 0);
+        assert_eq!(m.picture.dimensions(), (100, 100));
+        assert_eq!(m.picture.get_pixel(50, 50).0[0], 255);
+        assert_eq!(m.picture.get_pixel(0, 0).0[0], 0);
+        assert!(close(area(&m.picture), std::f32::consts::PI * 2500., 0.01));
+
+        // Edges are anti-aliased
+        assert!(m.picture.pixels().any(|p| p.0[0] > 0 && p.0[0] < 255));
+    }
+
+    #[test]
+    fn stroke_surrounds_shape() {
+        let m = mask("round", 100, Some("stroke = {width = 0.05, color = \"#ff0000\"}"));
+
+        assert_eq!(m.margin, 6);
+        assert_eq!(m.picture.dimensions(), (112, 112));
+        assert_eq!(m.fills.len(), 1);
+
+        let (stroke, color) = &m.fills[0];
+        assert_eq!(*color, [255, 0, 0]);
+        // Outer edge of the stroke is exact to half a pixel
+        let ring = |r: f32| std::f32::consts::PI * (r * r - 50. * 50.);
+        assert!((ring(54.5)..ring(55.5)).contains(&area(stroke)), "{}", area(stroke));
+
+        // Stroke and picture never cover the same pixel together more than fully
+        for (s, p) in stroke.pixels().zip(m.picture.pixels()) {
+            assert!(s.0[0] as u32 + p.0[0] as u32 <= 256);
+        }
+    }
+
+    #[test]
+    fn padding_leaves_a_ring() {
+        let m = mask("round", 100, Some("padding = 0.1\nring = \"#00ff00\""));
+        let pi = std::f32::consts::PI;
+
+        assert_eq!(m.margin, 0);
+        assert!(close(area(&m.picture), pi * 1600., 0.03));
+        assert!(close(area(&m.fills[0].0), pi * (2500. - 1600.), 0.05));
+    }
+
+    #[test]
+    fn shadow_moves_with_offset() {
+        let m = mask("sq45", 100, Some("shadow = {offset = [0.1, 0.0], opacity = 1.0}"));
+        let (shadow, _) = m.shadow.as_ref().unwrap();
+
+        let center = |img: &GrayImage| {
+            let sum = img.enumerate_pixels().map(|(x, _, p)| x as f32 * p.0[0] as f32).sum::<f32>();
+            sum / img.pixels().map(|p| p.0[0] as f32).sum::<f32>()
+        };
+
+        assert!((center(shadow) - center(&m.picture) - 10.).abs() < 0.5);
+        assert!(close(area(shadow), area(&m.picture), 0.01));
+    }
+
+    #[test]
+    fn apply_multiplies_alpha() {
+        let m = mask("round", 40, None);
+        let out = m.apply(&RgbaImage::from_pixel(40, 40, image::Rgba([200, 10, 10, 255])));
+
+        assert_eq!(out.get_pixel(20, 20).0, [200, 10, 10, 255]);
+        assert_eq!(out.get_pixel(0, 0).0, TRANSPARENT.0);
+    }
+
+    #[test]
+    fn bounds_of_triangle() {
+        // Points right, corners at x 0.25 and 1.0, y from 0.067 to 0.933
+        let (x, y, w, h) = mask("right", 100, None).bounds();
+
+        assert!((24..=25).contains(&x) && (75..=76).contains(&w), "{} {}", x, w);
+        assert!((6..=7).contains(&y) && (86..=88).contains(&h), "{} {}", y, h);
+    }
+}
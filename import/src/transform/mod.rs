@@ -1,27 +1,28 @@
-mod polygons;
-mod triangles;
-mod round;
-mod rectangle;
+mod mask;
+mod shape;
 
-use std::{f32::consts::PI, num::NonZeroU32, path::PathBuf};
-use image::{io::Reader as ImageReader, DynamicImage};
+use std::{fmt, num::NonZeroU32, ops::Deref, path::PathBuf, sync::Arc};
+use config::{AnimationMode, Color, ColorSpace, Effect, Filter, FilterPolicy, SharpenPolicy, Variant};
+use image::{io::Limits, DynamicImage, Frame};
 
-use adler::adler32_slice;
+use adler::{adler32_slice, Adler32};
 use anyhow::{anyhow, bail, Result};
-use config::ObjectStore;
 use fast_image_resize::PixelType;
-use image::{ImageBuffer, ImageFormat, Rgba};
-use imageproc::point::Point;
-use tracing::{debug, error, info, warn};
+use image::Rgba;
+use tracing::debug;
 
-pub use triangles::*;
-pub use round::*;
-pub use polygons::*;
-pub use rectangle::*;
+use crate::decode::{convert_rgba8, decode_frames, decode_source, output_icc};
+use crate::export::OutputTarget;
+use crate::template::{OutputName, PathTemplate};
+
+pub use mask::*;
+pub use shape::*;
 
 
 pub const TRANSPARENT: Rgba<u8> = image::Rgba::<u8>([0, 0, 0, 0]);
-pub const WHITE: Rgba<u8> = image::Rgba::<u8>([255, 255, 255, 100]);
+
+/// Variant dir of the shapes cut from an image, their variants go below it.
+pub const SHAPES_VARIANT: &str = "shapes";
 
 #[derive(Clone, Debug)]
 pub enum ScaleRef {
@@ -30,17 +31,67 @@ pub enum ScaleRef {
     Fixed(u32, u32)
 }
 
+#[derive(Clone, Debug)]
+pub enum OutputKind {
+    Resized,
+    /// Resized image with the effects of the named variant
+    Variant(String),
+    Shape,
+    ShapeVariant(String),
+}
+
+/// Frames of an animated source, shared between the outputs made from it. Posters and
+/// skipped sources don't keep every frame, the count is that of the source.
+#[derive(Clone)]
+pub struct Frames(Arc<Vec<Frame>>, usize);
+
+impl Frames {
+    pub fn count(&self) -> usize {
+        self.1
+    }
+}
+
+impl From<Vec<Frame>> for Frames {
+    fn from(frames: Vec<Frame>) -> Self {
+        let count = frames.len();
+        Self(Arc::new(frames), count)
+    }
+}
+
+impl Deref for Frames {
+    type Target = Vec<Frame>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Frames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Frames({}/{})", self.0.len(), self.1)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Transformable {
     pub width: NonZeroU32,
     pub height: NonZeroU32,
     pub scale: ScaleRef,
     pub source_path: PathBuf,
-    pub target_path: PathBuf,
-    pub target_name: String,
+    pub name: OutputName,
+    pub template: PathTemplate,
     pub target_ext: &'static str,
     pub pixel: PixelType,
     pub checksum: u32,
+    pub output: OutputKind,
+    pub frames: Option<Frames>,
+    pub upscale: Vec<String>,
+    /// Profile of the output color space, none for sRGB
+    pub icc: Option<Arc<Vec<u8>>>,
+    pub linear: bool,
+    pub filter: FilterPolicy,
+    pub sharpen: SharpenPolicy,
+    pub effects: Vec<Effect>,
 }
 
 impl Transformable {
@@ -82,142 +133,134 @@ impl Transformable {
             }
         }
     }
-    pub async fn get_resizables(&self, items: &[(u32, &str)], s3: &Option<ObjectStore>, fs: &Option<PathBuf>)
-    -> Vec<(u32, String, PathBuf)> {
+    /// Same as `convert_ratio` but sizes allowed to upscale aren't limited to the source size.
+    pub fn target_dimensions(&self, r: u32, id: &str) -> (u32, u32) {
+        if !self.upscale.iter().any(|u|u.eq(id)) {
+            return self.convert_ratio(r)
+        }
+
+        match self.scale {
+            ScaleRef::Width(u) => {
+                let h = r as u64 * self.height.get() as u64 / u as u64;
+                (r, h as u32)
+            },
+            ScaleRef::Height(u) => {
+                let w = r as u64 * self.width.get() as u64 / u as u64;
+                (w as u32, r)
+            },
+            ScaleRef::Fixed(_, _) => self.convert_ratio(r),
+        }
+    }
+    pub async fn get_resizables(&self, items: &[(u32, &str)], targets: &[OutputTarget])
+    -> Vec<(u32, String, PathBuf, Vec<OutputTarget>)> {
         let mut resizables = vec![];
 
         for (ratio, id) in items {
-            if !self.skip_duplicate(*id, s3, fs).await {
-                resizables.push((*ratio, id.to_string(), self.target_file_path(id)));
+            let path = self.target_file_path(id);
+            let mut exportable = vec![];
+
+            // Only targets whose filter accepts this output and don't already have it
+            for t in targets.iter().filter(|t|t.accepts(&self.output, id)) {
+                match t.is_duplicate(&path, self.output_checksum(id)).await {
+                    true => debug!("{}: {} already exported to {}", self.name.stem, id, t.name),
+                    false => exportable.push(t.clone()),
+                }
+            }
+
+            if !exportable.is_empty() {
+                resizables.push((*ratio, id.to_string(), path, exportable));
             }
         }
 
         resizables
     }
-    async fn skip_duplicate(&self, id: &str, s3: &Option<ObjectStore>, fs: &Option<PathBuf>) -> bool {
-        let file_path = self.target_file_path(id);
-
-        // See if the object already exists on S3
-        let s3_is_duplicate = match s3 {
-            Some(s) => {
-                let path = file_path.to_string_lossy();
-        
-                match s.get_tags(path).await {
-                    Ok(v) => match v.is_empty() {
-                        true => {
-                            debug!("Object {:?} does not exist / doesn't have tags", id);
-                            false
-                        },
-                        false => match v.into_iter().find(|t|t.key().eq("checksum")) {
-                            Some(t) => match t.value().eq(&self.checksum.to_string()) {
-                                true => {
-                                    debug!("Object {:?} is same as the provided item, skipping...", id);
-                                    true
-                                },
-                                false => {
-                                    info!("Object {:?} has changed, overwrite...", id);
-                                    false
-                                }
-                            },
-                            None => {
-                                warn!("Object {:?} is missing checksum tag", id);
-                                false
-                            },
-                        }
-                    },
-                    Err(e) => {
-                        error!("Duplicate check failed: {}, weird.", e);
-                        false
-                    }
-                }
+    /// Copy for the outputs of a variant, shapes go under `shapes/<variant>`.
+    pub fn variant(&self, variant: &Variant) -> Self {
+        let mut t = self.clone();
+        t.effects = variant.filters.clone();
+
+        match t.output {
+            OutputKind::Shape => {
+                t.name.variant = Some(format!("{}/{}", SHAPES_VARIANT, variant.name));
+                t.output = OutputKind::ShapeVariant(variant.name.clone());
+            },
+            _ => {
+                t.name.variant = Some(variant.name.clone());
+                t.output = OutputKind::Variant(variant.name.clone());
             },
-            None => false,
-        };
-        
-        // Bail as duplicate if filesystem exporting is disabled
-        if s3_is_duplicate && fs.is_none() {
-            return s3_is_duplicate
         }
 
-        // See if the object already exists on filesystem
-        if fs.is_some() {
-            // Check if the file exists on filesystem
-            if file_path.is_file() {
-                // Checksum file is next to the image
-                let checksum_file = self.checksum_file_path(id);
-
-                // Can't compare image without checksum file, overwrite
-                if !checksum_file.is_file() {
-                    return false
-                }
-
-                // Read checksum from file
-                match std::fs::read_to_string(checksum_file) {
-                    Ok(c) => match c.trim().parse::<u32>() {
-                        Ok(v) => match v.eq(&self.checksum) {
-                            true => {
-                                debug!("File {:?} already exists on filesystem", id);
-                                return true
-                            },
-                            false => {
-                                info!("File {:?} has changed, overwrite...", id);
-                                return false
-                            }
-                        },
-                        Err(e) => {
-                            error!("Failed to parse checksum from file: {:?}", e);
-                            return false
-                        }
-                    },
-                    Err(e) => {
-                        error!("Failed to read checksum file: {:?}", e);
-                        return false
-                    }
-                }
-            }
-        }        
-
-        false
+        t
     }
-    fn target_file_path(&self, id: &str) -> PathBuf {
-        let mut path = self.target_path.to_owned();
-        let ext = self.target_ext;
-
-        let filename = format!("{}.{}", id, ext);
-        path.push(filename);
+    /// Source checksum mixed with the settings an output of the size is made with, so that
+    /// changing the effects, filter or sharpening makes it again.
+    pub fn output_checksum(&self, id: &str) -> u32 {
+        let (filter, sharpen) = (self.filter.get(id), self.sharpen.get(id));
+
+        // Outputs of the default settings keep the checksum they always had
+        if self.effects.is_empty() && filter == Filter::default() && sharpen.is_none() {
+            return self.checksum
+        }
 
-        path
+        let mut hasher = Adler32::from_checksum(self.checksum);
+        hasher.write_slice(format!("{:?}{:?}{:?}", self.effects, filter, sharpen).as_bytes());
+        hasher.checksum()
     }
-    fn checksum_file_path(&self, id: &str) -> PathBuf {
-        let mut path = self.target_path.to_owned();
-        let filename = format!(".{}.checksum", id);
-        
-        path.push(filename);
+    pub fn target_file_path(&self, id: &str) -> PathBuf {
+        self.template.render(&self.name, id, self.target_ext)
+    }
+    /// Replace an animated source with one of its frames, index past the end picks the last one.
+    /// Frames are sRGB, the poster is converted into the output space like any still image.
+    pub fn poster(&mut self, index: usize, space: ColorSpace) -> Result<DynamicImage> {
+        let frame = match self.frames.take() {
+            Some(f) => match f.get(index).or(f.last()) {
+                Some(frame) => frame.buffer().clone(),
+                None => bail!("{:?}: Animation has no frames", self.source_path),
+            },
+            None => bail!("{:?}: Image is not animated", self.source_path),
+        };
+
+        // Outputs are made from this 8 bit frame only
+        self.checksum = adler32_slice(frame.as_raw());
+        self.pixel = PixelType::U8x4;
 
-        path
+        Ok(DynamicImage::ImageRgba8(convert_rgba8(frame, None, space)?))
     }
 }
 
-pub async fn transformable_img(importable: PathBuf, mut target_path: PathBuf) -> Result<(Transformable, DynamicImage)> {
+pub async fn transformable_img(importable: PathBuf, name: OutputName, template: PathTemplate, limits: Limits, color: Color,
+animation: AnimationMode, poster: usize) -> Result<(Transformable, DynamicImage)> {
     if !importable.is_file() {
         bail!("Stupid developer issue, image resizer fed with a non file: {:?}", &importable)
     }
 
-    let name: String = importable.to_string_lossy().into();
+    let source: String = importable.to_string_lossy().into();
     
     tokio::task::spawn_blocking(move || {
-        // Read source image from file
-        let reader = ImageReader::open(&importable)?.with_guessed_format()?;
-        let format = match reader.format() {
-            Some(f) => f,
-            None => bail!("Unable to detect image format from file."),
+        // Read source image from file, camera formats go through their own decoders
+        let (img, format, source_icc) = decode_source(&importable, &limits, color.space)?;
+
+        // Frames are converted into sRGB, animated outputs don't carry a profile
+        let frames = match decode_frames(&importable, format, &limits, animation, poster)? {
+            Some((count, f)) => Some(Frames(Arc::new(f.into_iter()
+                .map(|f| {
+                    let (left, top, delay) = (f.left(), f.top(), f.delay());
+                    let buffer = convert_rgba8(f.into_buffer(), source_icc.as_deref(), ColorSpace::Srgb)?;
+                    Ok(Frame::from_parts(buffer, left, top, delay))
+                })
+                .collect::<Result<Vec<_>>>()?), count)),
+            None => None,
         };
-
-        // Image is valid image
-        let img = reader.decode()?;
     
-        // Calculate checksum from image bytes
-        let checksum = adler32_slice(img.as_bytes());
+        // Calculate checksum from image bytes, animations from every frame
+        let checksum = match frames {
+            Some(ref f) => {
+                let mut hasher = Adler32::new();
+                f.iter().for_each(|f|hasher.write_slice(f.buffer().as_raw()));
+                hasher.checksum()
+            },
+            None => adler32_slice(img.as_bytes()),
+        };
     
         // Read image width and height into non-zero enum to avoid problems later on
         let width = match NonZeroU32::new(img.width()) {
@@ -238,26 +281,15 @@ pub async fn transformable_img(importable: PathBuf, mut target_path: PathBuf) ->
             false => ScaleRef::Height(height.get())
         };
 
-        // Target name is the filename without extension
-        let target_name = match importable.file_stem() {
-            Some(f) => match f.to_str() {
-                Some(s) => s.to_string(),
-                None => bail!("Failed to extract filename from source image path"),
-            },
-            None => bail!("Failed to extract filename from source image path")
-        };
-
-        // Default target path should contain the target name
-        target_path.push(&target_name);
-
-        // For formats that suppot transparency, export as PNG, else JPEG
-        let (target_ext, pixel) = match format {
-            ImageFormat::Png |
-            ImageFormat::Gif |
-            ImageFormat::WebP |
-            ImageFormat::Bmp |
-            ImageFormat::Tiff => ("png", PixelType::U8x4),
-            _ => ("jpeg", PixelType::U8x3),
+        // For formats that suppot transparency, export as PNG, else JPEG.
+        // High bit depth sources are resized in 16 bits to avoid banding.
+        let target_ext = format.target_ext();
+        let deep = img.color().bytes_per_pixel() > img.color().channel_count();
+        let (img, pixel) = match (target_ext, deep) {
+            ("png", true) => (DynamicImage::ImageRgba16(img.into_rgba16()), PixelType::U16x4),
+            ("png", false) => (DynamicImage::ImageRgba8(img.into_rgba8()), PixelType::U8x4),
+            (_, true) => (DynamicImage::ImageRgb16(img.into_rgb16()), PixelType::U16x3),
+            (_, false) => (DynamicImage::ImageRgb8(img.into_rgb8()), PixelType::U8x3),
         };
     
         let exportable = Transformable {
@@ -265,86 +297,23 @@ pub async fn transformable_img(importable: PathBuf, mut target_path: PathBuf) ->
             height,
             scale: scale_ref,
             source_path: importable,
-            target_path: target_path,
-            target_name,
+            name,
+            template,
             target_ext,
             pixel,
             checksum,
+            output: OutputKind::Resized,
+            frames,
+            upscale: vec![],
+            icc: output_icc(color.space)?.map(Arc::new),
+            linear: color.linear,
+            filter: FilterPolicy::default(),
+            sharpen: SharpenPolicy::default(),
+            effects: vec![],
         };
 
         Ok((exportable, img))
     })
     .await
-    .map_err(|e|anyhow!("{}: Source image failed to load: {}", name, e))?
-}
-
-pub fn polygon_points(r: i32, sides: u32) -> Vec<Point<i32>>{
-    let s = sides as f32;
-    let mut points = vec![];
-    
-    for i in 0..sides {
-        let rotation = ((2.0 * PI) / s) * i as f32;
-
-        // Get x and y points
-        let mut x = r as f32 * rotation.cos();
-        let mut y = r as f32 * rotation.sin();
-        
-        // Both are off by r length
-        x += r as f32;
-        y += r as f32;
-
-        points.push(Point::new(x.floor() as i32, y.floor() as i32));
-    }
-
-    points
-}
-
-pub fn substitute_color_px(target: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, source: &ImageBuffer<Rgba<u8>, Vec<u8>>, color: Rgba<u8>) {
-    for (x, y, p) in target.enumerate_pixels_mut() {
-        if color.eq(*&p) {
-            p.0 = source.get_pixel(x, y).0;
-        }
-    }
-}
-
-pub fn cleanup_vertical(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> (u32, u32) {
-    let mut y = 0;
-    let mut height = 0;
-
-    for mut p in img.rows() {
-        if p.all(|i|TRANSPARENT.eq(*&i)) {
-            match height == 0 {
-                true => y += 1,
-                false => break,
-            };
-
-            continue
-        }
-
-        height += 1;
-    }
-
-    (height, y)
-}
-
-pub fn cleanup_horizontal(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> (u32, u32) {
-    let mut left = usize::MAX;
-    let mut right = 0;
-
-    for p in img.rows() {
-        for (u, i) in p.enumerate() {
-            if !TRANSPARENT.eq(*&i) {
-                if u < left {
-                    left = u
-                }
-
-                if u > right {
-                    right = u
-                }
-            }
-        }
-
-    }
-
-    ((right - left) as u32, left as u32)
+    .map_err(|e|anyhow!("{}: Source image failed to load: {}", source, e))?
 }
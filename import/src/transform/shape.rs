@@ -0,0 +1,502 @@
+use std::f32::consts::PI;
+use std::fs::read_to_string;
+use std::sync::Arc;
+use anyhow::{anyhow, bail, Result};
+use config::{Config, Decoration, Outline, ShapeDef};
+use resvg::tiny_skia::{FillRule, Paint, Path, PathBuilder, Pixmap, Rect, Transform};
+use resvg::usvg;
+
+use super::Mask;
+
+
+/// Names of the built-in shapes in the order they are made.
+pub const PRESETS: [&str; 13] = [
+    "round", "hex", "sep", "sq45", "right", "left", "down", "up", "row2", "row3", "row4", "cross", "star",
+];
+
+/// How the shape is drawn, coordinates are normalized to the 0..1 square.
+#[derive(Debug, Clone)]
+pub enum Geometry {
+    Path(Path),
+    /// SVG document stretched over the square
+    Svg(Arc<String>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Shape {
+    pub name: String,
+    pub geometry: Geometry,
+    /// Clockwise degrees around the center
+    pub rotation: f32,
+    /// Crop the empty canvas around the shape
+    pub trim: bool,
+    pub decoration: Option<Decoration>,
+}
+
+impl Shape {
+    pub fn preset(name: &str) -> Option<Self> {
+        let (path, trim) = match name {
+            "round" => (ellipse(1.), false),
+            "hex" => (polygons(&[regular_polygon(6)]), true),
+            "sep" => (polygons(&[regular_polygon(7)]), false),
+            "sq45" => (polygons(&[regular_polygon(4)]), false),
+            // Triangle points right, the others are rotations of it
+            "right" => (polygons(&[regular_polygon(3)]), true),
+            "left" => (polygons(&[rotate(regular_polygon(3), |[x, y]| [1. - x, 1. - y])]), true),
+            "down" => (polygons(&[rotate(regular_polygon(3), |[x, y]| [1. - y, x])]), true),
+            "up" => (polygons(&[rotate(regular_polygon(3), |[x, y]| [y, 1. - x])]), true),
+            "row2" => (polygons(&rows(2)), false),
+            "row3" => (polygons(&rows(3)), false),
+            "row4" => (polygons(&rows(4)), false),
+            "cross" => (polygons(&[cross()]), false),
+            "star" => (polygons(&[star()]), true),
+            _ => return None,
+        };
+
+        Some(Self { name: name.to_string(), geometry: Geometry::Path(path?), rotation: 0., trim, decoration: None })
+    }
+    pub fn from_def(def: &ShapeDef, config: &Config) -> Result<Self> {
+        let path = match def.outline {
+            Outline::Polygon(ref points) => match points.len() >= 3 {
+                true => polygons(std::slice::from_ref(points)),
+                false => bail!("Shape {:?} polygon needs at least 3 points", def.name),
+            },
+            Outline::Path(ref d) => {
+                if d.contains(['"', '<', '>', '&']) {
+                    bail!("Shape {:?} path is not valid SVG path data", def.name)
+                }
+
+                let [x, y, w, h] = def.viewbox.unwrap_or([0., 0., 1., 1.]);
+
+                return Self::svg(def, format!(
+                    r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="{x} {y} {w} {h}" width="{w}" height="{h}" preserveAspectRatio="none"><path d="{d}" fill="#fff"/></svg>"##
+                ))
+            },
+            Outline::File(ref p) => {
+                let path = config.dir.join(p);
+                let svg = read_to_string(&path)
+                    .map_err(|e| anyhow!("Shape {:?} file {:?} can't be read: {}", def.name, path, e))?;
+
+                return Self::svg(def, svg)
+            },
+            Outline::Ngon { sides } => match sides {
+                3..=1000 => polygons(&[ngon(sides, 0.5, -PI / 2.)]),
+                _ => bail!("Shape {:?} needs 3 to 1000 sides", def.name),
+            },
+            Outline::Star { points, inner } => match (points, inner) {
+                (2..=1000, i) if i > 0. && i <= 1. => polygons(&[star_points(points, inner)]),
+                _ => bail!("Shape {:?} needs 2 to 1000 points and an inner ratio above 0 up to 1", def.name),
+            },
+            Outline::Rect { radius, ratio } => match (0. ..=0.5).contains(&radius) && ratio > 0. && ratio.is_finite() {
+                true => rect(radius, ratio),
+                false => bail!("Shape {:?} needs a radius from 0 to 0.5 and a positive ratio", def.name),
+            },
+            Outline::Squircle { exponent } => match exponent > 0. && exponent.is_finite() {
+                true => polygons(&[squircle(exponent)]),
+                false => bail!("Shape {:?} needs a positive exponent", def.name),
+            },
+            Outline::Heart {} => polygons(&[heart()]),
+            Outline::Ellipse { ratio } => match ratio > 0. && ratio.is_finite() {
+                true => ellipse(ratio),
+                false => bail!("Shape {:?} needs a positive ratio", def.name),
+            },
+        };
+
+        let path = path.ok_or_else(|| anyhow!("Shape {:?} has an empty outline", def.name))?;
+
+        Ok(Self { geometry: Geometry::Path(path), ..Self::custom(def) })
+    }
+    /// Broken SVGs are reported before any image is processed.
+    fn svg(def: &ShapeDef, svg: String) -> Result<Self> {
+        usvg::Tree::from_str(&svg, &usvg::Options::default())
+            .map_err(|e| anyhow!("Shape {:?} is not a valid SVG: {}", def.name, e))?;
+
+        Ok(Self { geometry: Geometry::Svg(Arc::new(svg)), ..Self::custom(def) })
+    }
+    fn custom(def: &ShapeDef) -> Self {
+        Self {
+            name: def.name.clone(),
+            geometry: Geometry::Svg(Arc::default()),
+            rotation: def.rotation,
+            trim: def.trim,
+            decoration: None,
+        }
+    }
+    /// Draw the anti-aliased shape over a square of the given size, decoration grows the canvas.
+    pub fn mask(&self, size: u32) -> Result<Mask> {
+        let mut pixmap = Pixmap::new(size, size)
+            .ok_or_else(|| anyhow!("Shape {:?} can't be drawn in {}px", self.name, size))?;
+
+        let s = size as f32;
+        let transform = Transform::from_rotate_at(self.rotation, s / 2., s / 2.);
+
+        match self.geometry {
+            Geometry::Path(ref path) => {
+                let mut paint = Paint::default();
+                paint.set_color_rgba8(255, 255, 255, 255);
+
+                pixmap.fill_path(path, &paint, FillRule::Winding, transform.pre_scale(s, s), None);
+            },
+            Geometry::Svg(ref svg) => {
+                let tree = usvg::Tree::from_str(svg, &usvg::Options::default())?;
+                let scale = transform.pre_scale(s / tree.size().width(), s / tree.size().height());
+
+                resvg::render(&tree, scale, &mut pixmap.as_mut());
+            },
+        }
+
+        let mask = Mask::from_pixmap(&pixmap)
+            .map_err(|e| anyhow!("Shape {:?}: {}", self.name, e))?;
+
+        Ok(match self.decoration {
+            Some(ref d) => mask.decorate(d),
+            None => mask,
+        })
+    }
+}
+
+/// Built-in shapes enabled in `[shapes]` followed by the custom ones.
+pub fn shapes_from_config(config: &Config) -> Result<Vec<Shape>> {
+    let mut shapes = match config.shapes.presets {
+        Some(ref names) => names.iter()
+            .map(|n| Shape::preset(n).ok_or_else(|| anyhow!("Unknown shape preset {:?}, presets are {:?}", n, PRESETS)))
+            .collect::<Result<Vec<_>>>()?,
+        None => PRESETS.iter().filter_map(|n| Shape::preset(n)).collect(),
+    };
+
+    for def in config.shapes.custom.iter() {
+        // Shape names end up in the output paths
+        match def.name.as_str() {
+            "" | "." | ".." => bail!("Shape name {:?} is not allowed", def.name),
+            n if n.contains(['/', '\\']) => bail!("Shape name {:?} can't contain path separators", n),
+            n if shapes.iter().any(|s| s.name.eq(n)) => bail!("Shape {:?} is defined twice", n),
+            _ => shapes.push(Shape::from_def(def, config)?),
+        }
+    }
+
+    for (name, d) in config.shapes.decoration.iter() {
+        if name.ne("*") && !shapes.iter().any(|s| s.name.eq(name)) {
+            bail!("Decoration for unknown shape {:?}", name)
+        }
+
+        check_decoration(name, d)?;
+    }
+
+    for s in shapes.iter_mut() {
+        s.decoration = config.shapes.decoration(&s.name).cloned();
+    }
+
+    Ok(shapes)
+}
+
+fn check_decoration(name: &str, d: &Decoration) -> Result<()> {
+    let shadow = d.shadow.as_ref();
+    let lengths = [
+        d.stroke.as_ref().map(|s| s.width).unwrap_or(0.),
+        d.padding,
+        shadow.map(|s| s.blur).unwrap_or(0.),
+    ];
+
+    if lengths.iter().any(|l| !l.is_finite() || *l < 0.) {
+        bail!("Decoration of {:?} needs positive stroke width, padding and shadow blur", name)
+    }
+
+    // Lengths are fractions of the shape size
+    if lengths.iter().chain(shadow.iter().flat_map(|s| s.offset.iter())).any(|l| l.abs() >= 0.5) {
+        bail!("Decoration of {:?} has lengths of half the shape size or more", name)
+    }
+
+    match shadow.map(|s| s.opacity) {
+        Some(o) if !(0. ..=1.).contains(&o) => bail!("Shadow opacity of {:?} has to be between 0 and 1", name),
+        _ => Ok(()),
+    }
+}
+
+fn regular_polygon(sides: u32) -> Vec<[f32; 2]> {
+    ngon(sides, 0.5, 0.)
+}
+
+/// Corners on a circle around the center, the first at `start` radians.
+fn ngon(sides: u32, r: f32, start: f32) -> Vec<[f32; 2]> {
+    (0..sides)
+        .map(|i| {
+            let rotation = start + (2. * PI / sides as f32) * i as f32;
+            [0.5 + r * rotation.cos(), 0.5 + r * rotation.sin()]
+        })
+        .collect()
+}
+
+fn rotate(points: Vec<[f32; 2]>, f: impl Fn([f32; 2]) -> [f32; 2]) -> Vec<[f32; 2]> {
+    points.into_iter().map(f).collect()
+}
+
+/// Horizontal rows with a gap between them, padding shrinks as the count grows.
+fn rows(count: u32) -> Vec<Vec<[f32; 2]>> {
+    let padding_sizer = match count {
+        2 => count + 2,
+        3 => count + 1,
+        _ => count,
+    };
+
+    let margin_sizer = match count {
+        2 | 3 => count - 1,
+        _ => count - 2,
+    };
+
+    let div = 1. / count as f32;
+    let padding = div / padding_sizer as f32;
+    let margin = padding / margin_sizer as f32 + div;
+    let height = div - padding;
+
+    (0..count)
+        .map(|i| {
+            let y = i as f32 * margin;
+            vec![[0., y], [1., y], [1., y + height], [0., y + height]]
+        })
+        .collect()
+}
+
+fn cross() -> Vec<[f32; 2]> {
+    let (a, b) = (1. / 3., 2. / 3.);
+
+    vec![
+        [a, 0.], [b, 0.], [b, a], [1., a], [1., b], [b, b],
+        [b, 1.], [a, 1.], [a, b], [0., b], [0., a], [a, a],
+    ]
+}
+
+/// Five sharp ends on the outer circle and five drawn in to half the radius.
+fn star() -> Vec<[f32; 2]> {
+    let step = 2. * PI / 10.;
+
+    (1..11)
+        .map(|i| {
+            let r = match i % 2 == 1 {
+                true => 0.5,
+                false => 0.25,
+            };
+            let rotation = step * i as f32;
+
+            [0.5 + r * rotation.sin(), 0.5 + r * rotation.cos()]
+        })
+        .collect()
+}
+
+/// Star pointing up, corners alternate between the outer and the inner circle.
+fn star_points(points: u32, inner: f32) -> Vec<[f32; 2]> {
+    let step = PI / points as f32;
+
+    (0..points * 2)
+        .map(|i| {
+            let r = match i % 2 {
+                0 => 0.5,
+                _ => 0.5 * inner,
+            };
+            let rotation = -PI / 2. + step * i as f32;
+
+            [0.5 + r * rotation.cos(), 0.5 + r * rotation.sin()]
+        })
+        .collect()
+}
+
+/// Superellipse |x|^n + |y|^n = 1 filling the square.
+fn squircle(exponent: f32) -> Vec<[f32; 2]> {
+    (0..256)
+        .map(|i| {
+            let t = 2. * PI * i as f32 / 256.;
+            let (sin, cos) = t.sin_cos();
+            let e = 2. / exponent;
+
+            [0.5 + 0.5 * cos.signum() * cos.abs().powf(e), 0.5 + 0.5 * sin.signum() * sin.abs().powf(e)]
+        })
+        .collect()
+}
+
+/// Classic heart curve scaled into the square.
+fn heart() -> Vec<[f32; 2]> {
+    // x spans -16..16 and y -17..12 before scaling, y grows down in images
+    (0..256)
+        .map(|i| {
+            let t = 2. * PI * i as f32 / 256.;
+            let x = 16. * t.sin().powi(3);
+            let y = 13. * t.cos() - 5. * (2. * t).cos() - 2. * (3. * t).cos() - (4. * t).cos();
+
+            [0.5 + x / 32., 0.5 - (y + 2.5) / 32.]
+        })
+        .collect()
+}
+
+/// Centered box of height to width `ratio`, the long side fills the square.
+fn fit(ratio: f32) -> Option<Rect> {
+    let (w, h) = match ratio <= 1. {
+        true => (1., ratio),
+        false => (1. / ratio, 1.),
+    };
+
+    Rect::from_xywh((1. - w) / 2., (1. - h) / 2., w, h)
+}
+
+fn ellipse(ratio: f32) -> Option<Path> {
+    fit(ratio).and_then(PathBuilder::from_oval)
+}
+
+/// Rounded corners are quarter circles of `radius` times the short side.
+fn rect(radius: f32, ratio: f32) -> Option<Path> {
+    let b = fit(ratio)?;
+    let r = radius * b.width().min(b.height());
+
+    // Control point distance of a cubic quarter circle
+    let k = r * 0.552_284_8;
+    let (l, t, rt, bt) = (b.left(), b.top(), b.right(), b.bottom());
+
+    let mut pb = PathBuilder::new();
+    pb.move_to(l + r, t);
+    pb.line_to(rt - r, t);
+    pb.cubic_to(rt - r + k, t, rt, t + r - k, rt, t + r);
+    pb.line_to(rt, bt - r);
+    pb.cubic_to(rt, bt - r + k, rt - r + k, bt, rt - r, bt);
+    pb.line_to(l + r, bt);
+    pb.cubic_to(l + r - k, bt, l, bt - r + k, l, bt - r);
+    pb.line_to(l, t + r);
+    pb.cubic_to(l, t + r - k, l + r - k, t, l + r, t);
+    pb.close();
+    pb.finish()
+}
+
+fn polygons(polygons: &[Vec<[f32; 2]>]) -> Option<Path> {
+    let mut pb = PathBuilder::new();
+
+    for p in polygons {
+        for (i, [x, y]) in p.iter().enumerate() {
+            match i {
+                0 => pb.move_to(*x, *y),
+                _ => pb.line_to(*x, *y),
+            }
+        }
+
+        pb.close();
+    }
+
+    pb.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn near(a: [f32; 2], b: [f32; 2]) -> bool {
+        (a[0] - b[0]).abs() < 1e-4 && (a[1] - b[1]).abs() < 1e-4
+    }
+
+    fn bounds(path: Option<Path>) -> [f32; 4] {
+        let b = path.unwrap().bounds();
+        [b.left(), b.top(), b.width(), b.height()]
+    }
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn every_preset_draws() {
+        for name in PRESETS {
+            let shape = Shape::preset(name).unwrap();
+            assert!(shape.mask(32).is_ok(), "{}", name);
+        }
+
+        assert!(Shape::preset("blob").is_none());
+    }
+
+    #[test]
+    fn polygon_corners() {
+        let hex = regular_polygon(6);
+        assert!(near(hex[0], [1., 0.5]) && near(hex[3], [0., 0.5]));
+
+        // First corner of a configured ngon points up
+        let tri = ngon(3, 0.5, -PI / 2.);
+        assert!(near(tri[0], [0.5, 0.]));
+
+        let star = star_points(5, 0.4);
+        assert_eq!(star.len(), 10);
+        assert!(near(star[0], [0.5, 0.]));
+        assert!(star.iter().skip(1).step_by(2).all(|[x, y]| ((x - 0.5).hypot(y - 0.5) - 0.2).abs() < 1e-4));
+
+        // Exponent 2 is a circle
+        assert!(squircle(2.).iter().all(|[x, y]| ((x - 0.5).hypot(y - 0.5) - 0.5).abs() < 1e-4));
+    }
+
+    #[test]
+    fn outlines_fill_the_square() {
+        let fits = |b: [f32; 4]| b.iter().all(|v| (-1e-4..=1. + 1e-4).contains(v));
+
+        for points in [heart(), star(), cross(), squircle(6.)] {
+            assert!(fits(bounds(polygons(&[points]))));
+        }
+
+        assert_eq!(bounds(ellipse(0.5)), [0., 0.25, 1., 0.5]);
+        assert_eq!(bounds(rect(0.5, 2.)), [0.25, 0., 0.5, 1.]);
+        assert_eq!(bounds(polygons(&[cross()])), [0., 0., 1., 1.]);
+    }
+
+    #[test]
+    fn rows_keep_gaps() {
+        for count in 2..=4 {
+            let r = rows(count);
+            assert_eq!(r.len(), count as usize);
+
+            for pair in r.windows(2) {
+                assert!(pair[0][2][1] < pair[1][0][1], "rows of {} overlap", count);
+            }
+
+            assert!(r.iter().all(|row| (0. ..1.).contains(&row[0][1])));
+        }
+    }
+
+    #[test]
+    fn custom_shapes() {
+        let c = config(r##"
+            [shapes]
+            presets = ["round"]
+
+            [[shapes.custom]]
+            name = "kite"
+            polygon = [[0.5, 0.0], [1.0, 0.4], [0.5, 1.0], [0.0, 0.4]]
+
+            [[shapes.custom]]
+            name = "blob"
+            path = "M0 0 H1 V1 Z"
+            rotation = 45.0
+
+            [shapes.decoration."*"]
+            padding = 0.1
+        "##);
+
+        let shapes = shapes_from_config(&c).unwrap();
+
+        assert_eq!(shapes.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(), ["round", "kite", "blob"]);
+        assert!(matches!(shapes[2].geometry, Geometry::Svg(_)));
+        assert!(shapes.iter().all(|s| s.decoration.is_some()));
+        assert!(shapes.iter().all(|s| s.mask(16).is_ok()));
+    }
+
+    #[test]
+    fn invalid_shapes() {
+        let invalid = [
+            "[[shapes.custom]]\nname = \"a\"\npolygon = [[0.0, 0.0], [1.0, 1.0]]",
+            "[[shapes.custom]]\nname = \"a\"\nngon = {sides = 2}",
+            "[[shapes.custom]]\nname = \"a\"\nstar = {points = 5, inner = 0.0}",
+            "[[shapes.custom]]\nname = \"a\"\nrect = {radius = 0.6}",
+            "[[shapes.custom]]\nname = \"a\"\npath = \"M0 0\\\" onload=\\\"\"",
+            "[[shapes.custom]]\nname = \"a/b\"\nheart = {}",
+            "[[shapes.custom]]\nname = \"round\"\nheart = {}",
+            "[shapes]\npresets = [\"blob\"]",
+            "[shapes.decoration.blob]\npadding = 0.1",
+            "[shapes.decoration.round]\npadding = 0.5",
+            "[shapes.decoration.round]\nshadow = {opacity = 2.0}",
+        ];
+
+        for toml in invalid {
+            assert!(shapes_from_config(&config(toml)).is_err(), "{}", toml);
+        }
+    }
+}
@@ -0,0 +1,404 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, bail, Result};
+use axum::{
+    extract::{RawQuery, Request, State},
+    http::{StatusCode, HeaderMap, Uri, header::{AUTHORIZATION, WWW_AUTHENTICATE}},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hmac::{Hmac, Mac};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::debug;
+
+use config::{Access, Auth};
+
+use crate::{Responder, ServerState};
+
+
+const API_KEY: &str = "x-api-key";
+/// Lifetime of signed URLs unless asked otherwise.
+const DEFAULT_TTL: u64 = 3600;
+
+/// What a route does with the key in its path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Get,
+    List,
+    Sign,
+}
+
+/// Access rules, API keys and signing keys of the `[server.auth]` config.
+pub struct Guard(Auth);
+
+impl Guard {
+    pub fn from_config(auth: Auth) -> Result<Self> {
+        if auth.keys.iter().any(|k| k.id.is_empty() || k.secret.is_empty()) {
+            bail!("Signing keys need an id and a secret")
+        }
+
+        if auth.tokens.iter().any(|t| t.is_empty()) {
+            bail!("Empty API key")
+        }
+
+        Ok(Self(auth))
+    }
+    /// Access the rules ask for. Listings reveal everything below them, the strictest
+    /// rule of a nested prefix applies as well.
+    fn required(&self, kind: Kind, key: &str) -> Access {
+        let rule = self.0.rules.iter()
+            .filter(|r| key.starts_with(&r.prefix))
+            .max_by_key(|r| r.prefix.len());
+
+        match kind {
+            Kind::Get => rule.map(|r| r.get).unwrap_or_default(),
+            Kind::List => self.0.rules.iter()
+                .filter(|r| r.prefix.starts_with(key))
+                .map(|r| r.list)
+                .chain([rule.map(|r| r.list).unwrap_or(Access::Token)])
+                .max()
+                .unwrap_or(Access::Token),
+            Kind::Sign => Access::Token,
+        }
+    }
+    /// API key of `Authorization: Bearer` or `X-Api-Key`.
+    fn has_token(&self, headers: &HeaderMap) -> bool {
+        let given = headers.get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .or_else(|| headers.get(API_KEY).and_then(|v| v.to_str().ok()));
+
+        match given {
+            Some(g) => self.0.tokens.iter().any(|t| same(t.as_bytes(), g.trim().as_bytes())),
+            None => false,
+        }
+    }
+    /// Checks `expires`, `key` and `sig` of the query. The signature covers the path and
+    /// the query up to `sig`, which has to come last.
+    fn verify(&self, path: &str, query: Option<&str>) -> Result<()> {
+        let (signed, sig) = match query.and_then(|q| q.rsplit_once("sig=")) {
+            Some((s, sig)) if s.is_empty() || s.ends_with('&') => (s.trim_end_matches('&'), sig),
+            _ => bail!("URL is not signed"),
+        };
+
+        let param = |name: &str| signed.split('&')
+            .find_map(|p| p.strip_prefix(name).and_then(|v| v.strip_prefix('=')));
+
+        let expires = match param("expires").map(str::parse::<u64>) {
+            Some(Ok(e)) => e,
+            _ => bail!("Signed URL has no expiry"),
+        };
+
+        if expires < now() {
+            bail!("Signed URL expired")
+        }
+
+        // Old keys keep verifying until they are removed from the config
+        let key = match param("key").and_then(|id| self.0.keys.iter().find(|k| k.id == id)) {
+            Some(k) => k,
+            None => bail!("Unknown signing key"),
+        };
+
+        let sig = hex::decode(sig).map_err(|_| anyhow!("Invalid signature"))?;
+
+        mac(&key.secret, path, signed).verify_slice(&sig)
+            .map_err(|_| anyhow!("Invalid signature"))
+    }
+    /// Response refusing the request, none if it may read the key.
+    fn refusal(&self, kind: Kind, key: &str, uri: &Uri, headers: &HeaderMap) -> Option<Response> {
+        match self.required(kind, key) {
+            Access::Public => None,
+            _ if self.has_token(headers) => None,
+            Access::Signed => match self.verify(uri.path(), uri.query()) {
+                Ok(()) => None,
+                Err(e) => {
+                    debug!("Refused {:?}: {}", uri, e);
+                    Some(Responder::with_status(StatusCode::FORBIDDEN, (), e).into_response())
+                },
+            },
+            Access::Token => {
+                let (code, body) = Responder::with_status(StatusCode::UNAUTHORIZED, (), anyhow!("API key needed"));
+                Some((code, [(WWW_AUTHENTICATE, "Bearer")], body).into_response())
+            },
+        }
+    }
+    /// Path and query of a signed URL, signed by the first key.
+    fn sign(&self, path: &str, query: &str, expires: u64) -> Result<String> {
+        let key = match self.0.keys.first() {
+            Some(k) => k,
+            None => bail!("No signing keys configured"),
+        };
+
+        let signed = match query {
+            "" => format!("expires={}&key={}", expires, key.id),
+            q => format!("{}&expires={}&key={}", q, expires, key.id),
+        };
+
+        let sig = hex::encode(mac(&key.secret, path, &signed).finalize().into_bytes());
+
+        Ok(format!("{}?{}&sig={}", path, signed, sig))
+    }
+}
+
+/// Middleware letting requests through that meet the access rule of their key.
+pub async fn check(state: State<ServerState>, request: Request, next: Next) -> Response {
+    let guard = match state.auth {
+        Some(ref g) => g,
+        None => return next.run(request).await,
+    };
+
+    let (kind, key) = match route(request.uri().path()) {
+        Some(r) => r,
+        None => return next.run(request).await,
+    };
+
+    // Resized images cached into the bucket follow the rules of their source
+    let key = match state.resize {
+        Some(ref r) if state.cache_dir.is_none() => match key.strip_prefix(&format!("{}/", r.cache_prefix)) {
+            Some(k) => k.to_string(),
+            None => key,
+        },
+        _ => key,
+    };
+
+    match guard.refusal(kind, &key, request.uri(), request.headers()) {
+        Some(r) => r,
+        None => next.run(request).await,
+    }
+}
+
+/// Refusal of the object a route resolved its path to, the rules of the path were met already.
+pub fn check_resolved(state: &ServerState, key: &str, uri: &Uri, headers: &HeaderMap) -> Option<Response> {
+    state.auth.as_ref()?.refusal(Kind::Get, key, uri, headers)
+}
+
+#[derive(Debug, Serialize)]
+pub struct Signed {
+    url: String,
+    /// Unix time
+    expires: u64,
+}
+
+/// Signed URL of `/sign/<route>?<query>`, valid for `ttl` seconds.
+pub async fn sign_handler(uri: Uri, RawQuery(query): RawQuery, state: State<ServerState>)
+-> Response {
+    let guard = match state.auth {
+        Some(ref g) => g,
+        None => return Responder::with_status(StatusCode::NOT_FOUND, (), anyhow!("Auth is not enabled")).into_response(),
+    };
+
+    let path = uri.path().strip_prefix("/sign").unwrap_or_default();
+
+    if !matches!(route(path), Some((Kind::Get, _)) | Some((Kind::List, _))) {
+        let e = anyhow!("{:?} is not a route to sign", path);
+        return Responder::with_status(StatusCode::BAD_REQUEST, (), e).into_response()
+    }
+
+    let mut ttl = DEFAULT_TTL;
+    let mut params = vec![];
+
+    for p in query.as_deref().unwrap_or_default().split('&').filter(|p| !p.is_empty()) {
+        match p.strip_prefix("ttl=") {
+            Some(t) => match t.parse() {
+                Ok(t) => ttl = t,
+                Err(_) => {
+                    let e = anyhow!("TTL {:?} is not a number of seconds", t);
+                    return Responder::with_status(StatusCode::BAD_REQUEST, (), e).into_response()
+                },
+            },
+            // Left to the signature
+            None if ["expires=", "key=", "sig="].iter().any(|n| p.starts_with(n)) => (),
+            None => params.push(p),
+        }
+    }
+
+    let expires = now().saturating_add(ttl);
+
+    match guard.sign(path, &params.join("&"), expires) {
+        Ok(url) => Responder::success(Signed { url, expires }).into_response(),
+        Err(e) => Responder::with_status(StatusCode::NOT_FOUND, (), e).into_response(),
+    }
+}
+
+/// Kind of the route and the key it reads, decoded. Listings end with a `/`.
+fn route(path: &str) -> Option<(Kind, String)> {
+    let decode = |p: &str| percent_decode_str(p).decode_utf8_lossy().to_string();
+    let dir = |p: &str| match decode(p).trim_end_matches('/') {
+        "" => String::new(),
+        d => format!("{}/", d),
+    };
+
+    if path == "/list" {
+        return Some((Kind::List, String::new()))
+    }
+
+    if path.starts_with("/sign/") {
+        return Some((Kind::Sign, String::new()))
+    }
+
+    let get = ["/s3/get/", "/img/"].iter().find_map(|r| path.strip_prefix(r));
+    let list = ["/s3/list/", "/s3/index/", "/list/", "/images/"].iter().find_map(|r| path.strip_prefix(r));
+
+    match (get, list) {
+        (Some(k), _) => Some((Kind::Get, decode(k))),
+        (_, Some(k)) => Some((Kind::List, dir(k))),
+        _ => None,
+    }
+}
+
+fn mac(secret: &str, path: &str, query: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(path.as_bytes());
+    mac.update(b"?");
+    mac.update(query.as_bytes());
+    mac
+}
+
+/// Compares without giving away how much of a key was right.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(config: &str) -> Guard {
+        Guard::from_config(toml::from_str(config).unwrap()).unwrap()
+    }
+
+    fn split(url: &str) -> (&str, &str) {
+        url.split_once('?').unwrap()
+    }
+
+    const KEYS: &str = r#"keys = [{ id = "new", secret = "s2" }, { id = "old", secret = "s1" }]"#;
+
+    #[test]
+    fn signed_url_verifies() {
+        let g = guard(KEYS);
+        let url = g.sign("/img/a/photo.jpg", "w=480", now() + 60).unwrap();
+        let (path, query) = split(&url);
+
+        assert!(query.starts_with("w=480&expires="));
+        assert!(query.contains("&key=new&sig="));
+        assert!(g.verify(path, Some(query)).is_ok());
+    }
+
+    #[test]
+    fn tampered_url_fails() {
+        let g = guard(KEYS);
+        let url = g.sign("/img/a/photo.jpg", "w=480", now() + 60).unwrap();
+        let (path, query) = split(&url);
+
+        assert!(g.verify("/img/a/other.jpg", Some(query)).is_err());
+        assert!(g.verify(path, Some(&query.replace("w=480", "w=4800"))).is_err());
+        assert!(g.verify(path, Some(&query.replace("key=new", "key=old"))).is_err());
+        assert!(g.verify(path, Some(&format!("{}&w=10", query))).is_err());
+        assert!(g.verify(path, Some(&format!("{}00", query))).is_err());
+        assert!(g.verify(path, Some("w=480")).is_err());
+        assert!(g.verify(path, None).is_err());
+
+        // Flip the last hex digit of the signature
+        let last = query.chars().last().unwrap();
+        let flipped = format!("{}{}", &query[..query.len() - 1], if last == '0' { '1' } else { '0' });
+        assert!(g.verify(path, Some(&flipped)).is_err());
+    }
+
+    #[test]
+    fn expired_url_fails() {
+        let g = guard(KEYS);
+        let url = g.sign("/img/a/photo.jpg", "", now() - 1).unwrap();
+        let (path, query) = split(&url);
+
+        assert!(g.verify(path, Some(query)).unwrap_err().to_string().contains("expired"));
+
+        // Pushing the expiry breaks the signature
+        let later = query.replacen(&(now() - 1).to_string(), &(now() + 60).to_string(), 1);
+        assert!(g.verify(path, Some(&later)).is_err());
+    }
+
+    #[test]
+    fn rotated_keys() {
+        let before = guard(r#"keys = [{ id = "old", secret = "s1" }]"#);
+        let during = guard(KEYS);
+        let after = guard(r#"keys = [{ id = "new", secret = "s2" }]"#);
+
+        let url = before.sign("/s3/get/a.jpg", "", now() + 60).unwrap();
+        let (path, query) = split(&url);
+
+        // Old URLs keep working until their key is removed
+        assert!(during.verify(path, Some(query)).is_ok());
+        assert!(after.verify(path, Some(query)).unwrap_err().to_string().contains("Unknown signing key"));
+
+        // Same id with another secret
+        let replaced = guard(r#"keys = [{ id = "old", secret = "other" }]"#);
+        assert!(replaced.verify(path, Some(query)).is_err());
+    }
+
+    #[test]
+    fn rules_of_nested_prefixes() {
+        let g = guard(r#"
+            [[rules]]
+            prefix = "clients/"
+            get = "signed"
+
+            [[rules]]
+            prefix = "clients/public/"
+            list = "public"
+
+            [[rules]]
+            prefix = "clients/public/secret/"
+            get = "token"
+            list = "token"
+
+            [[rules]]
+            prefix = "open/"
+            list = "public"
+        "#);
+
+        // Longest prefix wins for images
+        assert_eq!(g.required(Kind::Get, "other/a.jpg"), Access::Public);
+        assert_eq!(g.required(Kind::Get, "clients/a.jpg"), Access::Signed);
+        assert_eq!(g.required(Kind::Get, "clients/public/a.jpg"), Access::Public);
+        assert_eq!(g.required(Kind::Get, "clients/public/secret/a.jpg"), Access::Token);
+
+        // Listings take the strictest rule below them too
+        assert_eq!(g.required(Kind::List, "open/"), Access::Public);
+        assert_eq!(g.required(Kind::List, "clients/public/"), Access::Token);
+        assert_eq!(g.required(Kind::List, "clients/public/other/"), Access::Public);
+        assert_eq!(g.required(Kind::List, ""), Access::Token);
+        assert_eq!(g.required(Kind::Sign, "open/"), Access::Token);
+    }
+
+    #[test]
+    fn routes_read_decoded_keys() {
+        assert_eq!(route("/s3/get/a%20b/c.jpg"), Some((Kind::Get, "a b/c.jpg".to_string())));
+        assert_eq!(route("/img/a/c"), Some((Kind::Get, "a/c".to_string())));
+        assert_eq!(route("/list/a/b"), Some((Kind::List, "a/b/".to_string())));
+        assert_eq!(route("/images/a/"), Some((Kind::List, "a/".to_string())));
+        assert_eq!(route("/list"), Some((Kind::List, String::new())));
+        assert_eq!(route("/sign/img/a"), Some((Kind::Sign, String::new())));
+        assert_eq!(route("/health"), None);
+    }
+
+    #[test]
+    fn tokens_compare_whole() {
+        let g = guard(r#"tokens = ["secret"]"#);
+        let headers = |name: &str, value: &str| {
+            let mut h = HeaderMap::new();
+            h.insert(axum::http::HeaderName::from_bytes(name.as_bytes()).unwrap(), value.parse().unwrap());
+            h
+        };
+
+        assert!(g.has_token(&headers("authorization", "Bearer secret")));
+        assert!(g.has_token(&headers("x-api-key", "secret")));
+        assert!(!g.has_token(&headers("x-api-key", "secre")));
+        assert!(!g.has_token(&headers("authorization", "secret")));
+        assert!(!g.has_token(&HeaderMap::new()));
+    }
+}
@@ -0,0 +1,289 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+use axum::{
+    body::Body,
+    http::{
+        StatusCode, HeaderMap,
+        header::{
+            ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, HeaderName, HeaderValue,
+            IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE,
+        },
+    },
+    response::{IntoResponse, Response},
+};
+use glob::{MatchOptions, Pattern};
+use tokio::sync::Semaphore;
+
+use config::CacheControl;
+
+use crate::Responder;
+use crate::store::{Meta, Store};
+
+
+/// `Cache-Control` values by key pattern.
+#[derive(Clone, Default)]
+pub struct CacheRules(Vec<(Pattern, HeaderValue)>);
+
+impl CacheRules {
+    pub fn from_config(rules: &[CacheControl]) -> Result<Self> {
+        rules.iter()
+            .map(|r| {
+                let pattern = Pattern::new(&r.pattern)
+                    .map_err(|e| anyhow!("Pattern {:?}: {}", r.pattern, e))?;
+                let value = HeaderValue::from_str(&r.value)
+                    .map_err(|e| anyhow!("Value {:?}: {}", r.value, e))?;
+
+                Ok((pattern, value))
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(Self)
+    }
+    pub fn get(&self, key: &str) -> Option<&HeaderValue> {
+        let options = MatchOptions { require_literal_separator: true, ..Default::default() };
+
+        self.0.iter()
+            .find(|(p, _)| p.matches_with(key, options))
+            .map(|(_, v)| v)
+    }
+}
+
+/// Body of an object response.
+pub enum Content {
+    Bytes(Vec<u8>),
+    /// Streamed from the store once the response is known to have a body
+    Stream { store: Store, key: String, limit: Option<Arc<Semaphore>> },
+}
+
+/// Response for a GET of an object honoring conditional and range headers of the request.
+pub async fn object_response(
+    request: &HeaderMap,
+    meta: Meta,
+    content: Content,
+    cache_control: Option<&HeaderValue>,
+    extra: &[(HeaderName, HeaderValue)],
+) -> Response {
+    // Dates of HTTP have no fractions of a second
+    let last_modified = meta.last_modified.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| UNIX_EPOCH + Duration::from_secs(d.as_secs()));
+
+    let mut headers = HeaderMap::new();
+
+    if let Some(v) = meta.etag.as_deref().and_then(|e| HeaderValue::from_str(e).ok()) {
+        headers.insert(ETAG, v);
+    }
+
+    if let Some(t) = last_modified {
+        headers.insert(LAST_MODIFIED, HeaderValue::from_str(&httpdate::fmt_http_date(t)).unwrap());
+    }
+
+    if let Some(v) = cache_control {
+        headers.insert(CACHE_CONTROL, v.to_owned());
+    }
+
+    for (k, v) in extra {
+        headers.insert(k, v.to_owned());
+    }
+
+    if not_modified(request, meta.etag.as_deref(), last_modified) {
+        return (StatusCode::NOT_MODIFIED, headers).into_response()
+    }
+
+    if let Some(v) = meta.content_type.as_deref().and_then(|t| HeaderValue::from_str(t).ok()) {
+        headers.insert(CONTENT_TYPE, v);
+    }
+
+    let len = match (&content, meta.length) {
+        (Content::Bytes(b), _) => Some(b.len() as u64),
+        (Content::Stream { .. }, l) => l,
+    };
+
+    // Ranges need to know where the object ends
+    let range = match (request.get(RANGE).and_then(|v| v.to_str().ok()), len) {
+        (Some(r), Some(len)) if range_applies(request, meta.etag.as_deref(), last_modified) => byte_range(r, len),
+        _ => None,
+    };
+
+    if len.is_some() {
+        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    }
+
+    let (status, range) = match (range, len) {
+        (Some(Some((start, end))), Some(len)) => {
+            let content_range = format!("bytes {}-{}/{}", start, end, len);
+            headers.insert(CONTENT_RANGE, HeaderValue::from_str(&content_range).unwrap());
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(end - start + 1));
+
+            (StatusCode::PARTIAL_CONTENT, Some((start, end)))
+        },
+        (Some(None), Some(len)) => {
+            headers.insert(CONTENT_RANGE, HeaderValue::from_str(&format!("bytes */{}", len)).unwrap());
+
+            return (StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response()
+        },
+        (_, len) => {
+            if let Some(len) = len {
+                headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+            }
+
+            (StatusCode::OK, None)
+        },
+    };
+
+    let body = match content {
+        Content::Bytes(mut bytes) => {
+            if let Some((start, end)) = range {
+                bytes.truncate(end as usize + 1);
+                bytes.drain(..start as usize);
+            }
+
+            Body::from(bytes)
+        },
+        Content::Stream { store, key, limit } => {
+            // Waits for a running transfer to end when the limit is reached
+            let permit = match limit {
+                Some(l) => match l.acquire_owned().await {
+                    Ok(p) => Some(p),
+                    Err(e) => return Responder::with_status(StatusCode::SERVICE_UNAVAILABLE, (), e.into()).into_response(),
+                },
+                None => None,
+            };
+
+            match store.stream(&key, range, permit).await {
+                Ok(b) => b,
+                Err(e) => return Responder::error((), e).into_response(),
+            }
+        },
+    };
+
+    (status, headers, body).into_response()
+}
+
+/// `If-None-Match` decides when present, `If-Modified-Since` otherwise.
+fn not_modified(request: &HeaderMap, etag: Option<&str>, last_modified: Option<SystemTime>) -> bool {
+    if let Some(v) = request.get(IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+        return match etag {
+            Some(etag) => v.split(',').map(str::trim).any(|t| t == "*" || weak(t) == weak(etag)),
+            None => v.trim() == "*",
+        }
+    }
+
+    match (request.get(IF_MODIFIED_SINCE).and_then(|v| v.to_str().ok()), last_modified) {
+        (Some(v), Some(t)) => httpdate::parse_http_date(v).is_ok_and(|since| t <= since),
+        _ => false,
+    }
+}
+
+/// Ranges of a changed object are ignored when `If-Range` is given.
+fn range_applies(request: &HeaderMap, etag: Option<&str>, last_modified: Option<SystemTime>) -> bool {
+    match request.get(IF_RANGE).and_then(|v| v.to_str().ok()) {
+        None => true,
+        // Strong comparison only
+        Some(v) if v.starts_with('"') => etag.is_some_and(|e| e == v),
+        Some(v) => httpdate::parse_http_date(v).ok().zip(last_modified).is_some_and(|(a, b)| a == b),
+    }
+}
+
+fn weak(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+/// Inclusive byte range of a single range `Range` header, `Some(None)` if it can't be
+/// satisfied. Anything else is served whole.
+fn byte_range(header: &str, len: u64) -> Option<Option<(u64, u64)>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+
+    if spec.contains(',') {
+        return None
+    }
+
+    let (start, end) = spec.split_once('-')?;
+
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let n = suffix.parse::<u64>().ok()?;
+            (n > 0 && len > 0).then(|| (len.saturating_sub(n), len - 1))
+        },
+        (start, "") => {
+            let s = start.parse::<u64>().ok()?;
+            (s < len).then(|| (s, len - 1))
+        },
+        (start, end) => {
+            let (s, e) = (start.parse::<u64>().ok()?, end.parse::<u64>().ok()?);
+
+            if e < s {
+                return None
+            }
+
+            (s < len).then(|| (s, e.min(len - 1)))
+        },
+    };
+
+    Some(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(name: axum::http::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn byte_range_forms() {
+        assert_eq!(byte_range("bytes=0-99", 1000), Some(Some((0, 99))));
+        assert_eq!(byte_range("bytes=900-2000", 1000), Some(Some((900, 999))));
+        assert_eq!(byte_range("bytes=500-", 1000), Some(Some((500, 999))));
+        assert_eq!(byte_range("bytes=-100", 1000), Some(Some((900, 999))));
+        assert_eq!(byte_range("bytes=-5000", 1000), Some(Some((0, 999))));
+    }
+
+    #[test]
+    fn byte_range_unsatisfiable() {
+        assert_eq!(byte_range("bytes=1000-", 1000), Some(None));
+        assert_eq!(byte_range("bytes=1000-1100", 1000), Some(None));
+        assert_eq!(byte_range("bytes=-0", 1000), Some(None));
+        assert_eq!(byte_range("bytes=-10", 0), Some(None));
+    }
+
+    #[test]
+    fn byte_range_ignored() {
+        assert_eq!(byte_range("bytes=0-1,5-9", 1000), None);
+        assert_eq!(byte_range("bytes=9-5", 1000), None);
+        assert_eq!(byte_range("items=0-9", 1000), None);
+        assert_eq!(byte_range("bytes=a-b", 1000), None);
+    }
+
+    #[test]
+    fn not_modified_by_etag() {
+        let etag = Some("\"abc\"");
+
+        assert!(not_modified(&request(IF_NONE_MATCH, "\"abc\""), etag, None));
+        assert!(not_modified(&request(IF_NONE_MATCH, "\"x\", W/\"abc\""), etag, None));
+        assert!(not_modified(&request(IF_NONE_MATCH, "W/\"abc\""), Some("W/\"abc\""), None));
+        assert!(!not_modified(&request(IF_NONE_MATCH, "\"other\""), etag, None));
+    }
+
+    #[test]
+    fn not_modified_by_star() {
+        assert!(not_modified(&request(IF_NONE_MATCH, "*"), Some("\"abc\""), None));
+        assert!(not_modified(&request(IF_NONE_MATCH, "*"), None, None));
+        assert!(!not_modified(&request(IF_NONE_MATCH, "\"abc\""), None, None));
+    }
+
+    #[test]
+    fn not_modified_by_date() {
+        let modified = httpdate::parse_http_date("Tue, 28 May 2024 10:41:35 GMT").ok();
+
+        assert!(not_modified(&request(IF_MODIFIED_SINCE, "Tue, 28 May 2024 10:41:35 GMT"), None, modified));
+        assert!(!not_modified(&request(IF_MODIFIED_SINCE, "Mon, 27 May 2024 10:41:35 GMT"), None, modified));
+
+        // If-None-Match wins over the date
+        let mut headers = request(IF_MODIFIED_SINCE, "Tue, 28 May 2024 10:41:35 GMT");
+        headers.insert(IF_NONE_MATCH, "\"other\"".parse().unwrap());
+        assert!(!not_modified(&headers, Some("\"abc\""), modified));
+    }
+}
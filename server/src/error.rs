@@ -0,0 +1,65 @@
+use std::fmt;
+use std::io::ErrorKind;
+use axum::http::StatusCode;
+use s3::error::S3Error;
+
+
+/// Object or dir that the store doesn't have.
+#[derive(Debug)]
+pub struct NotFound(pub String);
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} not found", self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// Request path the server refuses to read.
+#[derive(Debug)]
+pub struct BadPath(pub String);
+
+impl fmt::Display for BadPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Path {:?} is not allowed", self.0)
+    }
+}
+
+impl std::error::Error for BadPath {}
+
+/// Status code telling a missing image from a bad request or a store that is down.
+pub fn status_of(e: &anyhow::Error) -> StatusCode {
+    if e.is::<NotFound>() {
+        return StatusCode::NOT_FOUND
+    }
+
+    if e.is::<BadPath>() {
+        return StatusCode::BAD_REQUEST
+    }
+
+    if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        return match e.kind() {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    match e.downcast_ref::<S3Error>() {
+        Some(S3Error::Http(code, _)) => match *code {
+            404 => StatusCode::NOT_FOUND,
+            400 => StatusCode::BAD_REQUEST,
+            401 | 403 => StatusCode::FORBIDDEN,
+            503 => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::BAD_GATEWAY,
+        },
+        // Store can't be reached at all
+        Some(S3Error::Reqwest(e)) if e.is_connect() || e.is_timeout() => StatusCode::SERVICE_UNAVAILABLE,
+        Some(S3Error::Io(_)) => StatusCode::SERVICE_UNAVAILABLE,
+        Some(S3Error::Credentials(_)) | Some(S3Error::WLCredentials) | Some(S3Error::RLCredentials) =>
+            StatusCode::INTERNAL_SERVER_ERROR,
+        Some(_) => StatusCode::BAD_GATEWAY,
+        None => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
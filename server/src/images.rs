@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::Serialize;
+
+use import::resize::TargetSize;
+use import::transform::SHAPES_VARIANT;
+
+use crate::{Responder, ServerState};
+use crate::error::NotFound;
+use crate::list::{items, Item};
+use crate::store::{safe_path, Entry};
+
+/// Characters escaped in a path segment of the URLs, commas would split `srcset` entries.
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ').add(b'"').add(b'#').add(b'%').add(b',').add(b'/')
+    .add(b'<').add(b'>').add(b'?').add(b'`').add(b'{').add(b'}');
+
+/// Single exported file of a source image.
+#[derive(Debug, Serialize)]
+pub struct Image {
+    /// Size id or shape name
+    id: String,
+    url: String,
+    /// Unknown for objects that couldn't be probed, they are left out of `srcset`
+    width: Option<u32>,
+    height: Option<u32>,
+    bytes: u64,
+    format: String,
+}
+
+/// Sizes of an image with the attributes of an `<img>` showing them.
+#[derive(Debug, Serialize)]
+pub struct ImageSet {
+    images: Vec<Image>,
+    srcset: String,
+    sizes: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Shape {
+    #[serde(flatten)]
+    image: Image,
+    variants: BTreeMap<String, Image>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Document {
+    path: String,
+    #[serde(flatten)]
+    set: ImageSet,
+    variants: BTreeMap<String, ImageSet>,
+    shapes: Vec<Shape>,
+}
+
+/// Everything exported of a source image under `/images/<source-path>`, read by the layout
+/// of the default path template: `<size>.<ext>`, `<variant>/<size>.<ext>`,
+/// `shapes/<shape>.<ext>` and `shapes/<variant>/<shape>.<ext>`.
+pub async fn images_handler(Path(tail): Path<String>, state: State<ServerState>) -> Response {
+    if let Err(e) = safe_path(&tail) {
+        return Responder::error((), e).into_response()
+    }
+
+    let tail = tail.trim_end_matches('/');
+    let prefix = format!("{}/", tail);
+
+    let mut entries: Vec<Entry> = vec![];
+    let mut after = None;
+
+    loop {
+        let (page, more) = match state.store.list_page(&prefix, true, after, 1000).await {
+            Ok(p) => p,
+            Err(e) => return Responder::error((), e).into_response(),
+        };
+
+        after = page.last().map(|e| e.key.clone());
+
+        // Dirs of other sources may be nested deeper, checksum files are hidden
+        entries.extend(page.into_iter().filter(|e| {
+            let rel = &e.key[prefix.len()..];
+            rel.split('/').count() <= 3 && !rel.rsplit('/').next().unwrap_or_default().starts_with('.')
+        }));
+
+        if !more {
+            break
+        }
+    }
+
+    let mut set = vec![];
+    let mut variants: BTreeMap<String, Vec<Image>> = BTreeMap::new();
+    let mut shapes: BTreeMap<String, Shape> = BTreeMap::new();
+    let mut shape_variants = vec![];
+
+    for item in items(&state, entries).await {
+        let rel = item.key[prefix.len()..].to_string();
+        let parts = rel.split('/').collect::<Vec<_>>();
+        let image = image(item);
+
+        match parts.as_slice() {
+            [_] if is_size(&image.id) => set.push(image),
+            [dir, _] if *dir == SHAPES_VARIANT => {
+                shapes.insert(image.id.to_owned(), Shape { image, variants: BTreeMap::new() });
+            },
+            [dir, _] if is_size(&image.id) && state.variants.iter().any(|v| v == dir) =>
+                variants.entry(dir.to_string()).or_default().push(image),
+            [dir, variant, _] if *dir == SHAPES_VARIANT => shape_variants.push((variant.to_string(), image)),
+            _ => (),
+        }
+    }
+
+    // Variants of shapes that weren't exported themselves are left out
+    for (variant, image) in shape_variants {
+        if let Some(shape) = shapes.get_mut(&image.id) {
+            shape.variants.insert(variant, image);
+        }
+    }
+
+    // Dirs of variants alone look the same as dirs of other sources
+    if set.is_empty() && shapes.is_empty() {
+        return Responder::error((), NotFound(tail.to_string()).into()).into_response()
+    }
+
+    let document = Document {
+        path: tail.to_string(),
+        set: image_set(set),
+        variants: variants.into_iter().map(|(k, v)| (k, image_set(v))).collect(),
+        shapes: shapes.into_values().collect(),
+    };
+
+    Responder::success(document).into_response()
+}
+
+fn is_size(id: &str) -> bool {
+    TargetSize::ids().contains(&id)
+}
+
+fn image(item: Item) -> Image {
+    let name = item.key.rsplit('/').next().unwrap_or_default();
+
+    // Hashed names carry the hash between the id and the extension
+    let id = name.split('.').next().unwrap_or_default().to_string();
+    let format = name.rsplit_once('.').map(|(_, e)| e.to_lowercase()).unwrap_or_default();
+
+    Image {
+        id,
+        url: url(&item.key),
+        width: item.width,
+        height: item.height,
+        bytes: item.bytes,
+        format,
+    }
+}
+
+fn url(key: &str) -> String {
+    let path = key.split('/')
+        .map(|s| utf8_percent_encode(s, SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    format!("/s3/get/{}", path)
+}
+
+/// Sizes from the largest down, `srcset` of the format most of them are in.
+fn image_set(mut images: Vec<Image>) -> ImageSet {
+    let ids = TargetSize::ids();
+    images.sort_by_key(|i| ids.iter().position(|id| *id == i.id));
+
+    let mut formats: BTreeMap<&str, usize> = BTreeMap::new();
+
+    for i in images.iter() {
+        *formats.entry(i.format.as_str()).or_default() += 1;
+    }
+
+    let format = formats.into_iter().max_by_key(|(_, n)| *n).map(|(f, _)| f.to_string());
+
+    let mut widths = images.iter()
+        .filter(|i| Some(&i.format) == format.as_ref())
+        .filter_map(|i| i.width.map(|w| (w, i.url.as_str())))
+        .collect::<Vec<_>>();
+
+    widths.sort_by_key(|(w, _)| *w);
+    widths.dedup_by_key(|(w, _)| *w);
+
+    let srcset = widths.iter()
+        .map(|(w, url)| format!("{} {}w", url, w))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    // Full width up to the largest size, never stretched beyond it
+    let sizes = match widths.last() {
+        Some((w, _)) => format!("(max-width: {w}px) 100vw, {w}px"),
+        None => String::new(),
+    };
+
+    ImageSet { images, srcset, sizes }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(key: &str, width: Option<u32>) -> Item {
+        Item {
+            key: key.to_string(),
+            bytes: 1,
+            last_modified: None,
+            content_type: "image/jpeg",
+            width,
+            height: None,
+            checksum: None,
+        }
+    }
+
+    #[test]
+    fn urls_are_encoded() {
+        assert_eq!(url("a/b/md.jpg"), "/s3/get/a/b/md.jpg");
+        assert_eq!(url("my photos/a,b#1?/md.3f2a1b.jpg"), "/s3/get/my%20photos/a%2Cb%231%3F/md.3f2a1b.jpg");
+        assert_eq!(url("ä/100%/md.jpg"), "/s3/get/%C3%A4/100%25/md.jpg");
+    }
+
+    #[test]
+    fn unknown_widths_stay_out_of_srcset() {
+        let set = image_set(vec![
+            image(item("a b/md.jpg", None)),
+            image(item("a b/xl.jpg", Some(1200))),
+            image(item("a b/lg.jpg", Some(600))),
+        ]);
+
+        assert_eq!(set.images.iter().map(|i| i.id.as_str()).collect::<Vec<_>>(), ["xl", "lg", "md"]);
+        assert_eq!(set.images[2].width, None);
+        assert_eq!(set.srcset, "/s3/get/a%20b/lg.jpg 600w, /s3/get/a%20b/xl.jpg 1200w");
+        assert_eq!(set.sizes, "(max-width: 1200px) 100vw, 1200px");
+    }
+}
@@ -0,0 +1,270 @@
+use std::str::FromStr;
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Path, Query, State},
+    http::{StatusCode, HeaderMap, Uri, header::{ACCEPT, VARY, HeaderValue}},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use config::DynamicResize;
+use import::decode::is_limit_error;
+use import::resize::dynamic::{resize_bytes, Fit, Format, Request};
+use import::resize::TargetSize;
+
+use crate::{Responder, ServerState};
+use crate::auth::check_resolved;
+use crate::caching::{object_response, Content};
+use crate::error::{status_of, NotFound};
+use crate::negotiate::pick;
+use crate::store::{etag, safe_path, Meta};
+
+
+#[derive(Debug, Deserialize)]
+pub struct ImgQuery {
+    w: Option<u32>,
+    h: Option<u32>,
+    fit: Option<String>,
+    fmt: Option<String>,
+    q: Option<u8>,
+}
+
+impl ImgQuery {
+    fn is_empty(&self) -> bool {
+        self.w.is_none() && self.h.is_none() && self.fit.is_none() && self.fmt.is_none() && self.q.is_none()
+    }
+}
+
+/// Resized image for `/img/<path>`, the path is an object or a dir of derived images.
+/// Without a query `/img/<dir>/<name>` serves the format of `<name>` the client likes best.
+pub async fn img_handler(
+    Path(tail): Path<String>,
+    Query(query): Query<ImgQuery>,
+    uri: Uri,
+    headers: HeaderMap,
+    state: State<ServerState>,
+) -> Response {
+    if let Err(e) = safe_path(&tail) {
+        return failure(StatusCode::BAD_REQUEST, e)
+    }
+
+    if query.is_empty() {
+        let accept = headers.get(ACCEPT).and_then(|v| v.to_str().ok());
+
+        return negotiated(&state, &tail, accept, &uri, &headers).await
+    }
+
+    let resize = match state.resize {
+        Some(ref r) => r,
+        None => return failure(StatusCode::NOT_FOUND, anyhow!("Resizing on request is not enabled")),
+    };
+
+    if !resize.allows(query.w, query.h) {
+        return failure(StatusCode::BAD_REQUEST, anyhow!("Size w={:?} h={:?} is not allowed", query.w, query.h))
+    }
+
+    let quality = query.q.unwrap_or(resize.quality);
+
+    if !resize.allows_quality(quality) {
+        return failure(StatusCode::BAD_REQUEST, anyhow!("Quality {} is not allowed", quality))
+    }
+
+    let fit = match query.fit.as_deref().map(Fit::from_str) {
+        Some(Ok(f)) => f,
+        Some(Err(e)) => return failure(StatusCode::BAD_REQUEST, e),
+        None => Fit::Inside,
+    };
+
+    let source = match find_source(&state, &tail).await {
+        Ok(s) => s,
+        Err(e) => return failure(status_of(&e), e),
+    };
+
+    if let Some(refused) = check_resolved(&state, &source, &uri, &headers) {
+        return refused
+    }
+
+    // Format of the source unless asked otherwise
+    let format = match query.fmt.as_deref() {
+        Some(f) => match Format::from_str(f) {
+            Ok(f) => f,
+            Err(e) => return failure(StatusCode::BAD_REQUEST, e),
+        },
+        None => match source.rsplit_once('.').map(|(_, e)| e.to_lowercase()).as_deref() {
+            Some("png") | Some("gif") => Format::Png,
+            Some("webp") => Format::Webp,
+            _ => Format::Jpeg,
+        },
+    };
+
+    let meta = match state.store.head(&source).await {
+        Ok(m) => m,
+        Err(e) => return failure(status_of(&e), e),
+    };
+
+    // Cached results of a replaced source are never read again
+    let request = Request { width: query.w, height: query.h, fit, format, quality };
+    let key = cache_key(&tail, meta.etag.as_deref().unwrap_or_default(), &request);
+
+    if let Some(cached) = read_cache(&state, resize, &key).await {
+        debug!("Serving {:?} from cache", key);
+        return image_response(&state, &headers, &tail, cached, format).await
+    }
+
+    // Too large sources are refused before downloading them
+    if let Err(e) = meta.length.map_or(Ok(()), |l| state.policy.check_size(&source, l)) {
+        return failure(StatusCode::UNPROCESSABLE_ENTITY, e)
+    }
+
+    // Sources are held in memory from download to encoding, only so many at once
+    let _permit = match state.resizes.acquire().await {
+        Ok(p) => p,
+        Err(e) => return failure(StatusCode::SERVICE_UNAVAILABLE, e.into()),
+    };
+
+    let bytes = match state.store.get(&source).await {
+        Ok(b) => b,
+        Err(e) => return failure(status_of(&e), e),
+    };
+
+    match resize_bytes(&source, bytes, request, &state.policy).await {
+        Ok(resized) => {
+            if let Err(e) = write_cache(&state, resize, &key, &resized, format).await {
+                warn!("Failed to cache {:?}: {}", key, e);
+            }
+
+            image_response(&state, &headers, &tail, resized, format).await
+        },
+        Err(e) if is_limit_error(&e) => failure(StatusCode::UNPROCESSABLE_ENTITY, e),
+        Err(e) => failure(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Sibling objects named `<name>.<ext>`, hashed names included, in the format picked by `Accept`.
+async fn negotiated(state: &ServerState, tail: &str, accept: Option<&str>, uri: &Uri, headers: &HeaderMap)
+-> Response {
+    let not_found = || failure(StatusCode::NOT_FOUND, NotFound(tail.to_string()).into());
+
+    let (dir, name) = match tail.trim_end_matches('/').rsplit_once('/') {
+        Some((_, n)) if n.contains('.') => return not_found(),
+        Some(p) => p,
+        None => return not_found(),
+    };
+
+    let candidates = match state.store.list(&format!("{}/", dir)).await {
+        Ok(keys) => keys.into_iter()
+            .filter(|k| k.rsplit('/').next().and_then(|n| n.split('.').next()) == Some(name))
+            .collect::<Vec<_>>(),
+        Err(e) => return failure(status_of(&e), e),
+    };
+
+    let (key, content_type) = match pick(accept, &candidates) {
+        Some(c) => c,
+        None => return not_found(),
+    };
+
+    if let Some(refused) = check_resolved(state, key, uri, headers) {
+        return refused
+    }
+
+    match state.store.head(key).await {
+        Ok(meta) => {
+            let meta = Meta { content_type: Some(content_type.to_string()), ..meta };
+            let content = Content::Stream { store: state.store.clone(), key: key.to_owned(), limit: state.transfers.clone() };
+            let vary = [(VARY, HeaderValue::from_static("Accept"))];
+
+            object_response(headers, meta, content, state.cache_control.get(tail), &vary).await
+        },
+        Err(e) => failure(status_of(&e), e),
+    }
+}
+
+async fn image_response(state: &ServerState, headers: &HeaderMap, tail: &str, bytes: Vec<u8>, format: Format)
+-> Response {
+    let meta = Meta {
+        content_type: Some(format.content_type().to_string()),
+        etag: Some(etag(&bytes)),
+        ..Default::default()
+    };
+
+    object_response(headers, meta, Content::Bytes(bytes), state.cache_control.get(tail), &[]).await
+}
+
+fn failure(code: StatusCode, e: anyhow::Error) -> Response {
+    Responder::with_status(code, (), e).into_response()
+}
+
+/// Key of the object itself or of the largest derived image in the dir.
+async fn find_source(state: &ServerState, tail: &str) -> Result<String> {
+    if state.store.exists(tail).await {
+        return Ok(tail.to_string())
+    }
+
+    let keys = state.store.list(&format!("{}/", tail.trim_end_matches('/'))).await?;
+
+    // Names start with the size id, hashed names carry the hash after it
+    TargetSize::ids().iter()
+        .find_map(|id| keys.iter().find(|k| {
+            let name = k.rsplit('/').next().unwrap_or(k);
+            name.split('.').next() == Some(*id)
+        }))
+        .cloned()
+        .ok_or_else(|| NotFound(tail.to_string()).into())
+}
+
+/// Cache path of a resized image, the source version is taken from its ETag.
+fn cache_key(tail: &str, version: &str, request: &Request) -> String {
+    let side = |v: Option<u32>| v.map(|v| v.to_string()).unwrap_or_default();
+    let fit = match request.fit {
+        Fit::Cover => "cover",
+        Fit::Contain => "contain",
+        Fit::Fill => "fill",
+        Fit::Inside => "inside",
+    };
+
+    // Quotes and the weak prefix are no use in a file name
+    let version = version.trim_start_matches("W/")
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+        .take(64)
+        .collect::<String>();
+
+    format!(
+        "{}/{}x{}-{}-q{}-{}.{}",
+        tail.trim_end_matches('/'),
+        side(request.width),
+        side(request.height),
+        fit,
+        request.quality,
+        version,
+        request.format.ext(),
+    )
+}
+
+async fn read_cache(state: &ServerState, resize: &DynamicResize, key: &str) -> Option<Vec<u8>> {
+    match state.cache_dir {
+        Some(ref dir) => tokio::fs::read(dir.join(key)).await.ok(),
+        None => state.store.get(&format!("{}/{}", resize.cache_prefix, key)).await.ok(),
+    }
+}
+
+async fn write_cache(state: &ServerState, resize: &DynamicResize, key: &str, bytes: &[u8], format: Format) -> Result<()> {
+    match state.cache_dir {
+        Some(ref dir) => {
+            let path = dir.join(key);
+
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+
+            tokio::fs::write(path, bytes).await?;
+        },
+        None => {
+            let key = format!("{}/{}", resize.cache_prefix, key);
+            state.store.put(&key, bytes, format.content_type()).await?;
+        },
+    }
+
+    Ok(())
+}
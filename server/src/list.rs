@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::path::Path as FsPath;
+use std::sync::{Arc, Mutex};
+use anyhow::anyhow;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{debug, warn};
+
+use config::{Manifest, ManifestEntry};
+
+use crate::{Responder, ServerState};
+use crate::store::{content_type, safe_path, Entry, Store};
+
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+/// Objects probed for their dimensions and checksum at once
+const PROBES: usize = 16;
+
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    limit: Option<usize>,
+    /// Key of the last item of the previous page
+    cursor: Option<String>,
+    /// Comma separated size ids or shape names
+    size: Option<String>,
+    /// Dir the image is in, like `gray` or `shapes`
+    variant: Option<String>,
+    /// Comma separated file extensions
+    ext: Option<String>,
+    #[serde(default)]
+    recursive: bool,
+}
+
+impl ListQuery {
+    fn matches(&self, key: &str) -> bool {
+        let path = FsPath::new(key);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+        // Checksum files and the manifest are bookkeeping of the import
+        if name.starts_with('.') || name == Manifest::FILENAME {
+            return false
+        }
+
+        let listed = |list: &Option<String>, value: &str| match list {
+            Some(l) => l.split(',').any(|v| v.trim().eq_ignore_ascii_case(value)),
+            None => true,
+        };
+
+        let id = name.split('.').next().unwrap_or_default();
+        let ext = path.extension().unwrap_or_default().to_string_lossy();
+        let dir = path.parent().and_then(|p| p.file_name()).unwrap_or_default().to_string_lossy();
+
+        listed(&self.size, id) && listed(&self.ext, &ext) && listed(&self.variant, &dir)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Item {
+    pub key: String,
+    pub bytes: u64,
+    pub last_modified: Option<String>,
+    pub content_type: &'static str,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub checksum: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Page {
+    items: Vec<Item>,
+    /// Cursor of the next page, none on the last one
+    cursor: Option<String>,
+}
+
+pub async fn list_root_handler(query: Query<ListQuery>, state: State<ServerState>) -> Response {
+    list_handler(Path(String::new()), query, state).await
+}
+
+/// Page of objects under `/list/<path>` with their metadata.
+pub async fn list_handler(Path(tail): Path<String>, Query(query): Query<ListQuery>, state: State<ServerState>)
+-> Response {
+    if !tail.is_empty() {
+        if let Err(e) = safe_path(&tail) {
+            return Responder::error((), e).into_response()
+        }
+    }
+
+    let limit = match query.limit.unwrap_or(DEFAULT_LIMIT) {
+        l @ 1..=MAX_LIMIT => l,
+        l => {
+            let e = anyhow!("Limit {} is not within 1..={}", l, MAX_LIMIT);
+            return Responder::with_status(StatusCode::BAD_REQUEST, (), e).into_response()
+        },
+    };
+
+    let prefix = match tail.trim_end_matches('/') {
+        "" => String::new(),
+        t => format!("{}/", t),
+    };
+
+    let mut entries: Vec<Entry> = vec![];
+    let mut after = query.cursor.clone();
+    let mut more = true;
+
+    // Filters may leave pages short, read on until one item past the page is found
+    while more && entries.len() <= limit {
+        let (page, truncated) = match state.store.list_page(&prefix, query.recursive, after.clone(), MAX_LIMIT).await {
+            Ok(p) => p,
+            Err(e) => return Responder::error((), e).into_response(),
+        };
+
+        more = truncated;
+        after = page.last().map(|e| e.key.clone());
+
+        entries.extend(page.into_iter().filter(|e| query.matches(&e.key)));
+    }
+
+    let cursor = match entries.len() > limit {
+        true => {
+            entries.truncate(limit);
+            entries.last().map(|e| e.key.clone())
+        },
+        false => None,
+    };
+
+    let items = items(&state, entries).await;
+
+    Responder::success(Page { items, cursor }).into_response()
+}
+
+/// Manifest entries by object key.
+type ByKey = Arc<HashMap<String, ManifestEntry>>;
+
+/// Manifest entries read again only once the ETag of the manifest has changed.
+#[derive(Clone)]
+pub struct ManifestCache {
+    key: String,
+    cached: Arc<Mutex<Option<(String, ByKey)>>>,
+}
+
+impl ManifestCache {
+    pub fn new(key: String) -> Self {
+        Self { key, cached: Arc::new(Mutex::new(None)) }
+    }
+    /// Plain names have no manifest, nothing is known of them.
+    async fn entries(&self, store: &Store) -> ByKey {
+        let etag = match store.head(&self.key).await {
+            Ok(m) => m.etag.unwrap_or_default(),
+            Err(_) => return Arc::default(),
+        };
+
+        if let Ok(cached) = self.cached.lock() {
+            if let Some((ref t, ref entries)) = *cached {
+                if t.eq(&etag) {
+                    return entries.clone()
+                }
+            }
+        }
+
+        let manifest = match store.get(&self.key).await.ok().map(|b| Manifest::from_slice(&b)) {
+            Some(Ok(m)) => m,
+            Some(Err(e)) => {
+                warn!("Failed to read manifest {:?}: {}", self.key, e);
+                return Arc::default()
+            },
+            None => return Arc::default(),
+        };
+
+        debug!("Manifest {:?} loaded with {} entries", self.key, manifest.0.len());
+
+        let entries = Arc::new(manifest.0.into_values()
+            .map(|e| (e.key.to_owned(), e))
+            .collect::<HashMap<String, ManifestEntry>>());
+
+        if let Ok(mut cached) = self.cached.lock() {
+            *cached = Some((etag, entries.clone()));
+        }
+
+        entries
+    }
+}
+
+/// Items with dimensions and checksum of the manifest, or of the objects themselves.
+pub async fn items(state: &ServerState, entries: Vec<Entry>) -> Vec<Item> {
+    let by_key = state.manifest.entries(&state.store).await;
+    let probes = Arc::new(Semaphore::new(PROBES));
+
+    let mut handles = JoinSet::new();
+
+    for (i, entry) in entries.into_iter().enumerate() {
+        let store = state.store.clone();
+        let known = by_key.get(&entry.key).cloned();
+        let probes = probes.clone();
+
+        handles.spawn(async move {
+            let _permit = probes.acquire_owned().await;
+
+            let (width, height, checksum) = match known {
+                Some(ManifestEntry { width: Some(w), height: Some(h), checksum, .. }) => (Some(w), Some(h), Some(checksum)),
+                Some(m) => {
+                    let (w, h) = store.dimensions(&entry.key).await.unzip();
+                    (w, h, Some(m.checksum))
+                },
+                None => {
+                    let (w, h) = store.dimensions(&entry.key).await.unzip();
+                    (w, h, store.checksum(&entry.key).await)
+                },
+            };
+
+            let item = Item {
+                content_type: content_type(FsPath::new(&entry.key)),
+                key: entry.key,
+                bytes: entry.size,
+                last_modified: entry.last_modified,
+                width,
+                height,
+                checksum,
+            };
+
+            (i, item)
+        });
+    }
+
+    let mut items = vec![];
+
+    while let Some(result) = handles.join_next().await {
+        if let Ok(item) = result {
+            items.push(item);
+        }
+    }
+
+    items.sort_by_key(|(i, _)| *i);
+    items.into_iter().map(|(_, item)| item).collect()
+}
@@ -1,49 +1,106 @@
+mod auth;
+mod caching;
+mod error;
+mod images;
+mod img;
+mod list;
+mod negotiate;
+mod serve;
+mod store;
+
 use axum::body::Bytes;
-use s3::Bucket;
 use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
 use tracing::{debug, info};
 use std::net::SocketAddr;
-use std::str::FromStr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use serde::Serialize;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower_http::{compression::CompressionLayer, decompression::RequestDecompressionLayer, timeout::TimeoutLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use axum::{
     extract::{State, Path, Json},
-    http::{StatusCode, header::{HeaderName, HeaderValue, HeaderMap}},
+    http::{StatusCode, HeaderMap, header::{CACHE_CONTROL, CONTENT_TYPE}},
     response::IntoResponse,
+    middleware,
     routing::get,
     Router
 };
 
-use config::{Config, ObjectStore};
+use config::{Config, DynamicResize, Manifest, ObjectStore};
+use import::decode::SourcePolicy;
+use auth::{check, sign_handler, Guard};
+use caching::{object_response, CacheRules, Content};
+use images::images_handler;
+use img::img_handler;
+use list::{list_handler, list_root_handler, ManifestCache};
+use serve::{serve, tls_acceptor, Listener, Options};
+use error::status_of;
+use store::{content_type, safe_path, Store};
 
 
 #[derive(Clone)]
 struct ServerState {
-    bucket: Bucket,
+    store: Store,
+    resize: Option<DynamicResize>,
+    /// Local cache of resized images, the bucket is used if not set
+    cache_dir: Option<PathBuf>,
+    cache_control: CacheRules,
+    /// Image sent along with 404 of `/s3/get`
+    placeholder: Option<(Bytes, &'static str)>,
+    /// Limit of objects streamed at once
+    transfers: Option<Arc<Semaphore>>,
+    /// Limit of images resized at once, `parallel_img_max` of the import
+    resizes: Arc<Semaphore>,
+    /// Source limits of the import apply to resizing on request as well
+    policy: SourcePolicy,
+    /// Manifest of hashed names in the store
+    manifest: ManifestCache,
+    /// Variant dirs the import writes, other dirs belong to other sources
+    variants: Vec<String>,
+    /// Every route is open if not set
+    auth: Option<Arc<Guard>>,
 }
 
 #[derive(Serialize)]
 struct Responder<T: serde::Serialize> {
     status: String,
     data: T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorBody>,
+}
+
+/// Error part of every failed JSON response.
+#[derive(Serialize)]
+struct ErrorBody {
+    code: u16,
+    message: String,
 }
 
 impl<T: serde::Serialize> Responder<T> {
-    fn error(value: T, message: anyhow::Error) -> Json<Responder<T>> {
+    /// Status code follows what went wrong, see `error::status_of`.
+    fn error(value: T, message: anyhow::Error) -> (StatusCode, Json<Responder<T>>) {
+        Self::with_status(status_of(&message), value, message)
+    }
+    fn with_status(code: StatusCode, value: T, message: anyhow::Error) -> (StatusCode, Json<Responder<T>>) {
         let response = Self {
-            status: format!("{} ({})", StatusCode::INTERNAL_SERVER_ERROR.to_string(), message),
-            data: value
+            status: code.to_string(),
+            data: value,
+            error: Some(ErrorBody { code: code.as_u16(), message: message.to_string() }),
         };
 
-        Json(response)
+        (code, Json(response))
     }
-    fn success(value: T) -> Json<Responder<T>> {
+    fn success(value: T) -> (StatusCode, Json<Responder<T>>) {
         let response = Self {
             status: StatusCode::OK.to_string(),
-            data: value
+            data: value,
+            error: None,
         };
 
-        Json(response)
+        (StatusCode::OK, Json(response))
     }
 }
 
@@ -78,8 +135,8 @@ async fn main() {
 
     debug!("Export config loaded...");
 
-    let s3_store = match export_config.s3 {
-        true => {
+    let store = match (export_config.s3, export_config.filesystem) {
+        (true, _) => {
             let s3_config = match config.s3_mut() {
                 Ok(c) => c,
                 Err(e) => panic!("Incomplete config for S3: {}", e),
@@ -92,94 +149,203 @@ async fn main() {
 
             debug!("S3 config loaded...");
 
-            ObjectStore::get(&s3_config).unwrap()
+            Store::S3(Box::new(ObjectStore::get(s3_config).unwrap().0))
         },
-        false => panic!("Server only reads objects from S3"),
+        // Same root the filesystem export writes to
+        (false, true) => {
+            let root = match export_config.filesystem_path {
+                Some(ref p) => p.to_owned(),
+                None => config.dir.to_owned(),
+            };
+
+            debug!("Reading objects from {:?}...", root);
+
+            Store::Filesystem(root)
+        },
+        (false, false) => panic!("Server reads objects from S3 or the filesystem export, neither is enabled"),
     };
 
-    let ip = match format!("{}:{}", server_config.host, server_config.port).parse::<SocketAddr>() {
-        Ok(a) => a,
-        Err(e) => panic!("Failed to join socket address from {:?}: {}", config.server, e),
+    // Where the import keeps the manifest of hashed names, object keys of S3 start with the config dir
+    let manifest_key = {
+        let mut key = match store {
+            Store::S3(_) => config.dir.to_owned(),
+            Store::Filesystem(_) => PathBuf::new(),
+        };
+
+        if let Some(ref p) = export_config.prefix {
+            key.push(p);
+        }
+
+        key.join(Manifest::FILENAME).to_string_lossy().to_string()
     };
 
-    let state = ServerState { bucket: s3_store.0 };
+    let cache_dir = server_config.resize.as_ref()
+        .and_then(|r|r.cache_dir.as_ref())
+        .map(|d|config.dir.join(d));
 
-    // App with routes to list and read
-    let app = Router::new()
-        .route("/s3/get/*tail", get(s3_get_handler))
+    let cache_control = match CacheRules::from_config(&server_config.cache_control) {
+        Ok(r) => r,
+        Err(e) => panic!("Invalid cache_control config: {}", e),
+    };
+
+    let placeholder = server_config.placeholder.as_ref().map(|p| {
+        let path = config.dir.join(p);
+
+        match std::fs::read(&path) {
+            Ok(b) => (Bytes::from(b), content_type(&path)),
+            Err(e) => panic!("Failed to read placeholder {:?}: {}", path, e),
+        }
+    });
+
+    let transfers = server_config.max_transfers.map(|n| Arc::new(Semaphore::new(n)));
+    let resizes = Arc::new(Semaphore::new(config.parallel_img_max.unwrap_or(4)));
+    let policy = SourcePolicy::from_config(&config);
+
+    let auth = server_config.auth.map(|a| match Guard::from_config(a) {
+        Ok(g) => Arc::new(g),
+        Err(e) => panic!("Invalid auth config: {}", e),
+    });
+
+    let variants = config.variants.iter().map(|v| v.name.to_owned()).collect();
+
+    let state = ServerState {
+        store,
+        resize: server_config.resize,
+        cache_dir,
+        cache_control,
+        placeholder,
+        transfers,
+        resizes,
+        policy,
+        manifest: ManifestCache::new(manifest_key),
+        variants,
+        auth,
+    };
+
+    // JSON of listings gets long, images are compressed already
+    let json = Router::new()
+        .route("/images/*tail", get(images_handler))
+        .route("/list", get(list_root_handler))
+        .route("/list/*tail", get(list_handler))
         .route("/s3/list/*tail", get(s3_object_handler))
         .route("/s3/index/*tail", get(s3_index_handler))
+        .route("/sign/*tail", get(sign_handler))
+        .layer(CompressionLayer::new())
+        .layer(RequestDecompressionLayer::new());
+
+    // App with routes to list and read
+    let mut app = Router::new()
+        .route("/img/*tail", get(img_handler))
+        .route("/s3/get/*tail", get(s3_get_handler))
+        .merge(json)
+        .layer(middleware::from_fn_with_state(state.clone(), check))
         .with_state(state);
 
-    let listener = match TcpListener::bind(&ip).await {
-        Ok(l) => l,
-        Err(e) => panic!("Unable to start listener: {}", e),
+    if let Some(n) = server_config.max_requests {
+        app = app.layer(GlobalConcurrencyLimitLayer::new(n));
+    }
+
+    // Waiting for a turn counts too
+    let timeout = server_config.request_timeout.map(Duration::from_secs);
+
+    if let Some(t) = timeout {
+        app = app.layer(TimeoutLayer::new(t));
+    }
+
+    let tls = server_config.tls.as_ref().map(|t| {
+        match tls_acceptor(&config.dir.join(&t.cert), &config.dir.join(&t.key)) {
+            Ok(a) => a,
+            Err(e) => panic!("Invalid TLS config: {}", e),
+        }
+    });
+
+    let scheme = match tls {
+        Some(_) => "https",
+        None => "http",
     };
-    
-    info!("Listening on http://{}...", ip);
-    
-    axum::serve(listener, app).await.unwrap()
-}
 
-async fn s3_object_handler(Path(tail): Path<String>, state: State<ServerState>)
--> Json<Responder<Vec<String>>> {
-    let delimiter = Some(String::from("/"));
-    let prefix = format!("{}/", tail);
-
-    match state.bucket.list(prefix, delimiter).await {
-        Ok(v) => {
-            
-            // info!("resp: {:?}", v);
-            
-            Responder::success(v.into_iter()
-                .map(|i|i.contents.into_iter()
-                    .map(|o|o.key)
-                    .collect::<Vec<String>>())
-                .flatten()
-                .collect()
-            )
+    let listener = match server_config.socket {
+        Some(ref p) => {
+            let path = config.dir.join(p);
+
+            match Listener::unix(&path) {
+                Ok(l) => {
+                    info!("Listening on {}+unix://{}...", scheme, path.display());
+                    l
+                },
+                Err(e) => panic!("Unable to bind socket {:?}: {}", path, e),
+            }
         },
-        Err(e) => return Responder::error(vec![], e.into()),
+        None => {
+            let ip = match format!("{}:{}", server_config.host, server_config.port).parse::<SocketAddr>() {
+                Ok(a) => a,
+                Err(e) => panic!("Failed to join socket address from {:?}: {}", config.server, e),
+            };
+
+            match TcpListener::bind(&ip).await {
+                Ok(l) => {
+                    info!("Listening on {}://{}...", scheme, ip);
+                    Listener::Tcp(l)
+                },
+                Err(e) => panic!("Unable to start listener: {}", e),
+            }
+        },
+    };
+
+    let options = Options {
+        tls,
+        header_timeout: timeout,
+        drain: Duration::from_secs(server_config.shutdown_timeout),
+    };
+
+    serve(listener, app, options).await;
+
+    info!("Stopped");
+}
+async fn s3_object_handler(Path(tail): Path<String>, state: State<ServerState>)
+-> impl IntoResponse {
+    if let Err(e) = safe_path(&tail) {
+        return Responder::error(vec![], e)
+    }
+
+    match state.store.list(&format!("{}/", tail)).await {
+        Ok(v) => Responder::success(v),
+        Err(e) => Responder::error(vec![], e),
     }
 }
 
 async fn s3_index_handler(Path(tail): Path<String>, state: State<ServerState>)
--> Json<Responder<Vec<String>>> {
-    let delimiter = Some(String::from("/"));
-    let prefix = format!("{}/", tail);
-
-    match state.bucket.list(prefix, delimiter).await {
-        Ok(v) => Responder::success(v.into_iter()
-            .map(|i|i.common_prefixes.into_iter()
-                .flat_map(|v|v.into_iter()
-                    .map(|p|p.prefix))
-                .collect::<Vec<String>>())
-            .flatten()
-            .collect()
-        ),
-        Err(e) => return Responder::error(vec![], e.into()),
+-> impl IntoResponse {
+    if let Err(e) = safe_path(&tail) {
+        return Responder::error(vec![], e)
+    }
+
+    match state.store.index(&format!("{}/", tail)).await {
+        Ok(v) => Responder::success(v),
+        Err(e) => Responder::error(vec![], e),
     }
 }
 
-async fn s3_get_handler(Path(tail): Path<String>, state: State<ServerState>)
+async fn s3_get_handler(Path(tail): Path<String>, headers: HeaderMap, state: State<ServerState>)
 -> impl IntoResponse {
-    let prefix = format!("{}", tail);
-    let mut headers = HeaderMap::new();
-
-    match state.bucket.get_object(prefix).await {
-        Ok(v) => {
-            for (k, v) in v.headers() {
-                if ["content-type", "content-length"].contains(&k.to_lowercase().as_str()) {
-                    headers.insert(
-                        HeaderName::from_str(&k).unwrap(),
-                        HeaderValue::from_str(&v).unwrap()
-                    );
-                }
-            }
-            
-            (headers, v.to_vec()).into_response()
-            
+    if let Err(e) = safe_path(&tail) {
+        return Responder::error((), e).into_response()
+    }
+
+    match state.store.head(&tail).await {
+        Ok(meta) => {
+            let content = Content::Stream { store: state.store.clone(), key: tail.clone(), limit: state.transfers.clone() };
+
+            object_response(&headers, meta, content, state.cache_control.get(&tail), &[]).await
+        },
+        Err(e) => match (status_of(&e), &state.placeholder) {
+            // Still a 404, but something to show in an <img>
+            (StatusCode::NOT_FOUND, Some((bytes, content_type))) => (
+                StatusCode::NOT_FOUND,
+                [(CONTENT_TYPE, *content_type), (CACHE_CONTROL, "no-store")],
+                bytes.clone(),
+            ).into_response(),
+            _ => Responder::error((), e).into_response(),
         },
-        Err(_e) => (headers, Bytes::default()).into_response(),
     }
 }
@@ -0,0 +1,131 @@
+/// Image types the server picks between, most preferred first when the client likes them equally.
+const PREFERRED: [(&str, &str); 6] = [
+    ("avif", "image/avif"),
+    ("webp", "image/webp"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("png", "image/png"),
+    ("gif", "image/gif"),
+];
+
+/// Media range from an `Accept` header with its weight.
+struct Range<'a> {
+    kind: &'a str,
+    subtype: &'a str,
+    q: f32,
+}
+
+fn ranges(accept: &str) -> Vec<Range<'_>> {
+    accept.split(',')
+        .filter_map(|part| {
+            let mut params = part.split(';').map(str::trim);
+            let (kind, subtype) = params.next()?.split_once('/')?;
+
+            let q = params
+                .filter_map(|p| p.strip_prefix("q="))
+                .find_map(|v| v.parse::<f32>().ok())
+                .unwrap_or(1.);
+
+            Some(Range { kind, subtype, q })
+        })
+        .collect()
+}
+
+/// Weight of a content type, the most specific matching range decides.
+fn weight(ranges: &[Range], content_type: &str) -> f32 {
+    let (kind, subtype) = content_type.split_once('/').unwrap_or((content_type, ""));
+
+    ranges.iter()
+        .filter_map(|r| match (r.kind, r.subtype) {
+            (k, s) if k.eq_ignore_ascii_case(kind) && s.eq_ignore_ascii_case(subtype) => Some((2, r.q)),
+            (k, "*") if k.eq_ignore_ascii_case(kind) => Some((1, r.q)),
+            ("*", "*") => Some((0, r.q)),
+            _ => None,
+        })
+        .max_by_key(|(specific, _)| *specific)
+        .map(|(_, q)| q)
+        .unwrap_or(0.)
+}
+
+/// Key of the best candidate for the `Accept` header and its content type. Candidates are
+/// keys of the same image in different formats, unknown formats are ignored. When the
+/// client accepts none of them JPEG or PNG is served anyway, or any known format.
+pub fn pick<'a>(accept: Option<&str>, candidates: &'a [String]) -> Option<(&'a String, &'static str)> {
+    let ranges = ranges(accept.unwrap_or("*/*"));
+
+    // Known formats in the order of preference
+    let known = PREFERRED.iter()
+        .flat_map(|(ext, content_type)| candidates.iter()
+            .filter(move |k| k.rsplit_once('.').is_some_and(|(_, e)| e.eq_ignore_ascii_case(ext)))
+            .map(move |k| (k, *content_type)))
+        .collect::<Vec<_>>();
+
+    let best = known.iter()
+        .map(|c| (c, weight(&ranges, c.1)))
+        .filter(|(_, q)| *q > 0.)
+        // First of the equally weighted, max_by would return the last
+        .fold(None, |best: Option<(&(&String, &str), f32)>, (c, q)| match best {
+            Some((_, bq)) if bq >= q => best,
+            _ => Some((c, q)),
+        })
+        .map(|(c, _)| *c);
+
+    best.or_else(|| known.iter()
+        .find(|(_, t)| *t == "image/jpeg" || *t == "image/png")
+        .or(known.first())
+        .copied())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| format!("a/b/{}", n)).collect()
+    }
+
+    fn picked<'a>(accept: Option<&str>, candidates: &'a [String]) -> Option<(&'a str, &'static str)> {
+        pick(accept, candidates).map(|(k, t)| (k.as_str(), t))
+    }
+
+    #[test]
+    fn preferred_order() {
+        let c = keys(&["md.jpg", "md.webp", "md.avif", "md.png"]);
+
+        assert_eq!(picked(Some("image/avif,image/webp,*/*"), &c), Some(("a/b/md.avif", "image/avif")));
+        assert_eq!(picked(Some("image/webp,*/*;q=0.8"), &c), Some(("a/b/md.webp", "image/webp")));
+        assert_eq!(picked(Some("image/*"), &c), Some(("a/b/md.avif", "image/avif")));
+        assert_eq!(picked(None, &c), Some(("a/b/md.avif", "image/avif")));
+    }
+
+    #[test]
+    fn weights_decide() {
+        let c = keys(&["md.webp", "md.jpg"]);
+
+        assert_eq!(picked(Some("image/webp;q=0.5,image/jpeg"), &c), Some(("a/b/md.jpg", "image/jpeg")));
+        assert_eq!(picked(Some("image/*;q=0.9,image/webp;q=0"), &c), Some(("a/b/md.jpg", "image/jpeg")));
+    }
+
+    #[test]
+    fn fallback_when_nothing_is_accepted() {
+        let c = keys(&["md.webp", "md.png"]);
+
+        assert_eq!(picked(Some("text/html"), &c), Some(("a/b/md.png", "image/png")));
+        assert_eq!(picked(Some("text/html"), &keys(&["md.webp"])), Some(("a/b/md.webp", "image/webp")));
+        assert_eq!(picked(Some("*/*"), &keys(&["md.txt", "md.json"])), None);
+    }
+
+    #[test]
+    fn gif_only() {
+        let c = keys(&["anim.gif"]);
+
+        assert_eq!(picked(Some("image/gif"), &c), Some(("a/b/anim.gif", "image/gif")));
+        assert_eq!(picked(Some("image/webp,*/*"), &c), Some(("a/b/anim.gif", "image/gif")));
+        assert_eq!(picked(Some("image/avif"), &c), Some(("a/b/anim.gif", "image/gif")));
+
+        // Still and animated outputs side by side, gif comes after the stills
+        let c = keys(&["anim.png", "anim.GIF"]);
+        assert_eq!(picked(Some("image/gif,image/png;q=0.5"), &c), Some(("a/b/anim.GIF", "image/gif")));
+        assert_eq!(picked(None, &c), Some(("a/b/anim.png", "image/png")));
+    }
+}
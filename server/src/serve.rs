@@ -0,0 +1,186 @@
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Result};
+use axum::Router;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio_rustls::{rustls::{crypto::ring, ServerConfig}, TlsAcceptor};
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn};
+
+
+/// Time a client gets to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub enum Listener {
+    Tcp(TcpListener),
+    /// Socket file is removed on shutdown
+    Unix(UnixListener, PathBuf),
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    /// Replaces the socket file a previous run left behind, any other file is kept.
+    pub fn unix(path: &Path) -> Result<Self> {
+        match std::fs::symlink_metadata(path) {
+            Ok(m) if m.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => bail!("{:?} exists and is not a socket", path),
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            Err(_) => (),
+        }
+
+        Ok(Self::Unix(UnixListener::bind(path)?, path.to_owned()))
+    }
+    async fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Self::Tcp(l) => l.accept().await.map(|(s, _)| Stream::Tcp(s)),
+            Self::Unix(l, _) => l.accept().await.map(|(s, _)| Stream::Unix(s)),
+        }
+    }
+}
+
+/// Connection options of `serve`.
+#[derive(Clone)]
+pub struct Options {
+    pub tls: Option<TlsAcceptor>,
+    /// Time to send the request headers in
+    pub header_timeout: Option<Duration>,
+    /// Time running requests get to finish on shutdown
+    pub drain: Duration,
+}
+
+/// TLS acceptor of a PEM certificate chain and private key.
+pub fn tls_acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut io::BufReader::new(std::fs::File::open(cert)?))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let key = rustls_pemfile::private_key(&mut io::BufReader::new(std::fs::File::open(key)?))?
+        .ok_or_else(|| anyhow!("No private key in {:?}", key))?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Serves the app until SIGTERM or Ctrl-C, then stops accepting and waits for running
+/// requests to finish.
+pub async fn serve(listener: Listener, app: Router, options: Options) {
+    let (close, closing) = watch::channel(());
+    let tracker = TaskTracker::new();
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(s) => s,
+                // Mostly out of file descriptors, give running requests a moment
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue
+                },
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let (app, options, closing) = (app.clone(), options.clone(), closing.clone());
+
+        tracker.spawn(async move {
+            match stream {
+                Stream::Tcp(s) => handle(s, app, options, closing).await,
+                Stream::Unix(s) => handle(s, app, options, closing).await,
+            }
+        });
+    }
+
+    info!("Shutting down, {} connections open...", tracker.len());
+
+    if let Listener::Unix(_, ref path) = listener {
+        if let Err(e) = std::fs::remove_file(path) {
+            warn!("Failed to remove socket {:?}: {}", path, e);
+        }
+    }
+
+    drop(listener);
+    drop(close);
+    tracker.close();
+
+    if tokio::time::timeout(options.drain, tracker.wait()).await.is_err() {
+        warn!("{} connections still open after {:?}, closing them", tracker.len(), options.drain);
+    }
+}
+
+async fn handle<I>(io: I, app: Router, options: Options, closing: watch::Receiver<()>)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match options.tls {
+        Some(ref acceptor) => match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(io)).await {
+            Ok(Ok(s)) => connection(s, app, &options, closing).await,
+            Ok(Err(e)) => debug!("TLS handshake failed: {}", e),
+            Err(_) => debug!("TLS handshake timed out"),
+        },
+        None => connection(io, app, &options, closing).await,
+    }
+}
+
+/// HTTP/1 or HTTP/2 connection, closed once running requests are done on shutdown.
+async fn connection<I>(io: I, app: Router, options: &Options, mut closing: watch::Receiver<()>)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut builder = Builder::new(TokioExecutor::new());
+
+    if let Some(t) = options.header_timeout {
+        builder.http1().timer(TokioTimer::new()).header_read_timeout(t);
+    }
+
+    let connection = builder.serve_connection_with_upgrades(TokioIo::new(io), TowerToHyperService::new(app));
+    tokio::pin!(connection);
+
+    let result = tokio::select! {
+        r = connection.as_mut() => r,
+        _ = closing.changed() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        },
+    };
+
+    // Clients going away mid request
+    if let Err(e) = result {
+        debug!("Connection ended: {}", e);
+    }
+}
+
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => panic!("Unable to listen for SIGTERM: {}", e),
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM..."),
+        _ = tokio::signal::ctrl_c() => info!("Received Ctrl-C..."),
+    }
+}
@@ -0,0 +1,458 @@
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::Result;
+use axum::body::Body;
+use s3::{error::S3Error, Bucket};
+use sha2::{Digest, Sha256};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::OwnedSemaphorePermit;
+use tokio_util::io::ReaderStream;
+use tracing::debug;
+
+use crate::error::{BadPath, NotFound};
+
+
+/// Where the exported images are read from.
+#[derive(Clone)]
+pub enum Store {
+    S3(Box<Bucket>),
+    /// Export root on disk, keys are paths relative to it
+    Filesystem(PathBuf),
+}
+
+/// Bytes in flight between the store and a client, per transfer.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Content type, validators and length of an object, as far as the store knows them.
+#[derive(Debug, Clone, Default)]
+pub struct Meta {
+    pub content_type: Option<String>,
+    /// Quoted entity tag
+    pub etag: Option<String>,
+    pub last_modified: Option<SystemTime>,
+    pub length: Option<u64>,
+}
+
+
+/// Object of a listing.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub key: String,
+    pub size: u64,
+    /// RFC 3339 time
+    pub last_modified: Option<String>,
+}
+
+impl Store {
+    /// Whole object in memory, for images that are decoded anyway.
+    pub async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        match self {
+            Self::S3(bucket) => {
+                let response = bucket.get_object(key).await?;
+
+                if response.status_code() != 200 {
+                    return Err(S3Error::Http(response.status_code(), String::new()).into())
+                }
+
+                Ok(response.to_vec())
+            },
+            Self::Filesystem(root) => Ok(tokio::fs::read(root.join(key)).await?),
+        }
+    }
+    pub async fn head(&self, key: &str) -> Result<Meta> {
+        match self {
+            Self::S3(bucket) => {
+                let (head, code) = bucket.head_object(key).await?;
+
+                if code != 200 {
+                    return Err(S3Error::Http(code, String::new()).into())
+                }
+
+                Ok(Meta {
+                    content_type: head.content_type,
+                    etag: head.e_tag,
+                    last_modified: head.last_modified.and_then(|v| httpdate::parse_http_date(&v).ok()),
+                    length: head.content_length.map(|l| l as u64),
+                })
+            },
+            Self::Filesystem(root) => {
+                let path = root.join(key);
+
+                let metadata = match tokio::fs::metadata(&path).await {
+                    Ok(m) if m.is_file() => m,
+                    Ok(_) => return Err(NotFound(key.to_string()).into()),
+                    Err(e) if e.kind() == ErrorKind::NotFound => return Err(NotFound(key.to_string()).into()),
+                    Err(e) => return Err(e.into()),
+                };
+
+                let last_modified = metadata.modified().ok();
+
+                // Size and time tell changes apart without reading the file
+                let etag = last_modified.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| format!("\"{:x}-{:x}\"", metadata.len(), d.as_nanos()));
+
+                Ok(Meta {
+                    content_type: Some(content_type(&path).to_string()),
+                    etag,
+                    last_modified,
+                    length: Some(metadata.len()),
+                })
+            },
+        }
+    }
+    /// Body reading the object, or the inclusive byte range of it, while the client takes it.
+    /// `get_object_stream` of S3 can't be sent across threads, objects are written into a
+    /// bounded pipe by a task instead. The permit is held until the transfer ends.
+    pub async fn stream(&self, key: &str, range: Option<(u64, u64)>, permit: Option<OwnedSemaphorePermit>)
+    -> Result<Body> {
+        let (reader, mut writer) = tokio::io::duplex(PIPE_SIZE);
+        let key = key.to_string();
+
+        match self {
+            Self::S3(bucket) => {
+                let bucket = bucket.clone();
+
+                tokio::spawn(async move {
+                    let _permit = permit;
+
+                    let result = match range {
+                        Some((start, end)) => bucket.get_object_range_to_writer(&key, start, Some(end), &mut writer).await,
+                        None => bucket.get_object_to_writer(&key, &mut writer).await,
+                    };
+
+                    // Mostly clients that went away
+                    if let Err(e) = result {
+                        debug!("Transfer of {:?} ended early: {}", key, e);
+                    }
+                });
+            },
+            Self::Filesystem(root) => {
+                let mut file = tokio::fs::File::open(root.join(&key)).await?;
+
+                let mut reader = match range {
+                    Some((start, end)) => {
+                        file.seek(SeekFrom::Start(start)).await?;
+                        file.take(end - start + 1)
+                    },
+                    None => file.take(u64::MAX),
+                };
+
+                tokio::spawn(async move {
+                    let _permit = permit;
+
+                    if let Err(e) = tokio::io::copy(&mut reader, &mut writer).await {
+                        debug!("Transfer of {:?} ended early: {}", key, e);
+                    }
+                });
+            },
+        }
+
+        Ok(Body::from_stream(ReaderStream::new(reader)))
+    }
+    pub async fn exists(&self, key: &str) -> bool {
+        match self {
+            Self::S3(bucket) => matches!(bucket.head_object(key).await, Ok((_, 200))),
+            Self::Filesystem(root) => root.join(key).is_file(),
+        }
+    }
+    pub async fn put(&self, key: &str, bytes: &[u8], content_type: &str) -> Result<()> {
+        match self {
+            Self::S3(bucket) => {
+                bucket.put_object_with_content_type(key, bytes, content_type).await?;
+            },
+            Self::Filesystem(root) => {
+                let path = root.join(key);
+
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+
+                tokio::fs::write(path, bytes).await?;
+            },
+        }
+
+        Ok(())
+    }
+    /// Keys of the objects right under the `dir/` prefix.
+    pub async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        match self {
+            Self::S3(bucket) => Ok(bucket.list(prefix.to_string(), Some("/".to_string())).await?
+                .into_iter()
+                .flat_map(|l| l.contents.into_iter().map(|o| o.key))
+                .collect()),
+            Self::Filesystem(root) => self.read_dir(root, prefix, false).await,
+        }
+    }
+    /// Prefixes of the dirs right under the `dir/` prefix, each ends with a `/`.
+    pub async fn index(&self, prefix: &str) -> Result<Vec<String>> {
+        match self {
+            Self::S3(bucket) => Ok(bucket.list(prefix.to_string(), Some("/".to_string())).await?
+                .into_iter()
+                .flat_map(|l| l.common_prefixes.into_iter()
+                    .flat_map(|v| v.into_iter().map(|p| p.prefix)))
+                .collect()),
+            Self::Filesystem(root) => self.read_dir(root, prefix, true).await,
+        }
+    }
+    /// Objects under the prefix ordered by key, starting after the given key. Tells if
+    /// there are more of them.
+    pub async fn list_page(&self, prefix: &str, recursive: bool, start_after: Option<String>, max: usize)
+    -> Result<(Vec<Entry>, bool)> {
+        match self {
+            Self::S3(bucket) => {
+                let delimiter = (!recursive).then(|| "/".to_string());
+                let (page, _) = bucket.list_page(prefix.to_string(), delimiter, None, start_after, Some(max)).await?;
+
+                let entries = page.contents.into_iter()
+                    .map(|o| Entry { key: o.key, size: o.size, last_modified: Some(o.last_modified) })
+                    .collect();
+
+                Ok((entries, page.is_truncated))
+            },
+            // Dirs are walked in key order, so the walk stops once the page is full
+            Self::Filesystem(root) => {
+                let after = start_after.as_deref();
+                let mut page = vec![];
+                let mut dirs = vec![self.sorted_dir(root, prefix).await?];
+
+                while let Some(dir) = dirs.last_mut() {
+                    let (key, is_dir) = match dir.pop() {
+                        Some(e) => e,
+                        None => {
+                            dirs.pop();
+                            continue
+                        },
+                    };
+
+                    // Dirs before the cursor have nothing left to list
+                    if is_dir {
+                        if recursive && after.is_none_or(|a| a.starts_with(&key) || key.as_str() > a) {
+                            dirs.push(self.sorted_dir(root, &key).await?);
+                        }
+
+                        continue
+                    }
+
+                    if after.is_some_and(|a| key.as_str() <= a) {
+                        continue
+                    }
+
+                    if page.len() == max {
+                        return Ok((page, true))
+                    }
+
+                    let metadata = tokio::fs::metadata(root.join(&key)).await?;
+                    let last_modified = metadata.modified().ok()
+                        .and_then(|t| OffsetDateTime::from(t).format(&Rfc3339).ok());
+
+                    page.push(Entry { key, size: metadata.len(), last_modified });
+                }
+
+                Ok((page, false))
+            },
+        }
+    }
+    /// Checksum the import tagged the object with, for names without a manifest.
+    pub async fn checksum(&self, key: &str) -> Option<u32> {
+        match self {
+            Self::S3(bucket) => bucket.get_object_tagging(key).await.ok()?.0
+                .into_iter()
+                .find(|t| t.key() == "checksum")
+                .and_then(|t| t.value().parse().ok()),
+            Self::Filesystem(root) => {
+                let path = root.join(key);
+                let id = path.file_stem()?.to_string_lossy().to_string();
+
+                tokio::fs::read_to_string(path.with_file_name(format!(".{}.checksum", id))).await.ok()?
+                    .trim()
+                    .parse()
+                    .ok()
+            },
+        }
+    }
+    /// Width and height from the image header, objects of S3 have them in their metadata.
+    pub async fn dimensions(&self, key: &str) -> Option<(u32, u32)> {
+        match self {
+            Self::S3(bucket) => {
+                let metadata = bucket.head_object(key).await.ok()?.0.metadata?;
+                let side = |k: &str| metadata.get(k).and_then(|v| v.parse().ok());
+
+                side("width").zip(side("height"))
+            },
+            Self::Filesystem(root) => {
+                let path = root.join(key);
+
+                tokio::task::spawn_blocking(move || image::image_dimensions(path).ok()).await.ok().flatten()
+            },
+        }
+    }
+    /// Keys of a dir from the last to the first, dirs end with a `/` so that they sort
+    /// the way their objects do. A missing dir is empty.
+    async fn sorted_dir(&self, root: &Path, prefix: &str) -> Result<Vec<(String, bool)>> {
+        let mut entries = match tokio::fs::read_dir(root.join(prefix)).await {
+            Ok(e) => e,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut keys = vec![];
+
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+
+            match entry.file_type().await?.is_dir() {
+                true => keys.push((format!("{}{}/", prefix, name), true)),
+                false => keys.push((format!("{}{}", prefix, name), false)),
+            }
+        }
+
+        keys.sort_by(|a, b| b.0.cmp(&a.0));
+
+        Ok(keys)
+    }
+    /// Entries of a dir as S3 would list them, a missing dir is empty.
+    async fn read_dir(&self, root: &Path, prefix: &str, dirs: bool) -> Result<Vec<String>> {
+        let mut entries = match tokio::fs::read_dir(root.join(prefix)).await {
+            Ok(e) => e,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut keys = vec![];
+
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+
+            match (dirs, entry.file_type().await?.is_dir()) {
+                (true, true) => keys.push(format!("{}{}/", prefix, name)),
+                (false, false) => keys.push(format!("{}{}", prefix, name)),
+                _ => (),
+            }
+        }
+
+        keys.sort();
+
+        Ok(keys)
+    }
+}
+
+/// Keys come from the URL and end up in paths, only plain names are allowed. Path components
+/// would skip `//` and `/./`, the key is checked as is since stores look it up that way.
+pub fn safe_path(tail: &str) -> Result<()> {
+    // Dirs may end with a slash
+    let key = tail.strip_suffix('/').unwrap_or(tail);
+
+    match key.split('/').all(|s| !matches!(s, "" | "." | "..")) {
+        true => Ok(()),
+        false => Err(BadPath(tail.to_string()).into()),
+    }
+}
+
+/// Strong entity tag from the content checksum.
+pub fn etag(bytes: &[u8]) -> String {
+    format!("\"{:x}\"", Sha256::digest(bytes))
+}
+
+pub fn content_type(path: &Path) -> &'static str {
+    let ext = path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match ext.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "webp" => "image/webp",
+        "gif" => "image/gif",
+        "avif" => "image/avif",
+        "svg" => "image/svg+xml",
+        "json" => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_path_plain_names() {
+        assert!(safe_path("a").is_ok());
+        assert!(safe_path("a/b/md.jpeg").is_ok());
+        assert!(safe_path("a/b/").is_ok());
+    }
+
+    #[test]
+    fn safe_path_parent_dirs() {
+        assert!(safe_path("..").is_err());
+        assert!(safe_path("../a").is_err());
+        assert!(safe_path("a/../../b").is_err());
+        assert!(safe_path("a/..").is_err());
+    }
+
+    #[test]
+    fn safe_path_absolute() {
+        assert!(safe_path("/etc/passwd").is_err());
+        assert!(safe_path("/").is_err());
+    }
+
+    #[test]
+    fn safe_path_current_dir() {
+        assert!(safe_path(".").is_err());
+        assert!(safe_path("./a").is_err());
+    }
+
+    #[test]
+    fn safe_path_empty_segments() {
+        assert!(safe_path("").is_err());
+        assert!(safe_path("a//b").is_err());
+        assert!(safe_path("a/b//").is_err());
+        assert!(safe_path("//a").is_err());
+    }
+
+    #[test]
+    fn safe_path_interior_current_dirs() {
+        assert!(safe_path("a/./b").is_err());
+        assert!(safe_path("a/.").is_err());
+        assert!(safe_path("a/./").is_err());
+        assert!(safe_path("a/.b/..c/md.jpg").is_ok());
+    }
+
+    #[test]
+    fn safe_path_error_is_bad_path() {
+        assert!(safe_path("..").unwrap_err().is::<BadPath>());
+    }
+
+    #[tokio::test]
+    async fn list_page_in_key_order() {
+        let root = std::env::temp_dir().join(format!("imgserver-list-{}", std::process::id()));
+        let keys = ["x/a-c.jpg", "x/a.jpg", "x/a/b.jpg", "x/b/c/d.jpg", "x/b0.jpg"];
+
+        for k in keys {
+            let path = root.join(k);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+
+        let store = Store::Filesystem(root.clone());
+        let mut listed = vec![];
+        let mut after = None;
+
+        loop {
+            let (page, more) = store.list_page("x/", true, after, 2).await.unwrap();
+            after = page.last().map(|e| e.key.clone());
+            listed.extend(page.into_iter().map(|e| e.key));
+
+            if !more {
+                break
+            }
+        }
+
+        let (flat, _) = store.list_page("x/", false, None, 10).await.unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+
+        assert_eq!(listed, keys);
+        assert_eq!(flat.into_iter().map(|e| e.key).collect::<Vec<_>>(), ["x/a-c.jpg", "x/a.jpg", "x/b0.jpg"]);
+    }
+}